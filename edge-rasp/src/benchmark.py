@@ -21,6 +21,8 @@ def send_request(protocol: aiocoap.protocol.Context, target: str, device_id: int
     payload = b""
     payload += len(data).to_bytes(4, byteorder='little', signed=False)
     payload += int(datetime.datetime.utcnow().timestamp()).to_bytes(8, byteorder='little', signed=False)
+    # dropped datapoints
+    payload += (0).to_bytes(4, byteorder='little', signed=False)
     for data_point in data:
         payload += data_point.serialize()
 
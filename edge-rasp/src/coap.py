@@ -1,21 +1,77 @@
 import asyncio
 import datetime
+import json
 import logging
 import os
+import struct
 from copy import deepcopy
+from typing import Dict, Optional
 
 import aiocoap
 import aiocoap.numbers.codes
 import aiocoap.resource as resource
 import suncalc
+from aiocoap.credentials import CredentialsMap
+from aiocoap.oscore_sitewrapper import OscoreSiteWrapper
 
-from model import CommandState, DataPoint, Command, CommandTypes
+from model import CommandState, DataPoint, Command, CommandTypes, DeviceInfo, DeviceStatus, TimeReference
 
 LEADER_CONNECTION_TIMEOUT = int(datetime.timedelta(
     seconds=int(os.environ.get("LEADER_CONNECTION_TIMEOUT_SECONDS", 60))).total_seconds())
 
+# Observers of a Location command get the current sun position pushed in this interval
+LOCATION_NOTIFICATION_INTERVAL = 10
 
-class CommandResource(resource.Resource):
+# Interfaces joining the All-CoAP-Nodes multicast group, so devices can discover the edge
+COAP_MULTICAST_INTERFACES = [interface for interface in os.environ.get("COAP_MULTICAST_INTERFACES", "").split(",")
+                             if interface]
+
+# Schedules cover a day with a waypoint every 10 minutes, devices interpolate between them
+SCHEDULE_DURATION = datetime.timedelta(days=1)
+SCHEDULE_INTERVAL = datetime.timedelta(minutes=10)
+
+# Directory with manifest.bin and firmware.bin created by sign_firmware.py
+FIRMWARE_DIR = os.environ.get("FIRMWARE_DIR", "firmware")
+
+# Directory with one OSCORE security context directory per device, OSCORE is disabled if unset
+OSCORE_CONTEXTS_DIR = os.environ.get("OSCORE_CONTEXTS_DIR")
+
+
+def sun_position(command_state: CommandState, time: datetime.datetime):
+    # suncalc uses local_time.timestamp() and .timestamp() does not respect timezone
+    # Therefore we add timezone information for calculations and then remove it once again
+    local_time = time.replace(tzinfo=datetime.timezone.utc).astimezone(
+        command_state.local_timezone).replace(tzinfo=datetime.timezone.utc)
+
+    logging.debug(f"COAP: sun_position(): Time: {local_time}, Longitude: {command_state.longitude}, "
+                  f"Latitude: {command_state.latitude}")
+    return suncalc.get_position(local_time, lng=command_state.longitude, lat=command_state.latitude)
+
+
+def load_server_credentials() -> CredentialsMap:
+    server_credentials = CredentialsMap()
+    if OSCORE_CONTEXTS_DIR is None:
+        logging.warning("COAP: OSCORE_CONTEXTS_DIR not set, accepting unprotected requests")
+        return server_credentials
+
+    for device in sorted(os.listdir(OSCORE_CONTEXTS_DIR)):
+        context_dir = os.path.join(OSCORE_CONTEXTS_DIR, device)
+        if os.path.isdir(context_dir):
+            server_credentials.load_from_dict({f":{device}": {"oscore": {"contextfile": context_dir}}})
+            logging.info(f"COAP: Loaded OSCORE security context for {device}")
+    return server_credentials
+
+
+def is_authenticated(request) -> bool:
+    # Requests passing the OSCORE site wrapper carry the security context as claim
+    return OSCORE_CONTEXTS_DIR is None or bool(request.remote.authenticated_claims)
+
+
+def unauthorized_response() -> aiocoap.Message:
+    return aiocoap.Message(code=aiocoap.numbers.codes.Code.UNAUTHORIZED, payload=b"OSCORE required")
+
+
+class CommandResource(resource.ObservableResource):
     command_state_lock: asyncio.Lock
     command_state: CommandState
 
@@ -26,9 +82,18 @@ class CommandResource(resource.Resource):
 
     def get_link_description(self):
         # Publish additional data in .well-known/core
-        return dict(**super().get_link_description(), title="Command pull resource.")
+        return dict(**super().get_link_description(), title="Command pull and observe resource.")
+
+    async def notify_location_periodically(self):
+        while True:
+            await asyncio.sleep(LOCATION_NOTIFICATION_INTERVAL)
+            if self.command_state.command in [CommandTypes.Location, CommandTypes.Hybrid]:
+                self.updated_state()
 
     async def render_get(self, request):
+        if not is_authenticated(request):
+            return unauthorized_response()
+
         logging.debug("COAP: Acquiring lock...")
         await self.command_state_lock.acquire()
 
@@ -43,7 +108,10 @@ class CommandResource(resource.Resource):
                 or (datetime.datetime.utcnow() - self.command_state.last_leader_update) > max_request_time:
             self.command_state.leader_device_id = device_id
 
+        offsets_changed = False
         if self.command_state.leader_device_id == device_id:
+            offsets_changed = (self.command_state.target_angle_offset_hor, self.command_state.target_angle_offset_ver) \
+                != (target_angle_offset_hor, target_angle_offset_ver)
             self.command_state.target_angle_offset_hor = target_angle_offset_hor
             self.command_state.target_angle_offset_ver = target_angle_offset_ver
             self.command_state.last_leader_update = datetime.datetime.utcnow()
@@ -52,37 +120,80 @@ class CommandResource(resource.Resource):
         self.command_state_lock.release()
         logging.debug("COAP: Lock released")
 
+        # Push the new offsets of the leader to the observing followers
+        if offsets_changed:
+            self.updated_state()
+
         command = Command(CommandTypes.Nop, 0, 0, 0.0, 0.0)
 
         if command_state.leader_device_id == device_id:
             command.command = command_state.command
         # Handle follower devices
         else:
-            if command_state.command in [CommandTypes.Location, CommandTypes.LightTracking]:
+            if command_state.command in [CommandTypes.Location, CommandTypes.LightTracking, CommandTypes.Hybrid]:
                 command.command = CommandTypes.Follower
             else:
                 command.command = command_state.command
 
-        if command.command == CommandTypes.Location:
-            # suncalc uses local_time.timestamp() and .timestamp() does not respect timezone
-            # Therefore we add timezone information for calculations and then remove it once again
-            local_time = datetime.datetime.utcnow().replace(tzinfo=datetime.timezone.utc).astimezone(
-                command_state.local_timezone).replace(tzinfo=datetime.timezone.utc)
-
-            logging.debug(f"COAP: render_get(): Time: {local_time}, Longitude: {command_state.longitude}, "
-                          f"Latitude: {command_state.latitude}")
-            sun_loc = suncalc.get_position(local_time, lng=command_state.longitude,
-                                           lat=command_state.latitude)
+        if command.command in [CommandTypes.Location, CommandTypes.Hybrid]:
+            sun_loc = sun_position(command_state, datetime.datetime.utcnow())
             command.azimuth = sun_loc["azimuth"]
             command.altitude = sun_loc["altitude"]
+            command.latitude = command_state.latitude
+            command.longitude = command_state.longitude
         elif command.command == CommandTypes.Follower:
             command.target_angle_offset_hor = command_state.target_angle_offset_hor
             command.target_angle_offset_ver = command_state.target_angle_offset_ver
+        elif command.command == CommandTypes.Goto:
+            command.angle_hor = command_state.angle_hor
+            command.angle_ver = command_state.angle_ver
+        elif command.command == CommandTypes.Schedule:
+            command.schedule_id = command_state.schedule_id
+        elif command.command == CommandTypes.Jog:
+            command.axis = command_state.jog_axis
+            command.steps = command_state.jog_steps
+            command.jog_sequence = command_state.jog_sequence
 
         logging.debug(f"COAP: Sending command: {repr(command)}")
         return aiocoap.Message(payload=command.serialize())
 
 
+class ScheduleResource(resource.Resource):
+    command_state_lock: asyncio.Lock
+    command_state: CommandState
+
+    def __init__(self, command_state, command_state_lock):
+        super().__init__()
+        self.command_state = command_state
+        self.command_state_lock = command_state_lock
+
+    def get_link_description(self):
+        # Publish additional data in .well-known/core
+        return dict(**super().get_link_description(), title="Solar positions of the schedule command.")
+
+    async def render_get(self, request):
+        if not is_authenticated(request):
+            return unauthorized_response()
+
+        async with self.command_state_lock:
+            command_state = deepcopy(self.command_state)
+        if command_state.command != CommandTypes.Schedule:
+            return aiocoap.Message(code=aiocoap.numbers.codes.Code.NOT_FOUND, payload=b"No schedule")
+
+        # Waypoints of time + azimuth + altitude, transferred block-wise
+        payload = b""
+        start = datetime.datetime.utcfromtimestamp(command_state.schedule_id)
+        for i in range(int(SCHEDULE_DURATION / SCHEDULE_INTERVAL) + 1):
+            time = start + i * SCHEDULE_INTERVAL
+            sun_loc = sun_position(command_state, time)
+            payload += (command_state.schedule_id + int((time - start).total_seconds())).to_bytes(
+                byteorder='little', signed=False, length=8) \
+                + struct.pack('<f', sun_loc["azimuth"]) + struct.pack('<f', sun_loc["altitude"])
+
+        logging.info(f"COAP: Sending schedule {command_state.schedule_id}")
+        return aiocoap.Message(payload=payload)
+
+
 class SensorData(resource.Resource):
     received_data_points_db: asyncio.Queue
     received_data_points_mqtt: asyncio.Queue
@@ -100,6 +211,9 @@ class SensorData(resource.Resource):
         return aiocoap.Message(payload=b"some response payload")
 
     async def render_post(self, request):
+        if not is_authenticated(request):
+            return unauthorized_response()
+
         logging.debug(f"POST received payload: {request.payload}")
 
         edge_current_time = datetime.datetime.utcnow()
@@ -112,13 +226,19 @@ class SensorData(resource.Resource):
         length = int.from_bytes(payload[0:4], byteorder='little', signed=False)
 
         client_current_time_size = 8
+        dropped_size = 4
+        header_size = length_size + client_current_time_size + dropped_size
 
-        expected_packet_size = length_size + client_current_time_size + DataPoint.get_serialized_size() * length
+        expected_packet_size = header_size + DataPoint.get_device_serialized_size() * length
         if len(payload) != expected_packet_size:
             return aiocoap.Message(code=aiocoap.numbers.codes.Code.BAD_REQUEST,
                                    payload=b"Expected packet size: " + str(expected_packet_size).encode())
 
-        datapoints = self.parse_payload(client_current_time_size, edge_current_time, length_size, payload)
+        dropped = int.from_bytes(payload[12:16], byteorder='little', signed=False)
+        if dropped > 0:
+            logging.warning(f"COAP: Device dropped {dropped} datapoints due to a full buffer")
+
+        datapoints = self.parse_payload(header_size, edge_current_time, payload)
 
         logging.debug("Sending datapoints to queues...")
         await self.received_data_points_mqtt.put(datapoints)
@@ -127,18 +247,21 @@ class SensorData(resource.Resource):
 
         return aiocoap.Message(code=aiocoap.numbers.codes.Code.CHANGED, payload=b"ok")
 
-    def parse_payload(self, client_current_time_size, edge_current_time, length_size, payload):
+    def parse_payload(self, header_size, edge_current_time, payload):
         client_current_time = int.from_bytes(payload[4:12], byteorder='little', signed=False)
         client_current_time = datetime.datetime.utcfromtimestamp(client_current_time)
 
         datapoints = []
-        index = length_size + client_current_time_size
+        index = header_size
 
         while index < len(payload):
-            dp = DataPoint.deserialize(payload[index:index + DataPoint.get_serialized_size()])
-            index += DataPoint.get_serialized_size()
-            time_passed = client_current_time - dp.timestamp
-            dp.timestamp = edge_current_time - time_passed
+            dp = DataPoint.deserialize(payload[index:index + DataPoint.get_device_serialized_size()])
+            index += DataPoint.get_device_serialized_size()
+            # Only timestamps of an unset device clock are mapped to the clock of the edge
+            if dp.time_reference == TimeReference.Relative:
+                time_passed = client_current_time - dp.timestamp
+                dp.timestamp = edge_current_time - time_passed
+                dp.time_reference = TimeReference.Absolute
 
             datapoints.append(dp)
 
@@ -146,16 +269,144 @@ class SensorData(resource.Resource):
         return datapoints
 
 
+class DeviceInfoResource(resource.Resource):
+    devices: Dict[int, DeviceInfo]
+
+    def __init__(self):
+        super().__init__()
+        self.devices = {}
+
+    def get_link_description(self):
+        # Publish additional data in .well-known/core
+        return dict(**super().get_link_description(), title="Device identity and capabilities.")
+
+    async def render_get(self, request):
+        devices = [device.to_dict() for device in self.devices.values()]
+        return aiocoap.Message(payload=json.dumps(devices).encode(),
+                               content_format=50)  # application/json
+
+    async def render_post(self, request):
+        if not is_authenticated(request):
+            return unauthorized_response()
+
+        # device_id + mac + capabilities + firmware version
+        if len(request.payload) < 4 + 6 + 4:
+            return aiocoap.Message(code=aiocoap.numbers.codes.Code.BAD_REQUEST, payload=b"Minimum packet size is 14")
+
+        try:
+            device = DeviceInfo.deserialize(request.payload)
+        except UnicodeDecodeError:
+            return aiocoap.Message(code=aiocoap.numbers.codes.Code.BAD_REQUEST, payload=b"Invalid firmware version")
+
+        if device.device_id not in self.devices:
+            logging.info(f"COAP: New device {device.device_id} ({device.mac}), firmware {device.firmware_version}")
+        self.devices[device.device_id] = device
+
+        return aiocoap.Message(code=aiocoap.numbers.codes.Code.CHANGED, payload=b"ok")
+
+
+class DeviceStatusResource(resource.Resource):
+    statuses: Dict[int, DeviceStatus]
+
+    def __init__(self):
+        super().__init__()
+        self.statuses = {}
+
+    def get_link_description(self):
+        # Publish additional data in .well-known/core
+        return dict(**super().get_link_description(), title="Execution status of the devices.")
+
+    def to_json(self) -> str:
+        return json.dumps([status.to_dict() for status in self.statuses.values()])
+
+    async def render_get(self, request):
+        return aiocoap.Message(payload=self.to_json().encode(),
+                               content_format=50)  # application/json
+
+    async def render_post(self, request):
+        if not is_authenticated(request):
+            return unauthorized_response()
+
+        if len(request.payload) != DeviceStatus.get_serialized_size():
+            return aiocoap.Message(code=aiocoap.numbers.codes.Code.BAD_REQUEST,
+                                   payload=f"Packet size must be {DeviceStatus.get_serialized_size()}".encode())
+
+        try:
+            status = DeviceStatus.deserialize(request.payload)
+        except ValueError:
+            return aiocoap.Message(code=aiocoap.numbers.codes.Code.BAD_REQUEST, payload=b"Invalid status")
+
+        previous = self.statuses.get(status.device_id)
+        if previous is None or previous.state != status.state or previous.last_fault != status.last_fault:
+            logging.info(f"COAP: Device {status.device_id} is {status.state.name} executing {status.command.name}, "
+                         f"last fault {status.last_fault}")
+        self.statuses[status.device_id] = status
+
+        return aiocoap.Message(code=aiocoap.numbers.codes.Code.CHANGED, payload=b"ok")
+
+
+class FirmwareFileResource(resource.Resource):
+    """Serves a file of the firmware directory, aiocoap splits large files into blocks"""
+    filename: str
+    content: bytes
+    mtime: Optional[float]
+
+    def __init__(self, filename, title):
+        super().__init__()
+        self.filename = filename
+        self.title = title
+        self.content = b""
+        self.mtime = None
+
+    def get_link_description(self):
+        # Publish additional data in .well-known/core
+        return dict(**super().get_link_description(), title=self.title)
+
+    def load(self) -> Optional[bytes]:
+        path = os.path.join(FIRMWARE_DIR, self.filename)
+        try:
+            mtime = os.path.getmtime(path)
+        except OSError:
+            return None
+
+        # Every block is requested separately, only read the file again after it changed
+        if mtime != self.mtime:
+            with open(path, "rb") as file:
+                self.content = file.read()
+            self.mtime = mtime
+        return self.content
+
+    async def render_get(self, request):
+        if not is_authenticated(request):
+            return unauthorized_response()
+
+        content = self.load()
+        if content is None:
+            return aiocoap.Message(code=aiocoap.numbers.codes.Code.NOT_FOUND, payload=b"No firmware available")
+        return aiocoap.Message(payload=content)
+
+
 async def run_coap(received_data_points_db: asyncio.Queue, received_data_points_mqtt: asyncio.Queue,
-                   command_state: CommandState, command_state_lock: asyncio.Lock):
+                   command_resource: CommandResource, device_status_resource: DeviceStatusResource):
     # Resource tree creation
     root = resource.Site()
     root.add_resource(['.well-known', 'core'],
                       resource.WKCResource(root.get_resources_as_linkheader))
-    root.add_resource(['command'], CommandResource(command_state, command_state_lock))
+    root.add_resource(['command'], command_resource)
+    root.add_resource(['schedule'], ScheduleResource(command_resource.command_state,
+                                                     command_resource.command_state_lock))
     root.add_resource(['sensor', 'data'], SensorData(received_data_points_db, received_data_points_mqtt))
+    root.add_resource(['device', 'info'], DeviceInfoResource())
+    root.add_resource(['device', 'status'], device_status_resource)
+    root.add_resource(['firmware', 'manifest'], FirmwareFileResource("manifest.bin", "Firmware update manifest."))
+    root.add_resource(['firmware', 'image'], FirmwareFileResource("firmware.bin", "Firmware update image."))
+
+    server_credentials = load_server_credentials()
+    site = OscoreSiteWrapper(root, server_credentials)
 
     logging.info("Creating CoAP server context")
-    await aiocoap.Context.create_server_context(root)
+    await aiocoap.Context.create_server_context(site, server_credentials=server_credentials,
+                                                multicast=COAP_MULTICAST_INTERFACES)
 
-    await asyncio.get_running_loop().create_future()
+    await asyncio.gather(command_resource.notify_location_periodically(),
+                         asyncio.get_running_loop().create_future())
@@ -16,12 +16,18 @@ CREATE TABLE IF NOT EXISTS sensor (
     temperature REAL NULL,
     photoresistor INTEGER NULL,
     power INTEGER NULL,
+    rssi SMALLINT NULL,
     PRIMARY KEY ("time")
 );
 """
 
+# Tables created before the signal strength was reported
+QUERY_MIGRATE_SENSORS = """
+ALTER TABLE sensor ADD COLUMN IF NOT EXISTS rssi SMALLINT NULL;
+"""
+
 QUERY_INSERT_SENSORS = """
-INSERT INTO sensor (time, device_id, temperature, photoresistor, power) VALUES ($1, $2, $3, $4, $5);
+INSERT INTO sensor (time, device_id, temperature, photoresistor, power, rssi) VALUES ($1, $2, $3, $4, $5, $6);
 """
 
 QUERY_GET_SENSORS = """
@@ -66,6 +72,7 @@ async def connect(conf: Config):
 async def setup_table(conn: asyncpg.connection):
     logging.info("Initialising sensors datapoint table")
     await conn.execute(QUERY_CREATE_SENSORS)
+    await conn.execute(QUERY_MIGRATE_SENSORS)
 
 
 async def store_datapoints(pool: asyncpg.Pool, datapoints: List[DataPoint]):
@@ -81,7 +88,7 @@ async def parse_insert(datapoints: List[DataPoint], conn: asyncpg.connection):
     for dp in datapoints:
         try:
             await conn.execute(QUERY_INSERT_SENSORS, dp.timestamp, dp.device_id,
-                               dp.temperature, dp.photoresistor, dp.power)
+                               dp.temperature, dp.photoresistor, dp.power, dp.rssi)
         except asyncpg.InterfaceError as ex:
             logging.error(f"Sensors DB connection failure during storing data: {ex}")
 
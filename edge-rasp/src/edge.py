@@ -8,7 +8,7 @@ from model import CommandState
 from http_server import run_http_server
 from model import Config
 from db import run_db
-from coap import run_coap
+from coap import run_coap, CommandResource, DeviceStatusResource
 from anomaly_detection import run_anomaly_detection
 from mqtt import run_mqtt
 
@@ -20,6 +20,8 @@ async def main():
     command_state_lock = asyncio.Lock()
     received_data_points_mqtt = asyncio.Queue()
     received_data_points_db = asyncio.Queue()
+    command_resource = CommandResource(command_state, command_state_lock)
+    device_status_resource = DeviceStatusResource()
 
     try:
         config_dict = toml.load("config.toml")
@@ -29,9 +31,11 @@ async def main():
 
         await asyncio.gather(run_anomaly_detection(pool, config),
                              run_db(pool, received_data_points_db),
-                             run_coap(received_data_points_db, received_data_points_mqtt, command_state, command_state_lock),
+                             run_coap(received_data_points_db, received_data_points_mqtt, command_resource,
+                                      device_status_resource),
                              run_mqtt(config, received_data_points_mqtt),
-                             run_http_server(command_state, command_state_lock))
+                             run_http_server(command_state, command_state_lock, command_resource.updated_state,
+                                             device_status_resource))
     except ValidationError as e:
         logging.critical("Failed to load config file")
         print(e)
@@ -14,10 +14,11 @@ from aiohttp.web_log import AccessLogger
 from aiohttp.web_request import Request
 from typing_extensions import Awaitable
 
-from model import CommandTypes, CommandState, DataPoint
+from model import Axis, CommandTypes, CommandState, DataPoint
 
 
-async def update_command(app, command_type: CommandTypes, timeoffset=None, latitude=None, longitude=None):
+async def update_command(app, command_type: CommandTypes, timeoffset=None, latitude=None, longitude=None,
+                         angle_hor=None, angle_ver=None, axis=None, steps=None, schedule_start=None):
     command_state = app['command_state']
     command_state_lock: asyncio.Lock = app['command_state_lock']
     logging.debug("HTTP: Acquiring lock...")
@@ -31,9 +32,19 @@ async def update_command(app, command_type: CommandTypes, timeoffset=None, latit
     if latitude is not None and longitude is not None:
         local_timezone = datetime.timezone(offset=datetime.timedelta(minutes=timeoffset))
         command_state.set_location_command_data(local_timezone, latitude, longitude)
+    if angle_hor is not None and angle_ver is not None:
+        command_state.angle_hor = angle_hor
+        command_state.angle_ver = angle_ver
+    if axis is not None and steps is not None:
+        command_state.set_jog_command_data(axis, steps)
+    if schedule_start is not None:
+        command_state.set_schedule_command_data(schedule_start)
     command_state_lock.release()
     logging.debug("HTTP: Lock released")
 
+    # Push the new command to observing devices
+    app['command_updated']()
+
 
 async def location(request: Request):
     data = await request.json()
@@ -41,6 +52,20 @@ async def location(request: Request):
     return web.Response()
 
 
+async def hybrid(request: Request):
+    data = await request.json()
+    await update_command(request.app, CommandTypes.Hybrid, data['timeoffset'], data['latitude'], data['longitude'])
+    return web.Response()
+
+
+async def schedule(request: Request):
+    data = await request.json()
+    # Devices fetch the table of the next day once and follow it without the edge
+    await update_command(request.app, CommandTypes.Schedule, data['timeoffset'], data['latitude'], data['longitude'],
+                         schedule_start=datetime.datetime.now(datetime.timezone.utc))
+    return web.Response()
+
+
 async def light_tracking(request: Request):
     await update_command(request.app, CommandTypes.LightTracking)
     return web.Response()
@@ -51,6 +76,26 @@ async def stop(request: Request):
     return web.Response()
 
 
+async def goto(request: Request):
+    data = await request.json()
+    await update_command(request.app, CommandTypes.Goto, angle_hor=int(data['hor']), angle_ver=int(data['ver']))
+    return web.Response()
+
+
+async def jog(request: Request):
+    data = await request.json()
+    try:
+        axis = Axis[data['axis']]
+    except KeyError:
+        return web.Response(status=400, text="Unknown axis")
+    await update_command(request.app, CommandTypes.Jog, axis=axis, steps=int(data['steps']))
+    return web.Response()
+
+
+async def device_status(request: Request):
+    return web.Response(text=request.app['device_status'].to_json(), content_type='application/json')
+
+
 async def control(_request: Request):
     command_state: CommandState = _request.app['command_state']
     with open("control.html", "r") as f:
@@ -126,12 +171,21 @@ async def run_app(
     await main_task
 
 
-async def run_http_server(command_state: CommandState, command_state_lock: asyncio.Lock):
+async def run_http_server(command_state: CommandState, command_state_lock: asyncio.Lock,
+                          command_updated: Callable[[], None], device_status):
     app = web.Application()
     app['command_state'] = command_state
     app['command_state_lock'] = command_state_lock
+    app['command_updated'] = command_updated
+    # Resource receiving the status of the devices
+    app['device_status'] = device_status
     app.add_routes([web.post('/api/v1/location', location)])
+    app.add_routes([web.post('/api/v1/hybrid', hybrid)])
+    app.add_routes([web.post('/api/v1/schedule', schedule)])
     app.add_routes([web.post('/api/v1/light_tracking', light_tracking)])
     app.add_routes([web.post('/api/v1/stop', stop)])
+    app.add_routes([web.post('/api/v1/goto', goto)])
+    app.add_routes([web.post('/api/v1/jog', jog)])
+    app.add_routes([web.get('/api/v1/status', device_status)])
     app.add_routes([web.get('/', control)])
     await run_app(app)
@@ -1,10 +1,11 @@
 import datetime
 import enum
+import math
 import struct
 from copy import deepcopy
 
 from dataclasses import dataclass
-from typing import Optional, List
+from typing import Optional, List, Tuple
 from pydantic import BaseModel
 
 
@@ -47,6 +48,19 @@ class CommandTypes(enum.Enum):
     LightTracking = 2
     Follower = 3
     Stop = 4
+    # Absolute motor angles
+    Goto = 5
+    # Steps of one axis relative to the last position
+    Jog = 6
+    # Solar positions of a day fetched once from /schedule
+    Schedule = 7
+    # Solar position refined by a light search on the device
+    Hybrid = 8
+
+
+class Axis(enum.Enum):
+    Horizontal = 0
+    Vertical = 1
 
 
 @dataclass
@@ -61,6 +75,15 @@ class CommandState:
     leader_device_id: Optional[int]
     last_leader_update: Optional[datetime.datetime]
 
+    angle_hor: int = 0
+    angle_ver: int = 0
+    jog_axis: Axis = Axis.Horizontal
+    jog_steps: int = 0
+    # Incremented with every jog, devices apply a jog only once
+    jog_sequence: int = 0
+    # Unix time of the first waypoint of the schedule
+    schedule_id: int = 0
+
     @staticmethod
     def default():
         return CommandState(CommandTypes.Nop, 0, 0, 0.0, 0.0, datetime.timezone.utc, None, None)
@@ -74,6 +97,11 @@ class CommandState:
         self.local_timezone = datetime.timezone.utc
         self.leader_device_id = None
         self.last_leader_update = None
+        self.angle_hor = 0
+        self.angle_ver = 0
+        self.jog_axis = Axis.Horizontal
+        self.jog_steps = 0
+        self.schedule_id = 0
 
     def __deepcopy__(self, memo):  # memo is a dict of id's to copies
         id_self = id(self)  # memoization avoids unnecessary recursion
@@ -87,7 +115,13 @@ class CommandState:
                 deepcopy(self.longitude, memo),
                 deepcopy(self.local_timezone, memo),
                 deepcopy(self.leader_device_id, memo),
-                deepcopy(self.last_leader_update, memo))
+                deepcopy(self.last_leader_update, memo),
+                deepcopy(self.angle_hor, memo),
+                deepcopy(self.angle_ver, memo),
+                deepcopy(self.jog_axis, memo),
+                deepcopy(self.jog_steps, memo),
+                deepcopy(self.jog_sequence, memo),
+                deepcopy(self.schedule_id, memo))
             memo[id_self] = _copy
         return _copy
 
@@ -96,6 +130,14 @@ class CommandState:
         self.latitude = latitude
         self.longitude = longitude
 
+    def set_jog_command_data(self, axis: Axis, steps: int):
+        self.jog_axis = axis
+        self.jog_steps = steps
+        self.jog_sequence = (self.jog_sequence + 1) % 2 ** 16
+
+    def set_schedule_command_data(self, start: datetime.datetime):
+        self.schedule_id = int(start.timestamp())
+
 
 @dataclass
 class Command:
@@ -104,19 +146,188 @@ class Command:
     target_angle_offset_ver: int
     azimuth: float
     altitude: float
+    # Sent with location commands, so devices can calculate the solar position without the edge
+    latitude: float = 0.0
+    longitude: float = 0.0
+    angle_hor: int = 0
+    angle_ver: int = 0
+    axis: Axis = Axis.Horizontal
+    steps: int = 0
+    jog_sequence: int = 0
+    schedule_id: int = 0
 
     def serialize(self) -> bytes:
-        if self.command == CommandTypes.Location:
+        if self.command in [CommandTypes.Location, CommandTypes.Hybrid]:
             return self.command.value.to_bytes(byteorder='little', signed=False, length=1) \
-                   + struct.pack('<f', self.azimuth) + struct.pack('<f', self.altitude)
+                   + struct.pack('<f', self.azimuth) + struct.pack('<f', self.altitude) \
+                   + struct.pack('<f', self.latitude) + struct.pack('<f', self.longitude)
         elif self.command == CommandTypes.Follower:
             return self.command.value.to_bytes(byteorder='little', signed=False, length=1) \
                    + self.target_angle_offset_hor.to_bytes(byteorder='little', signed=True, length=4) \
                    + self.target_angle_offset_ver.to_bytes(byteorder='little', signed=True, length=4)
+        elif self.command == CommandTypes.Goto:
+            return self.command.value.to_bytes(byteorder='little', signed=False, length=1) \
+                   + self.angle_hor.to_bytes(byteorder='little', signed=True, length=4) \
+                   + self.angle_ver.to_bytes(byteorder='little', signed=True, length=4)
+        elif self.command == CommandTypes.Jog:
+            return self.command.value.to_bytes(byteorder='little', signed=False, length=1) \
+                   + self.axis.value.to_bytes(byteorder='little', signed=False, length=1) \
+                   + self.steps.to_bytes(byteorder='little', signed=True, length=4) \
+                   + self.jog_sequence.to_bytes(byteorder='little', signed=False, length=2)
+        elif self.command == CommandTypes.Schedule:
+            return self.command.value.to_bytes(byteorder='little', signed=False, length=1) \
+                   + self.schedule_id.to_bytes(byteorder='little', signed=False, length=8)
         else:
             return self.command.value.to_bytes(byteorder='little', signed=False, length=1)
 
 
+class DeviceCapabilities(enum.IntFlag):
+    MotorHor = 1 << 0
+    MotorVer = 1 << 1
+    Photoresistor = 1 << 2
+    IRSensor = 1 << 3
+    TemperatureSensor = 1 << 4
+    PowerSensor = 1 << 5
+    Oscore = 1 << 6
+    PositionSensor = 1 << 7
+
+
+@dataclass
+class DeviceInfo:
+    device_id: int
+    mac: str
+    capabilities: DeviceCapabilities
+    firmware_version: str
+    last_seen: datetime.datetime
+
+    @staticmethod
+    def deserialize(payload: bytes):
+        device_id = int.from_bytes(payload[0:4], byteorder='little', signed=False)
+        mac = ':'.join(f'{byte:02x}' for byte in payload[4:10])
+        capabilities = DeviceCapabilities(int.from_bytes(payload[10:14], byteorder='little', signed=False))
+        firmware_version = payload[14:].decode()
+
+        return DeviceInfo(device_id=device_id, mac=mac, capabilities=capabilities,
+                          firmware_version=firmware_version, last_seen=datetime.datetime.utcnow())
+
+    def to_dict(self):
+        return {
+            "device_id": self.device_id,
+            "mac": self.mac,
+            "capabilities": [capability.name for capability in DeviceCapabilities if capability in self.capabilities],
+            "firmware_version": self.firmware_version,
+            "last_seen": self.last_seen.isoformat(),
+        }
+
+
+class DeviceState(enum.Enum):
+    Booting = 0
+    Homing = 1
+    Idle = 2
+    Tracking = 3
+    # Stowed for the night
+    Stowing = 4
+    Stopped = 5
+    Error = 6
+
+
+class DeviceFault(enum.Enum):
+    # The reference mark of the motors was not found
+    Homing = 1
+    # Reading a sensor of the platform failed
+    Sensor = 2
+    # The motors lost their position again after re-homing, e.g. a jammed axis
+    Motor = 3
+
+
+@dataclass
+class DeviceStatus:
+    device_id: int
+    # Last command applied by the device
+    command: CommandTypes
+    state: DeviceState
+    # Tracking mode while in the Tracking state, Goto while positioned by hand, Nop otherwise
+    mode: CommandTypes
+    # Most recent fault, kept after the device recovered from it
+    last_fault: Optional[DeviceFault]
+    # Homing or searching the light, the command is not executed yet
+    initializing: bool
+    # (horizontal, vertical) motor angles, light tracking has no target
+    target_angles: Optional[Tuple[int, int]]
+    current_angles: Tuple[int, int]
+    last_seen: datetime.datetime
+    # The target was outside the travel of the axes, e.g. the sun below the horizon
+    out_of_envelope: bool = False
+    # Last SNTP sync of the device clock since it booted
+    last_sync: Optional[datetime.datetime] = None
+    # Drift of the device clock in ppm between the last two syncs, positive if it runs fast
+    clock_drift: Optional[float] = None
+
+    @staticmethod
+    def get_serialized_size():
+        # device_id + command + state + mode + fault + flags + target and current angles
+        # + last sync + clock drift
+        return 4 + 5 + 4 * 4 + 8 + 4
+
+    @staticmethod
+    def deserialize(payload: bytes):
+        device_id = int.from_bytes(payload[0:4], byteorder='little', signed=False)
+        command = CommandTypes(payload[4])
+        state = DeviceState(payload[5])
+        mode = CommandTypes(payload[6])
+        last_fault = DeviceFault(payload[7]) if payload[7] != 0 else None
+        flags = payload[8]
+        target_hor, target_ver, current_hor, current_ver = struct.unpack('<4i', payload[9:25])
+        last_sync, clock_drift = struct.unpack('<Qf', payload[25:37])
+
+        return DeviceStatus(device_id=device_id, command=command, state=state, mode=mode, last_fault=last_fault,
+                            initializing=bool(flags & 1),
+                            target_angles=(target_hor, target_ver) if flags & 2 else None,
+                            current_angles=(current_hor, current_ver), last_seen=datetime.datetime.utcnow(),
+                            out_of_envelope=bool(flags & 4),
+                            last_sync=datetime.datetime.utcfromtimestamp(last_sync) if last_sync != 0 else None,
+                            clock_drift=clock_drift if not math.isnan(clock_drift) else None)
+
+    def time_since_last_sync(self) -> Optional[datetime.timedelta]:
+        if self.last_sync is None:
+            return None
+        return datetime.datetime.utcnow() - self.last_sync
+
+    def to_dict(self):
+        return {
+            "device_id": self.device_id,
+            "command": self.command.name,
+            "state": self.state.name,
+            "mode": self.mode.name,
+            "last_fault": self.last_fault.name if self.last_fault is not None else None,
+            "initializing": self.initializing,
+            "target_angles": self.target_angles,
+            "current_angles": self.current_angles,
+            "last_seen": self.last_seen.isoformat(),
+            "out_of_envelope": self.out_of_envelope,
+            "last_sync": self.last_sync.isoformat() if self.last_sync is not None else None,
+            "seconds_since_last_sync": int(self.time_since_last_sync().total_seconds())
+            if self.last_sync is not None else None,
+            "clock_drift_ppm": self.clock_drift,
+        }
+
+
+class TimeReference(enum.Enum):
+    # Taken before the device clock was set, relative to the client current time of the upload
+    Relative = 0
+    # Taken with a device clock set by SNTP
+    Absolute = 1
+
+
+class PowerMode(enum.Enum):
+    # CPU kept running between the control cycles
+    Active = 0
+    # CPU and wifi modem slept between the control cycles
+    LightSleep = 1
+    # Woken up from deep sleep
+    DeepSleep = 2
+
+
 @dataclass
 class DataPoint:
     # unique identifier of ESP device
@@ -128,6 +339,10 @@ class DataPoint:
     voltage: int
     current: int
     power: int
+    time_reference: TimeReference = TimeReference.Absolute
+    power_mode: PowerMode = PowerMode.Active
+    # Signal strength of the access point in dBm, None while the device was disconnected
+    rssi: Optional[int] = None
 
     def serialize(self) -> bytes:
         return self.device_id.to_bytes(4, 'little', signed=False) + \
@@ -144,6 +359,11 @@ class DataPoint:
         # device_id + timestamp + 4 * 6 (temperature, photoresistor, ir sensor, voltage, current, power)
         return 4 + 8 + 4 * 6
 
+    @staticmethod
+    def get_device_serialized_size():
+        # serialized size + time reference + power mode + rssi, only sent by devices
+        return DataPoint.get_serialized_size() + 1 + 1 + 1
+
     @staticmethod
     def deserialize(payload: bytes):
         index = 0
@@ -164,11 +384,18 @@ class DataPoint:
         index += 4
         power = int.from_bytes(payload[index:index + 4], byteorder='little', signed=False)
         index += 4
+        time_reference = TimeReference(payload[index])
+        index += 1
+        power_mode = PowerMode(payload[index])
+        index += 1
+        rssi = struct.unpack('<b', payload[index:index + 1])[0]
+        index += 1
 
         assert index == len(payload)
 
         return DataPoint(device_id=device_id, timestamp=timestamp, temperature=temperature, photoresistor=photoresistor,
-                         infrared=infrared, voltage=voltage, current=current, power=power)
+                         infrared=infrared, voltage=voltage, current=current, power=power,
+                         time_reference=time_reference, power_mode=power_mode, rssi=rssi if rssi != 0 else None)
 
     @staticmethod
     def aggregate_datapoints(datapoints):
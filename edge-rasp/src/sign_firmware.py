@@ -0,0 +1,75 @@
+"""
+Prepares a firmware image for over-the-air updates of the devices.
+
+Generate a key pair once, compile the printed public key into the firmware as esp_ota_public_key:
+    python3 sign_firmware.py generate-key ota_signing.key
+
+Sign a firmware image, the output directory is served by the edge as FIRMWARE_DIR:
+    python3 sign_firmware.py sign ota_signing.key <version> <image.bin> <output directory>
+"""
+import argparse
+import hashlib
+import os
+import shutil
+
+from cryptography.hazmat.primitives import serialization
+from cryptography.hazmat.primitives.asymmetric.ed25519 import Ed25519PrivateKey
+
+
+def generate_key(key_path: str):
+    private_key = Ed25519PrivateKey.generate()
+    with open(key_path, "wb") as key_file:
+        key_file.write(private_key.private_bytes(serialization.Encoding.Raw, serialization.PrivateFormat.Raw,
+                                                 serialization.NoEncryption()))
+    public_key = private_key.public_key().public_bytes(serialization.Encoding.Raw, serialization.PublicFormat.Raw)
+    print(f"esp_ota_public_key={public_key.hex()}")
+
+
+def build_manifest(private_key: Ed25519PrivateKey, version: str, image: bytes) -> bytes:
+    version_bytes = version.encode()
+    assert len(version_bytes) < 256
+
+    # version length + version + image size + sha256, followed by the signature
+    manifest = len(version_bytes).to_bytes(1, byteorder='little', signed=False)
+    manifest += version_bytes
+    manifest += len(image).to_bytes(4, byteorder='little', signed=False)
+    manifest += hashlib.sha256(image).digest()
+    return manifest + private_key.sign(manifest)
+
+
+def sign(key_path: str, version: str, image_path: str, output_dir: str):
+    with open(key_path, "rb") as key_file:
+        private_key = Ed25519PrivateKey.from_private_bytes(key_file.read())
+    with open(image_path, "rb") as image_file:
+        image = image_file.read()
+
+    os.makedirs(output_dir, exist_ok=True)
+    # Write the image first, devices only start downloading after the manifest changed
+    shutil.copyfile(image_path, os.path.join(output_dir, "firmware.bin"))
+    with open(os.path.join(output_dir, "manifest.bin"), "wb") as manifest_file:
+        manifest_file.write(build_manifest(private_key, version, image))
+    print(f"Signed firmware {version} ({len(image)} bytes)")
+
+
+def main():
+    parser = argparse.ArgumentParser(description="Sign firmware images for over-the-air updates")
+    subparsers = parser.add_subparsers(dest="command", required=True)
+
+    generate_parser = subparsers.add_parser("generate-key")
+    generate_parser.add_argument("key")
+
+    sign_parser = subparsers.add_parser("sign")
+    sign_parser.add_argument("key")
+    sign_parser.add_argument("version")
+    sign_parser.add_argument("image")
+    sign_parser.add_argument("output_dir")
+
+    args = parser.parse_args()
+    if args.command == "generate-key":
+        generate_key(args.key)
+    else:
+        sign(args.key, args.version, args.image, args.output_dir)
+
+
+if __name__ == "__main__":
+    main()
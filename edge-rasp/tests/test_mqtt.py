@@ -0,0 +1,73 @@
+# Runs against the broker of tests/docker-compose.yml:
+#   docker compose -f tests/docker-compose.yml up --build --abort-on-container-exit --exit-code-from tests
+import asyncio
+import datetime
+import os
+import unittest
+
+from asyncio_mqtt import Client
+
+import mqtt
+from model import Command, CommandTypes, DataPoint
+
+BROKER_HOST = os.environ.get("MQTT_BROKER_HOST", "localhost")
+BROKER_PORT = int(os.environ.get("MQTT_BROKER_PORT", "1883"))
+TIMEOUT_SECONDS = 10
+
+
+def datapoint(device_id: int, timestamp: datetime.datetime, value: int) -> DataPoint:
+    return DataPoint(device_id=device_id, timestamp=timestamp, temperature=float(value), photoresistor=value,
+                     infrared=value, voltage=value, current=value, power=value)
+
+
+class MqttTest(unittest.IsolatedAsyncioTestCase):
+    async def test_edge_publishes_aggregated_datapoints(self):
+        start = datetime.datetime(2022, 7, 26, 14, 0, 0)
+        first_interval = [datapoint(1, start + datetime.timedelta(seconds=20 * i), 100 + 10 * i) for i in range(3)]
+        # Starts the next interval, which publishes the first one
+        next_interval = [datapoint(1, start + mqtt.DATA_COLLECTION_INTERVAL, 500)]
+
+        async with Client(BROKER_HOST, BROKER_PORT, client_id="test-cloud") as cloud, \
+                Client(BROKER_HOST, BROKER_PORT, client_id="test-edge") as edge:
+            async with cloud.filtered_messages("sensors") as messages:
+                await cloud.subscribe("sensors")
+
+                received_data_points = asyncio.Queue()
+                worker = asyncio.create_task(mqtt.worker(edge, received_data_points))
+                try:
+                    await received_data_points.put(first_interval)
+                    await received_data_points.put(next_interval)
+                    message = await asyncio.wait_for(messages.__anext__(), TIMEOUT_SECONDS)
+                finally:
+                    worker.cancel()
+
+        self.assertEqual(message.payload, DataPoint.aggregate_datapoints(first_interval).serialize())
+        self.assertEqual(len(message.payload), DataPoint.get_serialized_size())
+
+    async def test_device_receives_retained_command(self):
+        # Panels using the MQTT uplink subscribe their command topic after every (re)connect
+        device_id = 2
+        topic = f"commands/{device_id}"
+        command = Command(CommandTypes.Goto, 0, 0, 0.0, 0.0, angle_hor=270, angle_ver=40)
+
+        async with Client(BROKER_HOST, BROKER_PORT, client_id="test-operator") as operator:
+            await operator.publish(topic, payload=command.serialize(), qos=1, retain=True)
+
+        try:
+            async with Client(BROKER_HOST, BROKER_PORT, client_id=f"solar-panel-{device_id}") as device:
+                async with device.filtered_messages(topic) as messages:
+                    await device.subscribe(topic, qos=1)
+                    message = await asyncio.wait_for(messages.__anext__(), TIMEOUT_SECONDS)
+        finally:
+            async with Client(BROKER_HOST, BROKER_PORT, client_id="test-operator") as operator:
+                # An empty retained message clears the command
+                await operator.publish(topic, payload=b"", qos=1, retain=True)
+
+        self.assertTrue(message.retain)
+        # command + i32 angles as parsed by the device
+        self.assertEqual(message.payload, bytes([CommandTypes.Goto.value]) + (270).to_bytes(4, 'little', signed=True)
+                         + (40).to_bytes(4, 'little', signed=True))
+
+
+if __name__ == '__main__':
+    unittest.main()
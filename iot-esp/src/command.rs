@@ -0,0 +1,162 @@
+use std::convert::{TryFrom, TryInto};
+
+use num_enum::TryFromPrimitive;
+
+#[derive(Clone, Copy, Debug, TryFromPrimitive, PartialEq)]
+#[repr(u8)]
+pub enum CommandType {
+    Nop,
+    Location,
+    LightTracking,
+    Follower,
+    Stop,
+    Goto,
+    Jog,
+    Schedule,
+    Hybrid,
+}
+
+impl Default for CommandType {
+    fn default() -> Self {
+        Self::Nop
+    }
+}
+
+#[derive(Clone, Copy, Debug, TryFromPrimitive, PartialEq)]
+#[repr(u8)]
+pub enum Axis {
+    Horizontal,
+    Vertical,
+}
+
+impl Default for Axis {
+    fn default() -> Self {
+        Self::Horizontal
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Command {
+    pub command: CommandType,
+    pub target_angle_offset_hor: i32,
+    pub target_angle_offset_ver: i32,
+    pub azimuth: f32,
+    pub altitude: f32,
+    /// Latitude and longitude in degrees of a location command, to follow the sun without the edge
+    pub location: Option<(f32, f32)>,
+    /// Absolute motor angles of a goto command, jog commands are resolved to them on receipt
+    pub angle_hor: i32,
+    pub angle_ver: i32,
+    /// Axis and signed number of steps of a jog command
+    pub axis: Axis,
+    pub steps: i32,
+    /// Changes with every jog, so a jog served repeatedly by the edge is applied only once
+    pub jog_sequence: u16,
+    /// Time of the first waypoint of the schedule to follow, the table is fetched separately
+    pub schedule_id: u64,
+}
+
+/// Parses a command as served by the edge, returns `None` for malformed payloads
+pub fn parse_command(payload: &[u8]) -> Option<Command> {
+    let command = CommandType::try_from(*payload.first()?).ok()?;
+    // command + two i32 offsets, angles or two f32 angles, optionally followed by the f32 location
+    let valid_len = match command {
+        CommandType::Follower | CommandType::Goto => payload.len() == 1 + 4 + 4,
+        CommandType::Location | CommandType::Hybrid => {
+            payload.len() == 1 + 4 + 4 || payload.len() == 1 + 4 * 4
+        }
+        // command + axis + i32 steps + u16 sequence
+        CommandType::Jog => payload.len() == 1 + 1 + 4 + 2,
+        CommandType::Schedule => payload.len() == 1 + 8,
+        _ => payload.len() == 1,
+    };
+    if !valid_len {
+        return None;
+    }
+
+    let mut payload_rest = &payload[1..];
+
+    let mut target_angle_offset_hor = 0;
+    let mut target_angle_offset_ver = 0;
+
+    let mut azimuth = 0.0;
+    let mut altitude = 0.0;
+    let mut location = None;
+
+    let mut angle_hor = 0;
+    let mut angle_ver = 0;
+    let mut axis = Axis::default();
+    let mut steps = 0;
+    let mut jog_sequence = 0;
+    let mut schedule_id = 0;
+
+    if command == CommandType::Follower {
+        let target_angle_hor_bytes;
+        (target_angle_hor_bytes, payload_rest) = payload_rest.split_at(std::mem::size_of::<i32>());
+        target_angle_offset_hor = i32::from_le_bytes(target_angle_hor_bytes.try_into().unwrap());
+
+        let target_angle_ver_bytes;
+        (target_angle_ver_bytes, payload_rest) = payload_rest.split_at(std::mem::size_of::<i32>());
+        target_angle_offset_ver = i32::from_le_bytes(target_angle_ver_bytes.try_into().unwrap());
+    } else if command == CommandType::Location || command == CommandType::Hybrid {
+        let azimuth_bytes;
+        (azimuth_bytes, payload_rest) = payload_rest.split_at(std::mem::size_of::<f32>());
+        azimuth = f32::from_le_bytes(azimuth_bytes.try_into().unwrap());
+
+        let altitude_bytes;
+        (altitude_bytes, payload_rest) = payload_rest.split_at(std::mem::size_of::<f32>());
+        altitude = f32::from_le_bytes(altitude_bytes.try_into().unwrap());
+
+        // Sent by newer edges
+        if !payload_rest.is_empty() {
+            let latitude_bytes;
+            (latitude_bytes, payload_rest) = payload_rest.split_at(std::mem::size_of::<f32>());
+            let longitude_bytes;
+            (longitude_bytes, payload_rest) = payload_rest.split_at(std::mem::size_of::<f32>());
+            location = Some((
+                f32::from_le_bytes(latitude_bytes.try_into().unwrap()),
+                f32::from_le_bytes(longitude_bytes.try_into().unwrap()),
+            ));
+        }
+    } else if command == CommandType::Goto {
+        let angle_hor_bytes;
+        (angle_hor_bytes, payload_rest) = payload_rest.split_at(std::mem::size_of::<i32>());
+        angle_hor = i32::from_le_bytes(angle_hor_bytes.try_into().unwrap());
+
+        let angle_ver_bytes;
+        (angle_ver_bytes, payload_rest) = payload_rest.split_at(std::mem::size_of::<i32>());
+        angle_ver = i32::from_le_bytes(angle_ver_bytes.try_into().unwrap());
+    } else if command == CommandType::Jog {
+        axis = Axis::try_from(payload_rest[0]).ok()?;
+        payload_rest = &payload_rest[1..];
+
+        let steps_bytes;
+        (steps_bytes, payload_rest) = payload_rest.split_at(std::mem::size_of::<i32>());
+        steps = i32::from_le_bytes(steps_bytes.try_into().unwrap());
+
+        let sequence_bytes;
+        (sequence_bytes, payload_rest) = payload_rest.split_at(std::mem::size_of::<u16>());
+        jog_sequence = u16::from_le_bytes(sequence_bytes.try_into().unwrap());
+    } else if command == CommandType::Schedule {
+        let schedule_id_bytes;
+        (schedule_id_bytes, payload_rest) = payload_rest.split_at(std::mem::size_of::<u64>());
+        schedule_id = u64::from_le_bytes(schedule_id_bytes.try_into().unwrap());
+    }
+
+    debug_assert_eq!(0, payload_rest.len());
+
+    Some(Command {
+        command,
+        target_angle_offset_hor,
+        target_angle_offset_ver,
+        azimuth,
+        altitude,
+        location,
+        angle_hor,
+        angle_ver,
+        axis,
+        steps,
+        jog_sequence,
+        schedule_id,
+    })
+}
@@ -0,0 +1,126 @@
+use std::time::{Duration, SystemTime};
+
+use super::solar::{self, Daylight, SolarPosition};
+
+// Time to initialise the platform before the sun rises
+const WAKE_BEFORE_SUNRISE: Duration = Duration::from_secs(15 * 60);
+// Apparent altitude of the sun that starts the night, the sun has fully set a few minutes before
+const NIGHT_ALTITUDE_DEGREES: f32 = -1.0;
+// Polar nights are checked again after a day
+const POLAR_NIGHT_RECHECK: Duration = Duration::from_secs(24 * 60 * 60);
+// Sunrises searched ahead for the wake time
+const SEARCHED_DAYS: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    Day,
+    Night,
+}
+
+/// Switches between day and night by the sunrise and sunset at the location of the panel
+///
+/// The night starts when the sun sets and ends shortly before the next sunrise. Without a valid
+/// clock or a location it is always day, as the platform falls back to following the light.
+#[derive(Clone, Copy)]
+pub struct DayCycle {
+    phase: Phase,
+    wake_at: SystemTime,
+    // Direction of the sun at the next sunrise
+    sunrise_position: Option<SolarPosition>,
+}
+
+impl DayCycle {
+    pub fn new() -> DayCycle {
+        DayCycle {
+            phase: Phase::Day,
+            wake_at: SystemTime::UNIX_EPOCH,
+            sunrise_position: None,
+        }
+    }
+
+    /// Updates the phase, returns the new phase on a change
+    pub fn update(&mut self, now: SystemTime, location: Option<(f32, f32)>) -> Option<Phase> {
+        let (latitude, longitude) = match location {
+            Some(location) => location,
+            None => return self.set_phase(Phase::Day),
+        };
+
+        match self.phase {
+            Phase::Day => {
+                let altitude = solar::position(now, latitude, longitude).altitude;
+                if altitude >= NIGHT_ALTITUDE_DEGREES.to_radians() {
+                    return None;
+                }
+                let sunrise = next_sunrise(now, latitude, longitude);
+                let wake_at = match sunrise {
+                    Some(sunrise) => sunrise.checked_sub(WAKE_BEFORE_SUNRISE).unwrap_or(sunrise),
+                    None => now + POLAR_NIGHT_RECHECK,
+                };
+                // Woken up shortly before sunrise while the sun is still below the horizon
+                if wake_at <= now {
+                    return None;
+                }
+                self.wake_at = wake_at;
+                self.sunrise_position = sunrise.map(|sunrise| SolarPosition {
+                    azimuth: solar::position(sunrise, latitude, longitude).azimuth,
+                    altitude: 0.0,
+                });
+                log::info!(
+                    "DayCycle: Sunset, waking up in {:?}",
+                    wake_at.duration_since(now).unwrap_or_default()
+                );
+                self.set_phase(Phase::Night)
+            }
+            Phase::Night => {
+                if now < self.wake_at {
+                    return None;
+                }
+                log::info!("DayCycle: Waking up for sunrise");
+                self.set_phase(Phase::Day)
+            }
+        }
+    }
+
+    pub fn is_night(&self) -> bool {
+        self.phase == Phase::Night
+    }
+
+    /// Time the night ends
+    pub fn wake_at(&self) -> Option<SystemTime> {
+        match self.phase {
+            Phase::Day => None,
+            Phase::Night => Some(self.wake_at),
+        }
+    }
+
+    /// Direction the panel is stowed in for the night, facing the sunrise at the horizon
+    ///
+    /// `None` during a polar night, the panel stays where it is then.
+    pub fn stow_position(&self) -> Option<SolarPosition> {
+        match self.phase {
+            Phase::Day => None,
+            Phase::Night => self.sunrise_position,
+        }
+    }
+
+    fn set_phase(&mut self, phase: Phase) -> Option<Phase> {
+        if self.phase == phase {
+            return None;
+        }
+        self.phase = phase;
+        Some(phase)
+    }
+}
+
+/// First sunrise after `now`, `None` during a polar night
+fn next_sunrise(now: SystemTime, latitude: f32, longitude: f32) -> Option<SystemTime> {
+    (0..=SEARCHED_DAYS)
+        .filter_map(|day| {
+            let time = now + Duration::from_secs(day as u64 * 24 * 60 * 60);
+            match solar::daylight(time, latitude, longitude) {
+                Daylight::SunRises { sunrise, .. } => Some(sunrise),
+                Daylight::AlwaysUp | Daylight::AlwaysDown => None,
+            }
+        })
+        .find(|sunrise| *sunrise > now)
+}
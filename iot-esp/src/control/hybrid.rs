@@ -0,0 +1,93 @@
+use std::convert::TryInto;
+
+use esp_idf_sys::EspError;
+
+use super::lighttracking::MotorAngles;
+use crate::storage::nvs::Nvs;
+
+// Later searches change the correction by at least this fraction, so it follows the day
+const MIN_WEIGHT: f32 = 0.05;
+
+/// Correction of the computed sun position learned from the light found around it
+///
+/// The first searches are averaged, afterwards the correction follows slow changes, e.g. of the
+/// mounting error over the course of the sun. It is kept in NVS ("hybrid" namespace), so a restart
+/// continues with the learned correction.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PointingCorrection {
+    hor: f32,
+    ver: f32,
+    samples: u32,
+}
+
+impl PointingCorrection {
+    pub fn load() -> PointingCorrection {
+        let blob = Nvs::open("hybrid")
+            .and_then(|nvs| nvs.get_blob("correction"))
+            .ok()
+            .flatten();
+        match blob {
+            // hor + ver + samples
+            Some(blob) if blob.len() == 4 + 4 + 4 => PointingCorrection {
+                hor: f32::from_le_bytes(blob[0..4].try_into().unwrap()),
+                ver: f32::from_le_bytes(blob[4..8].try_into().unwrap()),
+                samples: u32::from_le_bytes(blob[8..12].try_into().unwrap()),
+            },
+            _ => PointingCorrection::default(),
+        }
+    }
+
+    pub fn store(&self) -> Result<(), EspError> {
+        let mut blob = Vec::with_capacity(4 + 4 + 4);
+        blob.extend_from_slice(&self.hor.to_le_bytes());
+        blob.extend_from_slice(&self.ver.to_le_bytes());
+        blob.extend_from_slice(&self.samples.to_le_bytes());
+        Nvs::open("hybrid")?.set_blob("correction", &blob)
+    }
+
+    /// Motor angles added to the computed position
+    pub fn offset(&self) -> MotorAngles {
+        MotorAngles {
+            motor_hor: self.hor.round() as i32,
+            motor_ver: self.ver.round() as i32,
+        }
+    }
+
+    /// Learns from the light found at `found` when pointing at the corrected position `target`
+    ///
+    /// Light found at the border of the search scope may be further away or only diffuse, it is
+    /// ignored. Returns whether the offset changed.
+    pub fn learn(
+        &mut self,
+        target: &MotorAngles,
+        found: &MotorAngles,
+        scope: &MotorAngles,
+    ) -> bool {
+        let residual = found - target;
+        if residual.motor_hor.abs() >= scope.motor_hor / 2
+            || residual.motor_ver.abs() >= scope.motor_ver / 2
+        {
+            log::info!(
+                "PointingCorrection: Ignoring light at the border of the search, {:?} off",
+                residual
+            );
+            return false;
+        }
+
+        let offset = self.offset();
+        self.samples = self.samples.saturating_add(1);
+        let weight = (1.0 / self.samples as f32).max(MIN_WEIGHT);
+        self.hor += weight * residual.motor_hor as f32;
+        self.ver += weight * residual.motor_ver as f32;
+
+        if self.offset() == offset {
+            return false;
+        }
+        log::info!(
+            "PointingCorrection: Offset {:?} after {} searches",
+            self.offset(),
+            self.samples
+        );
+        true
+    }
+}
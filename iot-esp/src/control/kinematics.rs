@@ -0,0 +1,234 @@
+use std::f32::consts::PI;
+
+use super::lighttracking::MotorAngles;
+use super::solar::SolarPosition;
+
+/// Handling of positions outside the travel of the axes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnvelopePolicy {
+    /// Moves as close as possible, e.g. to the end of the travel
+    Clamp,
+    /// Stays at the current position until the position is reachable again
+    Refuse,
+}
+
+/// Drive train of an axis, from the motor steps to the rotation of the platform
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AxisKinematics {
+    /// Steps of the motor per revolution of its shaft
+    pub steps_per_revolution: i32,
+    /// Revolutions of the motor per revolution of the axis
+    pub gear_ratio: f32,
+    /// Degrees the axis can travel from the reference position at the motor angle 0
+    pub travel: f32,
+}
+
+impl AxisKinematics {
+    /// Motor steps per revolution of the axis
+    pub fn steps_per_turn(&self) -> i32 {
+        (self.steps_per_revolution as f32 * self.gear_ratio).round() as i32
+    }
+
+    /// Motor angle at the end of the travel
+    pub fn max_angle(&self) -> i32 {
+        (self.travel / 360.0 * self.steps_per_revolution as f32 * self.gear_ratio) as i32
+    }
+
+    fn to_steps(self, angle: f32) -> i32 {
+        (angle / (2.0 * PI) * self.steps_per_turn() as f32).round() as i32
+    }
+
+    fn to_radians(self, steps: i32) -> f32 {
+        steps as f32 / self.steps_per_turn() as f32 * 2.0 * PI
+    }
+
+    /// Wraps the angle by full turns into the travel, otherwise clamps it to the closer end
+    ///
+    /// Returns the angle and whether it is within the travel.
+    fn limit(self, angle: i32) -> (i32, bool) {
+        let max_angle = self.max_angle();
+        if (0..=max_angle).contains(&angle) {
+            return (angle, true);
+        }
+
+        let wrapped = angle.rem_euclid(self.steps_per_turn());
+        if wrapped <= max_angle {
+            (wrapped, true)
+        } else if self.steps_per_turn() - wrapped < wrapped - max_angle {
+            (0, false)
+        } else {
+            (max_angle, false)
+        }
+    }
+}
+
+/// Mapping between the azimuth and altitude faced by the platform and its motor angles
+///
+/// At the motor angles 0 the platform faces south (azimuth 0) at the horizon. Increasing
+/// horizontal angles turn the platform east, increasing vertical angles raise it. Any offset of
+/// the world angles or mount model is applied by the caller.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Kinematics {
+    pub horizontal: AxisKinematics,
+    pub vertical: AxisKinematics,
+}
+
+impl Kinematics {
+    pub fn max_angles(&self) -> MotorAngles {
+        MotorAngles {
+            motor_hor: self.horizontal.max_angle(),
+            motor_ver: self.vertical.max_angle(),
+        }
+    }
+
+    /// Motor angles facing `position`, without limiting them to the travel of the axes
+    pub fn motor_angles(&self, position: &SolarPosition) -> MotorAngles {
+        MotorAngles {
+            motor_hor: self.horizontal.to_steps(2.0 * PI - position.azimuth),
+            motor_ver: self.vertical.to_steps(position.altitude),
+        }
+    }
+
+    /// Azimuth and altitude faced at the motor angles
+    pub fn position(&self, angles: &MotorAngles) -> SolarPosition {
+        let azimuth = 2.0 * PI - self.horizontal.to_radians(angles.motor_hor);
+        SolarPosition {
+            azimuth: (azimuth + PI).rem_euclid(2.0 * PI) - PI,
+            altitude: self.vertical.to_radians(angles.motor_ver),
+        }
+    }
+
+    /// Motor angles within the travel of both axes closest to `angles`
+    ///
+    /// Returns the angles and whether `angles` are within the mechanical envelope.
+    pub fn limit(&self, angles: &MotorAngles) -> (MotorAngles, bool) {
+        let (motor_hor, reachable_hor) = self.horizontal.limit(angles.motor_hor);
+        let (motor_ver, reachable_ver) = self.vertical.limit(angles.motor_ver);
+        (
+            MotorAngles {
+                motor_hor,
+                motor_ver,
+            },
+            reachable_hor && reachable_ver,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Same drive trains as the platform
+    const KINEMATICS: Kinematics = Kinematics {
+        horizontal: AxisKinematics {
+            steps_per_revolution: 540,
+            gear_ratio: 1.0,
+            travel: 360.0 / 1.6,
+        },
+        vertical: AxisKinematics {
+            steps_per_revolution: 540,
+            gear_ratio: 1.0,
+            travel: 360.0 / 3.5,
+        },
+    };
+
+    #[test]
+    fn applies_the_gear_ratio() {
+        let axis = AxisKinematics {
+            steps_per_revolution: 200,
+            gear_ratio: 2.5,
+            travel: 90.0,
+        };
+        assert_eq!(axis.steps_per_turn(), 500);
+        assert_eq!(axis.max_angle(), 125);
+        assert_eq!(axis.to_steps(PI), 250);
+        assert!((axis.to_radians(125) - PI / 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn limit_keeps_angles_within_the_travel() {
+        let horizontal = KINEMATICS.horizontal;
+        assert_eq!(horizontal.max_angle(), 337);
+        assert_eq!(horizontal.limit(0), (0, true));
+        assert_eq!(horizontal.limit(200), (200, true));
+        assert_eq!(horizontal.limit(337), (337, true));
+    }
+
+    #[test]
+    fn limit_wraps_full_turns() {
+        let horizontal = KINEMATICS.horizontal;
+        assert_eq!(horizontal.limit(540), (0, true));
+        assert_eq!(horizontal.limit(540 + 200), (200, true));
+        assert_eq!(horizontal.limit(-540 + 200), (200, true));
+        assert_eq!(horizontal.limit(2 * 540 + 337), (337, true));
+    }
+
+    #[test]
+    fn limit_clamps_to_the_closer_end() {
+        let horizontal = KINEMATICS.horizontal;
+        // 63 steps past the end of the travel, 140 steps before the reference position
+        assert_eq!(horizontal.limit(400), (337, false));
+        // 163 steps past the end of the travel, 40 steps before the reference position
+        assert_eq!(horizontal.limit(500), (0, false));
+        assert_eq!(horizontal.limit(-40), (0, false));
+        assert_eq!(horizontal.limit(540 + 400), (337, false));
+    }
+
+    #[test]
+    fn limit_clamps_negative_altitudes_to_the_horizon() {
+        // East of south, within the travel of the horizontal axis after a full turn
+        let below_horizon = SolarPosition {
+            azimuth: -1.0,
+            altitude: -0.1,
+        };
+        let angles = KINEMATICS.motor_angles(&below_horizon);
+        assert!(angles.motor_ver < 0);
+
+        let (limited, reachable) = KINEMATICS.limit(&angles);
+        assert!(!reachable);
+        assert_eq!(limited.motor_ver, 0);
+        assert_eq!(limited.motor_hor, angles.motor_hor - 540);
+
+        // Past the zenith, beyond the travel of the vertical axis
+        let past_zenith = SolarPosition {
+            azimuth: -1.0,
+            altitude: 2.0,
+        };
+        let (limited, reachable) = KINEMATICS.limit(&KINEMATICS.motor_angles(&past_zenith));
+        assert!(!reachable);
+        assert_eq!(limited.motor_ver, KINEMATICS.max_angles().motor_ver);
+    }
+
+    #[test]
+    fn motor_angles_and_position_round_trip() {
+        let max_angles = KINEMATICS.max_angles();
+        for motor_hor in 0..=max_angles.motor_hor {
+            for motor_ver in (0..=max_angles.motor_ver).step_by(7) {
+                let angles = MotorAngles {
+                    motor_hor,
+                    motor_ver,
+                };
+                let position = KINEMATICS.position(&angles);
+                assert!((-PI..=PI).contains(&position.azimuth));
+
+                let (round_trip, reachable) = KINEMATICS.limit(&KINEMATICS.motor_angles(&position));
+                assert!(reachable);
+                assert_eq!(round_trip, angles);
+            }
+        }
+    }
+
+    #[test]
+    fn position_faces_south_at_the_reference_position() {
+        let position = KINEMATICS.position(&MotorAngles::default());
+        assert!(position.azimuth.abs() < 1e-6);
+        assert_eq!(position.altitude, 0.0);
+
+        // A quarter turn faces east, the azimuth counts from south towards west
+        let east = KINEMATICS.position(&MotorAngles {
+            motor_hor: 135,
+            motor_ver: 0,
+        });
+        assert!((east.azimuth + PI / 2.0).abs() < 1e-6);
+    }
+}
@@ -1,20 +1,37 @@
 use std::cmp::Ordering;
 use std::ops::{Add, Sub};
 
+use crate::command::Axis;
 use crate::sensors::motor::Speed;
 use crate::sensors::motor::StepperMotor;
+use crate::sensors::position::AxisSensor;
+use crate::storage::nvs::Nvs;
 use adc_interpolator::AdcInterpolator;
 use embedded_hal::{
     adc::{Channel, OneShot},
     digital::v2::OutputPin,
 };
+use esp_idf_sys::EspError;
+
+// IR sensor readings below this value see the reference mark
+const IR_SENSOR_DATA_CLOSE: u32 = 1500;
+
+// Moves after the first one to reach a target the position sensors found missed
+const MAX_CORRECTION_MOVES: usize = 3;
+// Steps the measured angle may differ from the counted one, e.g. by the resolution of the sensor
+const POSITION_SENSOR_TOLERANCE: i32 = 1;
+
+// Steps swept on both sides of the light when measuring the backlash
+const BACKLASH_SWEEP: i32 = 30;
+// Larger differences come from changing light rather than from the gearbox
+const MAX_BACKLASH: i32 = 15;
 
 #[derive(Clone, Copy, Debug)]
 pub enum LightTrackingError {
     ADCFailed,
 }
 
-#[derive(Clone, Copy, Debug, Default)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct MotorAngles {
     pub motor_hor: i32,
     pub motor_ver: i32,
@@ -73,8 +90,19 @@ pub trait PlatformTrait<
         interpolator_button: AdcInterpolator<Pin3, Word, LENGTH>,
     ) -> Self;
 
+    /// Positions the axis closed-loop once its angle is known, i.e. after homing or restoring it
+    fn with_position_sensor(self, axis: Axis, sensor: AxisSensor) -> Self;
+
     fn reset_motors_position(&mut self);
 
+    fn is_button_pressed<Adc, ADC>(&mut self, adc: &mut Adc) -> bool
+    where
+        Word: Copy + Into<u32> + PartialEq + PartialOrd,
+        Pin1: Channel<ADC>,
+        Pin2: Channel<ADC>,
+        Pin3: Channel<ADC>,
+        Adc: OneShot<ADC, Word, Pin1> + OneShot<ADC, Word, Pin2> + OneShot<ADC, Word, Pin3>;
+
     fn reset_if_button_pressed<Adc, ADC>(&mut self, adc: &mut Adc) -> bool
     where
         Word: Copy + Into<u32> + PartialEq + PartialOrd,
@@ -85,8 +113,32 @@ pub trait PlatformTrait<
 
     fn get_current_angles(&self) -> MotorAngles;
 
+    fn get_max_angles(&self) -> MotorAngles;
+
+    fn restore_angles(&mut self, angles: &MotorAngles);
+
+    fn get_backlash(&self) -> MotorAngles;
+
+    fn set_backlash(&mut self, backlash: &MotorAngles);
+
+    /// Measures the backlash of both axes by sweeping up and down across the light
+    ///
+    /// The platform must face the light, e.g. after `find_best_position`. Returns `None` if the
+    /// light was not found within the sweeps, the previous backlash stays in use then.
+    fn calibrate_backlash<ADC, Adc>(
+        &mut self,
+        adc: &mut Adc,
+    ) -> Result<Option<MotorAngles>, LightTrackingError>
+    where
+        Word: Copy + Into<u32> + PartialEq + PartialOrd,
+        Pin1: Channel<ADC>,
+        Pin2: Channel<ADC>,
+        Pin3: Channel<ADC>,
+        Adc: OneShot<ADC, Word, Pin1> + OneShot<ADC, Word, Pin2> + OneShot<ADC, Word, Pin3>;
+
     fn test_movement(&mut self);
 
+    /// Moves both axes, with position sensors until the measured angles reach the target
     fn rotate_to_angle(&mut self, ver_angle: i32, hor_angle: i32, speed: Speed);
 
     fn init_motors<Adc, ADC>(&mut self, adc: &mut Adc) -> Result<(), LightTrackingError>
@@ -97,6 +149,22 @@ pub trait PlatformTrait<
         Pin3: Channel<ADC>,
         Adc: OneShot<ADC, Word, Pin1> + OneShot<ADC, Word, Pin2> + OneShot<ADC, Word, Pin3>;
 
+    /// Cross-checks the horizontal angle against the reference mark and returns to it
+    ///
+    /// The mark must be found within `tolerance` steps of the angle 0, the angle is corrected
+    /// then. Returns `false` if the position was lost, the horizontal axis needs homing then.
+    fn verify_position<ADC, Adc>(
+        &mut self,
+        adc: &mut Adc,
+        tolerance: i32,
+    ) -> Result<bool, LightTrackingError>
+    where
+        Word: Copy + Into<u32> + PartialEq + PartialOrd,
+        Pin1: Channel<ADC>,
+        Pin2: Channel<ADC>,
+        Pin3: Channel<ADC>,
+        Adc: OneShot<ADC, Word, Pin1> + OneShot<ADC, Word, Pin2> + OneShot<ADC, Word, Pin3>;
+
     fn find_best_position<ADC, Adc>(&mut self, adc: &mut Adc) -> Result<(), LightTrackingError>
     where
         Word: Copy + Into<u32> + PartialEq + PartialOrd,
@@ -119,6 +187,20 @@ pub trait PlatformTrait<
         Pin3: Channel<ADC>,
         Adc: OneShot<ADC, Word, Pin1> + OneShot<ADC, Word, Pin2> + OneShot<ADC, Word, Pin3>;
 
+    /// Searches the light on both sides of the current position, e.g. around a computed position
+    fn refine_position<ADC, Adc>(
+        &mut self,
+        adc: &mut Adc,
+        angle_hor: i32,
+        angle_ver: i32,
+    ) -> Result<(), LightTrackingError>
+    where
+        Word: Copy + Into<u32> + PartialEq + PartialOrd,
+        Pin1: Channel<ADC>,
+        Pin2: Channel<ADC>,
+        Pin3: Channel<ADC>,
+        Adc: OneShot<ADC, Word, Pin1> + OneShot<ADC, Word, Pin2> + OneShot<ADC, Word, Pin3>;
+
     fn follow_light<ADC, Adc>(&mut self, adc: &mut Adc) -> Result<u32, LightTrackingError>
     where
         Word: Copy + Into<u32> + PartialEq + PartialOrd,
@@ -164,6 +246,8 @@ pub struct Platform<
     interpolator_ir_sensor: AdcInterpolator<Pin1, Word, LENGTH>,
     interpolator_photoresistor: AdcInterpolator<Pin2, Word, LENGTH>,
     interpolator_button: AdcInterpolator<Pin3, Word, LENGTH>,
+    position_sensor_hor: Option<AxisSensor>,
+    position_sensor_ver: Option<AxisSensor>,
 
     last_angle_hor: i32,
     last_angle_ver: i32,
@@ -229,12 +313,22 @@ impl<
             interpolator_ir_sensor,
             interpolator_photoresistor,
             interpolator_button,
+            position_sensor_hor: None,
+            position_sensor_ver: None,
             last_angle_hor: 0,
             last_angle_ver: 0,
             hor_direction: Direction::None,
         }
     }
 
+    fn with_position_sensor(mut self, axis: Axis, sensor: AxisSensor) -> Self {
+        match axis {
+            Axis::Horizontal => self.position_sensor_hor = Some(sensor),
+            Axis::Vertical => self.position_sensor_ver = Some(sensor),
+        }
+        self
+    }
+
     fn reset_motors_position(&mut self) {
         self.stepper_motor_hor.rotate_to_angle(Speed::High, 0);
         self.stepper_motor_hor.stop_motor();
@@ -243,7 +337,7 @@ impl<
         self.hor_direction = Direction::None;
     }
 
-    fn reset_if_button_pressed<Adc, ADC>(&mut self, adc: &mut Adc) -> bool
+    fn is_button_pressed<Adc, ADC>(&mut self, adc: &mut Adc) -> bool
     where
         Word: Copy + Into<u32> + PartialEq + PartialOrd,
         Pin1: Channel<ADC>,
@@ -258,7 +352,18 @@ impl<
             .unwrap()
             .expect("Interpolation of infrared sensor failed");
 
-        if value < 1500 {
+        value < 1500
+    }
+
+    fn reset_if_button_pressed<Adc, ADC>(&mut self, adc: &mut Adc) -> bool
+    where
+        Word: Copy + Into<u32> + PartialEq + PartialOrd,
+        Pin1: Channel<ADC>,
+        Pin2: Channel<ADC>,
+        Pin3: Channel<ADC>,
+        Adc: OneShot<ADC, Word, Pin1> + OneShot<ADC, Word, Pin2> + OneShot<ADC, Word, Pin3>,
+    {
+        if self.is_button_pressed(adc) {
             self.reset_motors_position();
             true
         } else {
@@ -273,6 +378,103 @@ impl<
         }
     }
 
+    fn get_max_angles(&self) -> MotorAngles {
+        MotorAngles {
+            motor_hor: self.stepper_motor_hor.max_angle(),
+            motor_ver: self.stepper_motor_ver.max_angle(),
+        }
+    }
+
+    fn restore_angles(&mut self, angles: &MotorAngles) {
+        self.stepper_motor_hor.restore_angle(angles.motor_hor);
+        self.stepper_motor_ver.restore_angle(angles.motor_ver);
+        reference_sensor(self.position_sensor_hor.as_mut(), angles.motor_hor);
+        reference_sensor(self.position_sensor_ver.as_mut(), angles.motor_ver);
+    }
+
+    fn get_backlash(&self) -> MotorAngles {
+        MotorAngles {
+            motor_hor: self.stepper_motor_hor.backlash(),
+            motor_ver: self.stepper_motor_ver.backlash(),
+        }
+    }
+
+    fn set_backlash(&mut self, backlash: &MotorAngles) {
+        self.stepper_motor_hor.set_backlash(backlash.motor_hor);
+        self.stepper_motor_ver.set_backlash(backlash.motor_ver);
+    }
+
+    fn calibrate_backlash<ADC, Adc>(
+        &mut self,
+        adc: &mut Adc,
+    ) -> Result<Option<MotorAngles>, LightTrackingError>
+    where
+        Word: Copy + Into<u32> + PartialEq + PartialOrd,
+        Pin1: Channel<ADC>,
+        Pin2: Channel<ADC>,
+        Pin3: Channel<ADC>,
+        Adc: OneShot<ADC, Word, Pin1> + OneShot<ADC, Word, Pin2> + OneShot<ADC, Word, Pin3>,
+    {
+        let previous_backlash = self.get_backlash();
+        // The sweeps measure the slack, it must not be taken up
+        self.set_backlash(&MotorAngles::default());
+
+        let init_angle_hor = self.stepper_motor_hor.current_angle();
+        let init_angle_ver = self.stepper_motor_ver.current_angle();
+
+        // Starting above the light, the downward sweep sees it at its angle and the upward sweep
+        // sees it the backlash later
+        let low = (init_angle_hor - BACKLASH_SWEEP).max(0);
+        let high = (init_angle_hor + BACKLASH_SWEEP).min(self.stepper_motor_hor.max_angle());
+        self.stepper_motor_hor
+            .rotate_to_angle(Speed::HighMedium, high);
+        let mut downward = Vec::new();
+        for angle in (low..=high).rev() {
+            let angle_hor = self.stepper_motor_hor.rotate_to_angle(Speed::Medium, angle);
+            downward.push((angle_hor, self.read_photoresistor(adc)?));
+        }
+        let mut upward = Vec::new();
+        for angle in low..=high {
+            let angle_hor = self.stepper_motor_hor.rotate_to_angle(Speed::Medium, angle);
+            upward.push((angle_hor, self.read_photoresistor(adc)?));
+        }
+        self.stepper_motor_hor.stop_motor();
+        let backlash_hor = measure_backlash(&upward, &downward);
+        log::info!("Measured horizontal backlash {:?}", backlash_hor);
+
+        let low = (init_angle_ver - BACKLASH_SWEEP).max(0);
+        let high = (init_angle_ver + BACKLASH_SWEEP).min(self.stepper_motor_ver.max_angle());
+        self.stepper_motor_ver
+            .rotate_to_angle(Speed::HighMedium, high);
+        let mut downward = Vec::new();
+        for angle in (low..=high).rev() {
+            let angle_ver = self.stepper_motor_ver.rotate_to_angle(Speed::Medium, angle);
+            downward.push((angle_ver, self.read_photoresistor(adc)?));
+        }
+        let mut upward = Vec::new();
+        for angle in low..=high {
+            let angle_ver = self.stepper_motor_ver.rotate_to_angle(Speed::Medium, angle);
+            upward.push((angle_ver, self.read_photoresistor(adc)?));
+        }
+        self.stepper_motor_ver.stop_motor();
+        let backlash_ver = measure_backlash(&upward, &downward);
+        log::info!("Measured vertical backlash {:?}", backlash_ver);
+
+        let backlash = match (backlash_hor, backlash_ver) {
+            (Some(motor_hor), Some(motor_ver)) => Some(MotorAngles {
+                motor_hor,
+                motor_ver,
+            }),
+            _ => None,
+        };
+        self.set_backlash(backlash.as_ref().unwrap_or(&previous_backlash));
+        // Back to the light, now with the compensation
+        self.rotate_to_angle(init_angle_ver, init_angle_hor, Speed::HighMedium);
+        self.hor_direction = Direction::None;
+
+        Ok(backlash)
+    }
+
     fn test_movement(&mut self) {
         let mut current_angle = 0;
 
@@ -304,10 +506,27 @@ impl<
     }
 
     fn rotate_to_angle(&mut self, ver_angle: i32, hor_angle: i32, speed: Speed) {
-        self.stepper_motor_ver.rotate_to_angle(speed, ver_angle);
-        self.stepper_motor_ver.stop_motor();
-        self.stepper_motor_hor.rotate_to_angle(speed, hor_angle);
-        self.stepper_motor_hor.stop_motor();
+        // Without position sensors nothing is corrected, the first move is the only one
+        for _ in 0..=MAX_CORRECTION_MOVES {
+            self.stepper_motor_ver.rotate_to_angle(speed, ver_angle);
+            self.stepper_motor_ver.stop_motor();
+            self.stepper_motor_hor.rotate_to_angle(speed, hor_angle);
+            self.stepper_motor_hor.stop_motor();
+
+            let corrected_ver = correct_angle(
+                &mut self.stepper_motor_ver,
+                self.position_sensor_ver.as_mut(),
+                "vertical",
+            );
+            let corrected_hor = correct_angle(
+                &mut self.stepper_motor_hor,
+                self.position_sensor_hor.as_mut(),
+                "horizontal",
+            );
+            if !corrected_ver && !corrected_hor {
+                break;
+            }
+        }
     }
 
     fn init_motors<Adc, ADC>(&mut self, adc: &mut Adc) -> Result<(), LightTrackingError>
@@ -320,30 +539,80 @@ impl<
     {
         log::info!("Initiating motors position");
 
-        let ir_sensor_data_close: u32 = 1500;
-
         /*
         // init stepper_motor_ver angle
         log::info!("Rotating vertical until IR sensor hits");
-        while self.read_ir(adc)? > ir_sensor_data_close {
+        while self.read_ir(adc)? > IR_SENSOR_DATA_CLOSE {
             self.stepper_motor_ver.rotate_right(Speed::Low);
         }
         self.stepper_motor_ver.stop_motor();
         self.stepper_motor_ver.init_angle();
         */
 
-        // init stepper_motor_hor angle
+        // init stepper_motor_hor angle, the counted angle may be wrong, e.g. after lost steps
+        self.stepper_motor_hor.release_angle();
         log::info!("Rotating horizontal until IR sensor hits");
-        while self.read_ir(adc)? > ir_sensor_data_close {
+        while self.read_ir(adc)? > IR_SENSOR_DATA_CLOSE {
             self.stepper_motor_hor.rotate_right(Speed::Low);
         }
         self.stepper_motor_hor.stop_motor();
         self.stepper_motor_hor.init_angle();
 
+        reference_sensor(self.position_sensor_hor.as_mut(), 0);
+        reference_sensor(
+            self.position_sensor_ver.as_mut(),
+            self.stepper_motor_ver.current_angle(),
+        );
+
         log::info!("Initiating motors finished");
         Ok(())
     }
 
+    fn verify_position<ADC, Adc>(
+        &mut self,
+        adc: &mut Adc,
+        tolerance: i32,
+    ) -> Result<bool, LightTrackingError>
+    where
+        Word: Copy + Into<u32> + PartialEq + PartialOrd,
+        Pin1: Channel<ADC>,
+        Pin2: Channel<ADC>,
+        Pin3: Channel<ADC>,
+        Adc: OneShot<ADC, Word, Pin1> + OneShot<ADC, Word, Pin2> + OneShot<ADC, Word, Pin3>,
+    {
+        let init_angle_hor = self.stepper_motor_hor.current_angle();
+
+        // Turning right like the homing, the mark is seen at the same edge
+        let mut angle = init_angle_hor;
+        while self.read_ir(adc)? > IR_SENSOR_DATA_CLOSE {
+            if angle <= -tolerance {
+                self.stepper_motor_hor.stop_motor();
+                log::warn!("Reference mark not found {} steps past angle 0", tolerance);
+                return Ok(false);
+            }
+            if angle == 0 {
+                // The motor missed steps turning left, search on beyond the angle 0
+                self.stepper_motor_hor.release_angle();
+            }
+            self.stepper_motor_hor.rotate_right(Speed::HighMedium);
+            angle -= self.stepper_motor_hor.step_size();
+        }
+        self.stepper_motor_hor.stop_motor();
+
+        if angle > tolerance {
+            log::warn!("Reached the reference mark at angle {}", angle);
+            return Ok(false);
+        }
+        log::info!("Confirmed the reference mark at angle {}", angle);
+        self.stepper_motor_hor.init_angle();
+        reference_sensor(self.position_sensor_hor.as_mut(), 0);
+        self.stepper_motor_hor
+            .rotate_to_angle(Speed::HighMedium, init_angle_hor);
+        self.stepper_motor_hor.stop_motor();
+
+        Ok(true)
+    }
+
     fn find_best_position<ADC, Adc>(&mut self, adc: &mut Adc) -> Result<(), LightTrackingError>
     where
         Word: Copy + Into<u32> + PartialEq + PartialOrd,
@@ -470,6 +739,24 @@ impl<
         Ok(())
     }
 
+    fn refine_position<ADC, Adc>(
+        &mut self,
+        adc: &mut Adc,
+        angle_hor: i32,
+        angle_ver: i32,
+    ) -> Result<(), LightTrackingError>
+    where
+        Word: Copy + Into<u32> + PartialEq + PartialOrd,
+        Pin1: Channel<ADC>,
+        Pin2: Channel<ADC>,
+        Pin3: Channel<ADC>,
+        Adc: OneShot<ADC, Word, Pin1> + OneShot<ADC, Word, Pin2> + OneShot<ADC, Word, Pin3>,
+    {
+        // The direction of the last search says nothing about the error of a computed position
+        self.hor_direction = Direction::None;
+        self.search_scope(adc, Speed::Medium, angle_hor, angle_ver)
+    }
+
     fn follow_light<ADC, Adc>(&mut self, adc: &mut Adc) -> Result<u32, LightTrackingError>
     where
         Word: Copy + Into<u32> + PartialEq + PartialOrd,
@@ -535,3 +822,79 @@ impl<
             .expect("Interpolation of photoresistor sensor failed"))
     }
 }
+
+/// Backlash measured by `calibrate_backlash`, kept in NVS ("motor" namespace)
+pub fn load_backlash() -> Option<MotorAngles> {
+    let nvs = Nvs::open("motor").ok()?;
+    Some(MotorAngles {
+        motor_hor: nvs.get_u32("backlash_hor").ok()?? as i32,
+        motor_ver: nvs.get_u32("backlash_ver").ok()?? as i32,
+    })
+}
+
+pub fn store_backlash(backlash: &MotorAngles) -> Result<(), EspError> {
+    let mut nvs = Nvs::open("motor")?;
+    nvs.set_u32("backlash_hor", backlash.motor_hor as u32)?;
+    nvs.set_u32("backlash_ver", backlash.motor_ver as u32)
+}
+
+/// Takes the current count of the position sensor as `angle`, e.g. after homing
+fn reference_sensor(sensor: Option<&mut AxisSensor>, angle: i32) {
+    if let Some(sensor) = sensor {
+        if let Err(e) = sensor.set_reference(angle) {
+            log::warn!("Referencing the position sensor failed: {:?}", e);
+        }
+    }
+}
+
+/// Takes over the measured angle if the motor missed steps, returns whether it was corrected
+///
+/// Without a sensor, or if reading it fails, the counted angle is kept.
+fn correct_angle<M1: OutputPin, M2: OutputPin, M3: OutputPin, M4: OutputPin>(
+    motor: &mut StepperMotor<M1, M2, M3, M4>,
+    sensor: Option<&mut AxisSensor>,
+    axis: &str,
+) -> bool {
+    let counted = motor.current_angle();
+    let measured = match sensor.map(|sensor| sensor.measure(counted)) {
+        Some(Ok(Some(measured))) => measured,
+        Some(Err(e)) => {
+            log::warn!("Reading the {} position sensor failed: {:?}", axis, e);
+            return false;
+        }
+        _ => return false,
+    };
+    if (measured - counted).abs() <= POSITION_SENSOR_TOLERANCE {
+        return false;
+    }
+
+    log::warn!(
+        "Measured {} angle {} instead of {}, correcting",
+        axis,
+        measured,
+        counted
+    );
+    motor.restore_angle(measured);
+    true
+}
+
+/// Difference of the angles where the sweeps up and down saw the most light
+///
+/// `None` if the light was at the border of a sweep or the difference is implausible.
+fn measure_backlash(upward: &[(i32, u32)], downward: &[(i32, u32)]) -> Option<i32> {
+    let backlash = brightest_angle(upward)? - brightest_angle(downward)?;
+    (0..=MAX_BACKLASH).contains(&backlash).then(|| backlash)
+}
+
+/// Center of the angles with the most light, i.e. the lowest photoresistor reading
+fn brightest_angle(readings: &[(i32, u32)]) -> Option<i32> {
+    let brightest = readings.iter().map(|(_, light)| *light).min()?;
+    let first = readings.iter().position(|(_, light)| *light == brightest)?;
+    let last = readings
+        .iter()
+        .rposition(|(_, light)| *light == brightest)?;
+    if first == 0 || last == readings.len() - 1 {
+        return None;
+    }
+    Some((readings[first].0 + readings[last].0) / 2)
+}
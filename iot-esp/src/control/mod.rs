@@ -1 +1,8 @@
+pub mod daycycle;
+pub mod hybrid;
+pub mod kinematics;
 pub mod lighttracking;
+pub mod mount;
+pub mod schedule;
+pub mod solar;
+pub mod verification;
@@ -0,0 +1,496 @@
+use std::convert::TryInto;
+use std::f64::consts::PI;
+use std::time::{Duration, SystemTime};
+
+use esp_idf_sys::EspError;
+
+use super::solar::SolarPosition;
+use crate::storage::nvs::Nvs;
+
+// Observations kept for the fit, the oldest are replaced so the model follows a settling mount
+const MAX_OBSERVATIONS: usize = 32;
+// Yaw, pitch, roll and the zero altitude need observations along the course of the sun
+const MIN_OBSERVATIONS: usize = 6;
+const MIN_AZIMUTH_SPAN: f64 = PI / 4.0;
+// Close to the horizon the light search is unreliable
+const MIN_SUN_ALTITUDE: f32 = 10.0 * std::f32::consts::PI / 180.0;
+// Seconds between two observations, so they spread over the day
+const OBSERVATION_INTERVAL: u64 = 30 * 60;
+// Fits deviating more on average are rejected, e.g. when clouds misled the light searches
+const MAX_RMS_ERROR: f64 = 2.0 * PI / 180.0;
+const FIT_ITERATIONS: usize = 20;
+// Parameter change for the numerical derivatives
+const DERIVATIVE_STEP: f64 = 1e-6;
+// time + azimuth and altitude of the motors + azimuth and altitude of the sun
+const OBSERVATION_SIZE: usize = 8 + 4 * 4;
+// yaw + pitch + roll + zero altitude
+const MODEL_SIZE: usize = 4 * 4;
+
+/// Orientation of the mount fitted from observations of the sun, all angles in radians
+///
+/// The yaw is the azimuth the platform faces at the horizontal motor angle 0, the pitch tilts the
+/// mount about the east-west axis and the roll about the north-south axis. The zero altitude is
+/// the altitude the platform faces at the vertical motor angle 0 on a level mount.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MountModel {
+    pub yaw: f32,
+    pub pitch: f32,
+    pub roll: f32,
+    pub zero_altitude: f32,
+}
+
+impl MountModel {
+    /// Model stored in NVS ("mount" namespace), `None` until the mount was calibrated
+    pub fn load() -> Option<MountModel> {
+        let blob = Nvs::open("mount").ok()?.get_blob("model").ok().flatten()?;
+        if blob.len() != MODEL_SIZE {
+            return None;
+        }
+        let value = |i: usize| f32::from_le_bytes(blob[4 * i..4 * i + 4].try_into().unwrap());
+        Some(MountModel {
+            yaw: value(0),
+            pitch: value(1),
+            roll: value(2),
+            zero_altitude: value(3),
+        })
+    }
+
+    pub fn store(&self) -> Result<(), EspError> {
+        let mut blob = Vec::with_capacity(MODEL_SIZE);
+        for value in [self.yaw, self.pitch, self.roll, self.zero_altitude].iter() {
+            blob.extend_from_slice(&value.to_le_bytes());
+        }
+        Nvs::open("mount")?.set_blob("model", &blob)
+    }
+
+    pub fn erase() -> Result<(), EspError> {
+        Nvs::open("mount")?.erase_key("model")
+    }
+
+    /// Azimuth and altitude of `position` as seen by the motors of the mount
+    pub fn apply(&self, position: &SolarPosition) -> SolarPosition {
+        let (azimuth, altitude) = predict(
+            &[
+                self.yaw as f64,
+                self.pitch as f64,
+                self.roll as f64,
+                self.zero_altitude as f64,
+            ],
+            position.azimuth as f64,
+            position.altitude as f64,
+        );
+        SolarPosition {
+            azimuth: azimuth as f32,
+            altitude: altitude as f32,
+        }
+    }
+}
+
+/// Direction of the platform at the best light and the calculated position of the sun
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Observation {
+    /// Unix time in seconds
+    pub time: u64,
+    /// Motor angles converted to azimuth and altitude of an uncalibrated mount
+    pub motors: SolarPosition,
+    pub sun: SolarPosition,
+}
+
+/// Observations of the sun collected for fitting the mount model by least squares
+///
+/// A single light search is biased by the sensors and diffuse light, so the model is only fitted
+/// from observations spread over the day. They are kept in NVS ("mount" namespace).
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MountCalibration {
+    observations: Vec<Observation>,
+}
+
+impl MountCalibration {
+    pub fn load() -> MountCalibration {
+        let blob = Nvs::open("mount")
+            .and_then(|nvs| nvs.get_blob("observations"))
+            .ok()
+            .flatten()
+            .filter(|blob| blob.len() % OBSERVATION_SIZE == 0)
+            .unwrap_or_default();
+        let observations = blob
+            .chunks(OBSERVATION_SIZE)
+            .map(|chunk| {
+                let value =
+                    |i: usize| f32::from_le_bytes(chunk[8 + 4 * i..12 + 4 * i].try_into().unwrap());
+                Observation {
+                    time: u64::from_le_bytes(chunk[0..8].try_into().unwrap()),
+                    motors: SolarPosition {
+                        azimuth: value(0),
+                        altitude: value(1),
+                    },
+                    sun: SolarPosition {
+                        azimuth: value(2),
+                        altitude: value(3),
+                    },
+                }
+            })
+            .collect();
+        MountCalibration { observations }
+    }
+
+    pub fn store(&self) -> Result<(), EspError> {
+        let mut blob = Vec::with_capacity(self.observations.len() * OBSERVATION_SIZE);
+        for observation in &self.observations {
+            blob.extend_from_slice(&observation.time.to_le_bytes());
+            blob.extend_from_slice(&observation.motors.azimuth.to_le_bytes());
+            blob.extend_from_slice(&observation.motors.altitude.to_le_bytes());
+            blob.extend_from_slice(&observation.sun.azimuth.to_le_bytes());
+            blob.extend_from_slice(&observation.sun.altitude.to_le_bytes());
+        }
+        Nvs::open("mount")?.set_blob("observations", &blob)
+    }
+
+    /// Drops all observations, e.g. after the platform was moved
+    pub fn clear(&mut self) {
+        self.observations.clear();
+    }
+
+    /// Adds an observation, returns false if it was skipped
+    ///
+    /// Observations are taken every 30 minutes at most, with the sun well above the horizon.
+    pub fn observe(&mut self, time: SystemTime, motors: SolarPosition, sun: SolarPosition) -> bool {
+        let time = time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or(Duration::from_secs(0))
+            .as_secs();
+        let too_soon = self
+            .observations
+            .last()
+            .map_or(false, |last| time < last.time + OBSERVATION_INTERVAL);
+        if too_soon || sun.altitude < MIN_SUN_ALTITUDE {
+            return false;
+        }
+
+        if self.observations.len() == MAX_OBSERVATIONS {
+            self.observations.remove(0);
+        }
+        self.observations.push(Observation { time, motors, sun });
+        log::info!(
+            "MountCalibration: Observed the sun at {:?} with the motors at {:?}",
+            sun,
+            motors
+        );
+        true
+    }
+
+    /// Fits the mount model by least squares, `None` if the observations are not sufficient
+    pub fn fit(&self) -> Option<MountModel> {
+        if self.observations.len() < MIN_OBSERVATIONS || self.azimuth_span() < MIN_AZIMUTH_SPAN {
+            return None;
+        }
+
+        // Start with a level mount facing the first observation
+        let first = &self.observations[0];
+        let mut params = [
+            (first.sun.azimuth - first.motors.azimuth) as f64,
+            0.0,
+            0.0,
+            (first.sun.altitude - first.motors.altitude) as f64,
+        ];
+        // Gauss-Newton with numerical derivatives, the model is almost linear for small tilts
+        for _ in 0..FIT_ITERATIONS {
+            let residuals = self.residuals(&params);
+            // Columns of the jacobian, the derivatives of the residuals by each parameter
+            let jacobian = (0..4)
+                .map(|index| {
+                    self.shifted_residuals(&params, index)
+                        .iter()
+                        .zip(&residuals)
+                        .map(|(shifted, residual)| (shifted - residual) / DERIVATIVE_STEP)
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+
+            // Normal equations JᵀJ·Δ = -Jᵀr
+            let dot = |a: &[f64], b: &[f64]| a.iter().zip(b).map(|(a, b)| a * b).sum::<f64>();
+            let mut normal = [[0.0; 4]; 4];
+            let mut rhs = [0.0; 4];
+            for (i, column) in jacobian.iter().enumerate() {
+                for (j, other) in jacobian.iter().enumerate() {
+                    normal[i][j] = dot(column, other);
+                }
+                rhs[i] = -dot(column, &residuals);
+            }
+            let delta = solve(normal, rhs)?;
+            for (param, delta) in params.iter_mut().zip(delta.iter()) {
+                *param += delta;
+            }
+            if delta.iter().all(|delta| delta.abs() < 1e-9) {
+                break;
+            }
+        }
+
+        let residuals = self.residuals(&params);
+        let rms_error =
+            (residuals.iter().map(|r| r * r).sum::<f64>() / self.observations.len() as f64).sqrt();
+        if !rms_error.is_finite() || rms_error > MAX_RMS_ERROR {
+            log::warn!(
+                "MountCalibration: Rejecting fit with an error of {:.2}°",
+                rms_error.to_degrees()
+            );
+            return None;
+        }
+
+        let model = MountModel {
+            yaw: wrap_angle(params[0]) as f32,
+            pitch: params[1] as f32,
+            roll: params[2] as f32,
+            zero_altitude: params[3] as f32,
+        };
+        log::info!(
+            "MountCalibration: Fitted {:?} from {} observations, error {:.2}°",
+            model,
+            self.observations.len(),
+            rms_error.to_degrees()
+        );
+        Some(model)
+    }
+
+    fn azimuth_span(&self) -> f64 {
+        let azimuths = self.observations.iter().map(|o| o.sun.azimuth as f64);
+        let min = azimuths.clone().fold(f64::INFINITY, f64::min);
+        let max = azimuths.fold(f64::NEG_INFINITY, f64::max);
+        max - min
+    }
+
+    /// Azimuth and altitude errors of the model, the azimuth error scaled to an angle on the sky
+    fn residuals(&self, params: &[f64; 4]) -> Vec<f64> {
+        let mut residuals = Vec::with_capacity(2 * self.observations.len());
+        for observation in &self.observations {
+            let (azimuth, altitude) = predict(
+                params,
+                observation.sun.azimuth as f64,
+                observation.sun.altitude as f64,
+            );
+            let motors_altitude = observation.motors.altitude as f64;
+            residuals.push(
+                wrap_angle(azimuth - observation.motors.azimuth as f64) * motors_altitude.cos(),
+            );
+            residuals.push(altitude - motors_altitude);
+        }
+        residuals
+    }
+
+    fn shifted_residuals(&self, params: &[f64; 4], index: usize) -> Vec<f64> {
+        let mut shifted = *params;
+        shifted[index] += DERIVATIVE_STEP;
+        self.residuals(&shifted)
+    }
+}
+
+/// Azimuth and altitude seen by the motors for the sun at `azimuth` and `altitude`
+///
+/// The sun is rotated from the world, x facing south, y west and z up, into the tilted mount.
+fn predict(params: &[f64; 4], azimuth: f64, altitude: f64) -> (f64, f64) {
+    let [yaw, pitch, roll, zero_altitude] = *params;
+    let (x, y, z) = (
+        altitude.cos() * azimuth.cos(),
+        altitude.cos() * azimuth.sin(),
+        altitude.sin(),
+    );
+    // Undo yaw about z, pitch about y and roll about x
+    let (x, y) = rotate(x, y, -yaw);
+    let (z, x) = rotate(z, x, -pitch);
+    let (y, z) = rotate(y, z, -roll);
+    (
+        wrap_angle(y.atan2(x)),
+        z.clamp(-1.0, 1.0).asin() - zero_altitude,
+    )
+}
+
+fn rotate(a: f64, b: f64, angle: f64) -> (f64, f64) {
+    let (sin, cos) = angle.sin_cos();
+    (a * cos - b * sin, a * sin + b * cos)
+}
+
+/// Solves the 4×4 linear system by gaussian elimination, `None` if it is singular
+fn solve(mut matrix: [[f64; 4]; 4], mut rhs: [f64; 4]) -> Option<[f64; 4]> {
+    for column in 0..4 {
+        let pivot = (column..4).max_by(|&a, &b| {
+            matrix[a][column]
+                .abs()
+                .partial_cmp(&matrix[b][column].abs())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })?;
+        if matrix[pivot][column].abs() < 1e-12 {
+            return None;
+        }
+        matrix.swap(column, pivot);
+        rhs.swap(column, pivot);
+
+        let pivot_row = matrix[column];
+        for row in column + 1..4 {
+            let factor = matrix[row][column] / pivot_row[column];
+            for (value, pivot_value) in matrix[row].iter_mut().zip(&pivot_row).skip(column) {
+                *value -= factor * pivot_value;
+            }
+            rhs[row] -= factor * rhs[column];
+        }
+    }
+
+    let mut solution = [0.0; 4];
+    for row in (0..4).rev() {
+        let sum = (row + 1..4)
+            .map(|i| matrix[row][i] * solution[i])
+            .sum::<f64>();
+        solution[row] = (rhs[row] - sum) / matrix[row][row];
+    }
+    Some(solution)
+}
+
+/// Wraps an angle in radians into -π..π
+fn wrap_angle(angle: f64) -> f64 {
+    (angle + PI).rem_euclid(2.0 * PI) - PI
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNT: [f64; 4] = [0.3, 0.05, -0.08, 0.02];
+
+    fn position(azimuth: f64, altitude: f64) -> SolarPosition {
+        SolarPosition {
+            azimuth: azimuth as f32,
+            altitude: altitude as f32,
+        }
+    }
+
+    // Observations along the course of the sun from `from` to `to` azimuth, seen by the mount
+    fn course(params: &[f64; 4], from: f64, to: f64) -> Vec<Observation> {
+        (0..8)
+            .map(|index| {
+                let azimuth = from + (to - from) * index as f64 / 7.0;
+                let altitude = 0.3 + 0.6 * azimuth.cos();
+                let (motors_azimuth, motors_altitude) = predict(params, azimuth, altitude);
+                Observation {
+                    time: 1_656_000_000 + index as u64 * OBSERVATION_INTERVAL,
+                    motors: position(motors_azimuth, motors_altitude),
+                    sun: position(azimuth, altitude),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn predict_is_identity_for_a_level_mount() {
+        let (azimuth, altitude) = predict(&[0.0; 4], -2.5, 0.7);
+        assert!((azimuth + 2.5).abs() < 1e-12);
+        assert!((altitude - 0.7).abs() < 1e-12);
+
+        // The yaw and zero altitude offset the angles directly
+        let (azimuth, altitude) = predict(&[0.3, 0.0, 0.0, 0.1], 3.0, 0.7);
+        assert!((azimuth - wrap_angle(3.0 - 0.3)).abs() < 1e-12);
+        assert!((altitude - 0.6).abs() < 1e-12);
+    }
+
+    #[test]
+    fn fit_recovers_the_mount() {
+        let calibration = MountCalibration {
+            observations: course(&MOUNT, -1.2, 1.2),
+        };
+        let model = calibration.fit().unwrap();
+        let fitted = [model.yaw, model.pitch, model.roll, model.zero_altitude];
+        for (fitted, expected) in fitted.iter().zip(MOUNT.iter()) {
+            assert!(
+                (*fitted as f64 - expected).abs() < 1e-4,
+                "{:?} != {:?}",
+                fitted,
+                MOUNT
+            );
+        }
+
+        // The model maps the sun onto the motors of the mount
+        let sun = position(0.4, 0.5);
+        let (azimuth, altitude) = predict(&MOUNT, 0.4, 0.5);
+        let applied = model.apply(&sun);
+        assert!((applied.azimuth as f64 - azimuth).abs() < 1e-4);
+        assert!((applied.altitude as f64 - altitude).abs() < 1e-4);
+    }
+
+    #[test]
+    fn fit_wraps_the_yaw() {
+        let mount = [3.0, 0.0, 0.05, 0.0];
+        let calibration = MountCalibration {
+            observations: course(&mount, -1.0, 1.0),
+        };
+        let model = calibration.fit().unwrap();
+        assert!((model.yaw as f64 - 3.0).abs() < 1e-4);
+        assert!((model.roll as f64 - 0.05).abs() < 1e-4);
+    }
+
+    #[test]
+    fn fit_requires_observations_along_the_course_of_the_sun() {
+        let mut observations = course(&MOUNT, -1.2, 1.2);
+        observations.truncate(MIN_OBSERVATIONS - 1);
+        assert_eq!(MountCalibration { observations }.fit(), None);
+
+        let narrow = MountCalibration {
+            observations: course(&MOUNT, 0.0, MIN_AZIMUTH_SPAN * 0.9),
+        };
+        assert!(narrow.azimuth_span() < MIN_AZIMUTH_SPAN);
+        assert_eq!(narrow.fit(), None);
+    }
+
+    #[test]
+    fn fit_rejects_noisy_observations() {
+        let mut observations = course(&MOUNT, -1.2, 1.2);
+        // Light searches off by 5° in alternating directions, e.g. misled by clouds
+        for (index, observation) in observations.iter_mut().enumerate() {
+            let noise = if index % 2 == 0 { 5.0 } else { -5.0 };
+            observation.motors.altitude += (noise as f32).to_radians();
+        }
+        assert_eq!(MountCalibration { observations }.fit(), None);
+    }
+
+    #[test]
+    fn observe_spreads_observations_over_the_day() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_656_000_000);
+        let sun = position(0.0, 0.5);
+        let mut calibration = MountCalibration::default();
+        assert!(calibration.observe(start, sun, sun));
+        assert!(!calibration.observe(start + Duration::from_secs(60), sun, sun));
+        let later = start + Duration::from_secs(OBSERVATION_INTERVAL);
+        assert!(!calibration.observe(later, sun, position(0.0, 0.1)));
+        assert!(calibration.observe(later, sun, sun));
+
+        for index in 2..MAX_OBSERVATIONS as u64 + 2 {
+            let time = start + Duration::from_secs(index * OBSERVATION_INTERVAL);
+            assert!(calibration.observe(time, sun, sun));
+        }
+        assert_eq!(calibration.observations.len(), MAX_OBSERVATIONS);
+        assert_eq!(
+            calibration.observations[0].time,
+            1_656_000_000 + 2 * OBSERVATION_INTERVAL
+        );
+    }
+
+    #[test]
+    fn solve_solves_linear_systems() {
+        let matrix = [
+            [0.0, 2.0, 0.0, 1.0],
+            [4.0, 0.0, 1.0, 0.0],
+            [0.0, 1.0, 3.0, 0.0],
+            [1.0, 0.0, 0.0, 5.0],
+        ];
+        let expected = [1.0, -2.0, 0.5, 3.0];
+        let mut rhs = [0.0; 4];
+        for (value, row) in rhs.iter_mut().zip(matrix.iter()) {
+            *value = row.iter().zip(expected.iter()).map(|(a, b)| a * b).sum();
+        }
+        let solution = solve(matrix, rhs).unwrap();
+        for (solved, expected) in solution.iter().zip(expected.iter()) {
+            assert!((solved - expected).abs() < 1e-12);
+        }
+
+        let mut singular = matrix;
+        singular[3] = singular[1];
+        assert_eq!(solve(singular, rhs), None);
+    }
+}
@@ -0,0 +1,238 @@
+use std::convert::TryInto;
+use std::f32::consts::PI;
+use std::time::{Duration, SystemTime};
+
+use esp_idf_sys::EspError;
+
+use super::solar::SolarPosition;
+use crate::storage::nvs::Nvs;
+
+// time + azimuth + altitude
+const WAYPOINT_SIZE: usize = 8 + 4 + 4;
+
+/// Position of the sun at a unix time in seconds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Waypoint {
+    pub time: u64,
+    pub position: SolarPosition,
+}
+
+/// Table of solar positions uploaded by the edge, e.g. for a day
+///
+/// The positions between the waypoints are interpolated, the table expires after the last
+/// waypoint. It is identified by the time of the first waypoint, which the schedule command refers
+/// to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schedule {
+    waypoints: Vec<Waypoint>,
+}
+
+impl Schedule {
+    /// Parses the waypoints as served by the edge, they must be in chronological order
+    pub fn parse(payload: &[u8]) -> Option<Schedule> {
+        if payload.len() % WAYPOINT_SIZE != 0 || payload.len() < 2 * WAYPOINT_SIZE {
+            return None;
+        }
+
+        let waypoints = payload
+            .chunks(WAYPOINT_SIZE)
+            .map(|chunk| Waypoint {
+                time: u64::from_le_bytes(chunk[0..8].try_into().unwrap()),
+                position: SolarPosition {
+                    azimuth: f32::from_le_bytes(chunk[8..12].try_into().unwrap()),
+                    altitude: f32::from_le_bytes(chunk[12..16].try_into().unwrap()),
+                },
+            })
+            .collect::<Vec<_>>();
+        if waypoints
+            .windows(2)
+            .any(|pair| pair[0].time >= pair[1].time)
+        {
+            return None;
+        }
+
+        Some(Schedule { waypoints })
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(self.waypoints.len() * WAYPOINT_SIZE);
+        for waypoint in &self.waypoints {
+            payload.extend_from_slice(&waypoint.time.to_le_bytes());
+            payload.extend_from_slice(&waypoint.position.azimuth.to_le_bytes());
+            payload.extend_from_slice(&waypoint.position.altitude.to_le_bytes());
+        }
+        payload
+    }
+
+    pub fn id(&self) -> u64 {
+        self.waypoints[0].time
+    }
+
+    /// Interpolated position of the sun, `None` before the first and after the last waypoint
+    pub fn position(&self, time: SystemTime) -> Option<SolarPosition> {
+        let time = unix_time(time);
+        let next = self
+            .waypoints
+            .iter()
+            .position(|waypoint| waypoint.time as f64 >= time)?;
+        if next == 0 {
+            return (self.waypoints[0].time as f64 == time).then(|| self.waypoints[0].position);
+        }
+
+        let (from, to) = (&self.waypoints[next - 1], &self.waypoints[next]);
+        let fraction = ((time - from.time as f64) / (to.time - from.time) as f64) as f32;
+        // The azimuth wraps around in the north, take the shorter way
+        let azimuth_change =
+            (to.position.azimuth - from.position.azimuth + PI).rem_euclid(2.0 * PI) - PI;
+        Some(SolarPosition {
+            azimuth: wrap_angle(from.position.azimuth + fraction * azimuth_change),
+            altitude: from.position.altitude
+                + fraction * (to.position.altitude - from.position.altitude),
+        })
+    }
+}
+
+/// Schedule stored in NVS ("schedule" namespace), it survives reboots and deep sleep
+pub fn load_schedule() -> Option<Schedule> {
+    let nvs = Nvs::open("schedule").ok()?;
+    Schedule::parse(&nvs.get_blob("table").ok().flatten()?)
+}
+
+pub fn store_schedule(schedule: &Schedule) -> Result<(), EspError> {
+    Nvs::open("schedule")?.set_blob("table", &schedule.serialize())
+}
+
+fn unix_time(time: SystemTime) -> f64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0))
+        .as_secs_f64()
+}
+
+/// Wraps an angle in radians into -π..π
+fn wrap_angle(angle: f32) -> f32 {
+    (angle + PI).rem_euclid(2.0 * PI) - PI
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: u64 = 1_656_000_000;
+
+    fn waypoint(time: u64, azimuth: f32, altitude: f32) -> Waypoint {
+        Waypoint {
+            time,
+            position: SolarPosition { azimuth, altitude },
+        }
+    }
+
+    fn parsed(waypoints: &[Waypoint]) -> Schedule {
+        let payload = Schedule {
+            waypoints: waypoints.to_vec(),
+        }
+        .serialize();
+        Schedule::parse(&payload).unwrap()
+    }
+
+    fn assert_close(position: Option<SolarPosition>, azimuth: f32, altitude: f32) {
+        let position = position.unwrap();
+        assert!((position.azimuth - azimuth).abs() < 1e-5, "{:?}", position);
+        assert!(
+            (position.altitude - altitude).abs() < 1e-5,
+            "{:?}",
+            position
+        );
+    }
+
+    fn at(seconds: f64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs_f64(START as f64 + seconds)
+    }
+
+    #[test]
+    fn parses_serialized_waypoints() {
+        let waypoints = [
+            waypoint(START, -1.0, 0.1),
+            waypoint(START + 600, -0.9, 0.2),
+            waypoint(START + 1200, -0.8, 0.25),
+        ];
+        let schedule = parsed(&waypoints);
+        assert_eq!(schedule.waypoints, waypoints);
+        assert_eq!(schedule.id(), START);
+
+        let mut payload = schedule.serialize();
+        assert_eq!(payload.len(), 3 * WAYPOINT_SIZE);
+        assert_eq!(&payload[0..8], &START.to_le_bytes());
+        assert_eq!(&payload[8..12], &(-1.0f32).to_le_bytes());
+
+        payload.pop();
+        assert_eq!(Schedule::parse(&payload), None);
+    }
+
+    #[test]
+    fn rejects_invalid_tables() {
+        // A single waypoint cannot be interpolated
+        let single = Schedule {
+            waypoints: vec![waypoint(START, 0.0, 0.0)],
+        };
+        assert_eq!(Schedule::parse(&single.serialize()), None);
+        assert_eq!(Schedule::parse(&[]), None);
+
+        for times in &[[START + 600, START], [START, START]] {
+            let unordered = Schedule {
+                waypoints: vec![waypoint(times[0], 0.0, 0.0), waypoint(times[1], 0.0, 0.0)],
+            };
+            assert_eq!(Schedule::parse(&unordered.serialize()), None);
+        }
+    }
+
+    #[test]
+    fn interpolates_between_waypoints() {
+        let schedule = parsed(&[
+            waypoint(START, -1.0, 0.1),
+            waypoint(START + 600, -0.8, 0.3),
+            waypoint(START + 1200, -0.7, 0.2),
+        ]);
+
+        assert_close(schedule.position(at(150.0)), -0.95, 0.15);
+        assert_close(schedule.position(at(600.0)), -0.8, 0.3);
+        assert_close(schedule.position(at(900.0)), -0.75, 0.25);
+    }
+
+    #[test]
+    fn interpolates_across_the_north() {
+        // From the north-west to the north-east through azimuth ±π, e.g. the midnight sun
+        let schedule = parsed(&[
+            waypoint(START, PI - 0.1, 0.05),
+            waypoint(START + 600, -PI + 0.1, 0.05),
+        ]);
+
+        let position = schedule.position(at(150.0)).unwrap();
+        assert!((position.azimuth - (PI - 0.05)).abs() < 1e-5);
+
+        let position = schedule.position(at(300.0)).unwrap();
+        assert!(PI - position.azimuth.abs() < 1e-5);
+
+        let position = schedule.position(at(450.0)).unwrap();
+        assert!((position.azimuth - (-PI + 0.05)).abs() < 1e-5);
+
+        // And back
+        let schedule = parsed(&[
+            waypoint(START, -PI + 0.1, 0.05),
+            waypoint(START + 600, PI - 0.1, 0.05),
+        ]);
+        let position = schedule.position(at(450.0)).unwrap();
+        assert!((position.azimuth - (PI - 0.05)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn expires_outside_the_waypoints() {
+        let schedule = parsed(&[waypoint(START, -1.0, 0.1), waypoint(START + 600, -0.8, 0.3)]);
+
+        assert_eq!(schedule.position(at(-1.0)), None);
+        assert_eq!(schedule.position(at(-0.5)), None);
+        assert_close(schedule.position(at(0.0)), -1.0, 0.1);
+        assert_close(schedule.position(at(600.0)), -0.8, 0.3);
+        assert_eq!(schedule.position(at(600.5)), None);
+        assert_eq!(schedule.position(SystemTime::UNIX_EPOCH), None);
+    }
+}
@@ -0,0 +1,295 @@
+use std::f64::consts::PI;
+use std::time::{Duration, SystemTime};
+
+// Julian date of the unix epoch and of J2000.0
+const JULIAN_DATE_UNIX_EPOCH: f64 = 2_440_587.5;
+const JULIAN_DATE_J2000: f64 = 2_451_545.0;
+
+/// Position of the sun in radians, the azimuth in the convention of the edge (suncalc)
+///
+/// The azimuth is measured from south towards west, so 0 is south and π/2 is west. The altitude
+/// is the apparent altitude above the horizon including atmospheric refraction. suncalc returns
+/// the geometric altitude instead, which is up to 0.6° lower close to the horizon.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SolarPosition {
+    pub azimuth: f32,
+    pub altitude: f32,
+}
+
+/// Calculates the position of the sun with the algorithm of the NOAA solar calculator
+///
+/// Accurate to about 0.01° for the years 1800 to 2100. Latitude and longitude are in degrees,
+/// north and east being positive. f64 is required, f32 cannot resolve a julian date below a day.
+pub fn position(time: SystemTime, latitude: f32, longitude: f32) -> SolarPosition {
+    let unix_time = match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(unix_time) => unix_time.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    };
+    let latitude = (latitude as f64).to_radians();
+    let longitude = longitude as f64;
+
+    let julian_date = unix_time / 86_400.0 + JULIAN_DATE_UNIX_EPOCH;
+    let (declination, equation_of_time) = sun_declination_equation_of_time(julian_date);
+
+    // Minutes since midnight UTC, corrected to the local true solar time
+    let day_minutes = (unix_time / 60.0).rem_euclid(1440.0);
+    let true_solar_time = (day_minutes + equation_of_time + 4.0 * longitude).rem_euclid(1440.0);
+    let hour_angle = (true_solar_time / 4.0 - 180.0).to_radians();
+
+    let cos_zenith =
+        latitude.sin() * declination.sin() + latitude.cos() * declination.cos() * hour_angle.cos();
+    let zenith = cos_zenith.clamp(-1.0, 1.0).acos();
+    let altitude = PI / 2.0 - zenith;
+
+    // Azimuth from south towards west
+    let azimuth = hour_angle
+        .sin()
+        .atan2(hour_angle.cos() * latitude.sin() - declination.tan() * latitude.cos());
+
+    SolarPosition {
+        azimuth: azimuth as f32,
+        altitude: (altitude + refraction(altitude)) as f32,
+    }
+}
+
+/// Declination of the sun in radians and the equation of time in minutes
+fn sun_declination_equation_of_time(julian_date: f64) -> (f64, f64) {
+    // Julian centuries since J2000.0
+    let t = (julian_date - JULIAN_DATE_J2000) / 36_525.0;
+
+    let mean_longitude = (280.46646 + t * (36000.76983 + t * 0.0003032)).rem_euclid(360.0);
+    let mean_anomaly = 357.52911 + t * (35999.05029 - 0.0001537 * t);
+    let eccentricity = 0.016708634 - t * (0.000042037 + 0.0000001267 * t);
+
+    let m = mean_anomaly.to_radians();
+    let equation_of_center = m.sin() * (1.914602 - t * (0.004817 + 0.000014 * t))
+        + (2.0 * m).sin() * (0.019993 - 0.000101 * t)
+        + (3.0 * m).sin() * 0.000289;
+    let true_longitude = mean_longitude + equation_of_center;
+
+    let omega = (125.04 - 1934.136 * t).to_radians();
+    let apparent_longitude = (true_longitude - 0.00569 - 0.00478 * omega.sin()).to_radians();
+
+    let mean_obliquity =
+        23.0 + (26.0 + (21.448 - t * (46.815 + t * (0.00059 - t * 0.001813))) / 60.0) / 60.0;
+    let obliquity = (mean_obliquity + 0.00256 * omega.cos()).to_radians();
+
+    let declination = (obliquity.sin() * apparent_longitude.sin()).asin();
+
+    let y = (obliquity / 2.0).tan().powi(2);
+    let l = mean_longitude.to_radians();
+    let equation_of_time = 4.0
+        * (y * (2.0 * l).sin() - 2.0 * eccentricity * m.sin()
+            + 4.0 * eccentricity * y * m.sin() * (2.0 * l).cos()
+            - 0.5 * y * y * (4.0 * l).sin()
+            - 1.25 * eccentricity * eccentricity * (2.0 * m).sin())
+        .to_degrees();
+
+    (declination, equation_of_time)
+}
+
+/// Atmospheric refraction in radians for the true altitude, as approximated by NOAA
+fn refraction(altitude: f64) -> f64 {
+    let elevation = altitude.to_degrees();
+    let tan_elevation = altitude.tan();
+
+    // Arc seconds
+    let refraction = if elevation > 85.0 {
+        0.0
+    } else if elevation > 5.0 {
+        58.1 / tan_elevation - 0.07 / tan_elevation.powi(3) + 0.000086 / tan_elevation.powi(5)
+    } else if elevation > -0.575 {
+        1735.0
+            + elevation * (-518.2 + elevation * (103.4 + elevation * (-12.79 + elevation * 0.711)))
+    } else {
+        -20.772 / tan_elevation
+    };
+
+    (refraction / 3600.0).to_radians()
+}
+
+/// Times of sunrise and sunset of a day
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Daylight {
+    SunRises {
+        sunrise: SystemTime,
+        sunset: SystemTime,
+    },
+    /// Polar day
+    AlwaysUp,
+    /// Polar night
+    AlwaysDown,
+}
+
+/// Calculates sunrise and sunset of the solar day around `time` with the NOAA algorithm
+///
+/// Sunrise and sunset are the times the upper limb of the sun touches the horizon, accurate to
+/// about a minute.
+pub fn daylight(time: SystemTime, latitude: f32, longitude: f32) -> Daylight {
+    let unix_time = match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(unix_time) => unix_time.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    };
+    let latitude = (latitude as f64).to_radians();
+    let longitude = longitude as f64;
+
+    // Mean solar noon of the day at the longitude, 4 minutes per degree
+    let day = ((unix_time + longitude * 240.0) / 86_400.0).floor();
+    let mean_noon = day * 86_400.0 + 43_200.0 - longitude * 240.0;
+    let (declination, equation_of_time) =
+        sun_declination_equation_of_time(mean_noon / 86_400.0 + JULIAN_DATE_UNIX_EPOCH);
+    let noon = mean_noon - equation_of_time * 60.0;
+
+    // Refraction and the radius of the sun lift the sun by 0.833° at the horizon
+    let horizon = (-0.833f64).to_radians();
+    let cos_hour_angle =
+        (horizon.sin() - latitude.sin() * declination.sin()) / (latitude.cos() * declination.cos());
+    if cos_hour_angle > 1.0 {
+        return Daylight::AlwaysDown;
+    }
+    if cos_hour_angle < -1.0 {
+        return Daylight::AlwaysUp;
+    }
+
+    let half_day = cos_hour_angle.acos() / (2.0 * PI) * 86_400.0;
+    let at = |unix_time: f64| {
+        if unix_time >= 0.0 {
+            SystemTime::UNIX_EPOCH + Duration::from_secs_f64(unix_time)
+        } else {
+            SystemTime::UNIX_EPOCH - Duration::from_secs_f64(-unix_time)
+        }
+    };
+    Daylight::SunRises {
+        sunrise: at(noon - half_day),
+        sunset: at(noon + half_day),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(unix_time: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(unix_time)
+    }
+
+    fn assert_near(actual: f32, expected: f64, tolerance: f64) {
+        assert!(
+            (actual as f64 - expected).abs() < tolerance,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    fn assert_daylight(daylight: Daylight, sunrise: u64, sunset: u64) {
+        let seconds = |time: SystemTime| {
+            time.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs_f64()
+        };
+        match daylight {
+            Daylight::SunRises {
+                sunrise: actual_sunrise,
+                sunset: actual_sunset,
+            } => {
+                assert!((seconds(actual_sunrise) - sunrise as f64).abs() < 60.0);
+                assert!((seconds(actual_sunset) - sunset as f64).abs() < 60.0);
+            }
+            _ => panic!("No sunrise: {:?}", daylight),
+        }
+    }
+
+    // Apparent altitude and azimuth of the NOAA solar calculator, the azimuth converted to suncalc's
+    // convention (azimuth from north - 180°)
+    #[test]
+    fn position_matches_noaa() {
+        let cases = [
+            // 2024-06-21 12:00 UTC, Munich
+            (1_718_971_200, 48.14, 11.58, 23.5551, 63.7792),
+            // 2024-12-21 09:30 UTC, Sydney after sunset
+            (1_734_773_400, -33.87, 151.21, 57.2077, -5.0939),
+            // 2024-03-20 18:00 UTC, New York
+            (1_710_957_600, 40.71, -74.01, 21.2840, 47.5503),
+            // 2024-09-23 06:00 UTC, equator at sunrise
+            (1_727_071_200, 0.0, 0.0, -89.7180, 2.2128),
+            // 2024-06-21 00:00 UTC, Tromsø midnight sun
+            (1_718_928_000, 69.65, 18.96, -163.0260, 4.2234),
+            // 2013-03-05 00:00 UTC, night in Kyiv
+            (1_362_441_600, 50.5, 30.5, -143.0466, -39.9409),
+        ];
+        for &(time, latitude, longitude, azimuth, altitude) in cases.iter() {
+            let position = position(at(time), latitude, longitude);
+            assert_near(position.azimuth, f64::to_radians(azimuth), 2e-4);
+            assert_near(position.altitude, f64::to_radians(altitude), 2e-4);
+        }
+    }
+
+    // suncalc returns the geometric altitude, values of its getPosition()
+    #[test]
+    fn altitude_is_suncalc_altitude_with_refraction() {
+        // 2024-06-21 00:00 UTC, Tromsø midnight sun
+        let midnight_sun = position(at(1_718_928_000), 69.65, 18.96);
+        let suncalc_altitude = 0.070_340_108_704;
+        assert_near(midnight_sun.azimuth, -2.846_337_044_854, 2e-3);
+        assert_near(
+            midnight_sun.altitude,
+            suncalc_altitude + refraction(suncalc_altitude),
+            2e-4,
+        );
+        // 0.19° refraction close to the horizon
+        assert!((midnight_sun.altitude as f64 - suncalc_altitude).abs() > 3e-3);
+
+        // Test case of suncalc, 2013-03-05 00:00 UTC, refraction is negligible far below the horizon
+        let night = position(at(1_362_441_600), 50.5, 30.5);
+        assert_near(night.azimuth, -2.500_317_590_717, 5e-3);
+        assert_near(night.altitude, -0.700_040_683_878, 5e-3);
+    }
+
+    // Sunrise and sunset of the NOAA solar calculator
+    #[test]
+    fn daylight_matches_noaa() {
+        // 2024-06-21, Munich: 03:13:30 and 19:17:40 UTC
+        assert_daylight(
+            daylight(at(1_718_971_200), 48.14, 11.58),
+            1_718_939_610,
+            1_718_997_460,
+        );
+        // 2024-12-21, Sydney: 18:40:52 UTC the day before and 09:05:41 UTC
+        assert_daylight(
+            daylight(at(1_734_782_400), -33.87, 151.21),
+            1_734_720_052,
+            1_734_771_941,
+        );
+        // 2024-03-20, New York: 10:58:30 and 23:08:44 UTC
+        assert_daylight(
+            daylight(at(1_710_936_000), 40.71, -74.01),
+            1_710_932_310,
+            1_710_976_124,
+        );
+        // 2024-01-15, Reykjavík: 10:56:07 and 16:18:31 UTC
+        assert_daylight(
+            daylight(at(1_705_320_000), 64.15, -21.94),
+            1_705_316_167,
+            1_705_335_511,
+        );
+    }
+
+    #[test]
+    fn daylight_detects_polar_day_and_night() {
+        // Tromsø at the solstices
+        assert_eq!(
+            daylight(at(1_718_971_200), 69.65, 18.96),
+            Daylight::AlwaysUp
+        );
+        assert_eq!(
+            daylight(at(1_734_782_400), 69.65, 18.96),
+            Daylight::AlwaysDown
+        );
+        // McMurdo Station in the southern winter
+        assert_eq!(
+            daylight(at(1_718_971_200), -77.85, 166.67),
+            Daylight::AlwaysDown
+        );
+    }
+}
@@ -0,0 +1,56 @@
+use std::time::{Duration, Instant};
+
+use crate::state::{Event, Fault};
+
+// Each check turns the horizontal axis to its reference mark and back
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Losing the position again right after re-homing points to a jammed axis, not to a few lost steps
+const MAX_REHOMINGS: u32 = 1;
+
+/// Schedule of the cross-checks of the counted motor angles against the reference mark
+///
+/// The stepper motors are driven open-loop, a jammed panel keeps counting steps it never made.
+/// A lost position triggers re-homing, losing it again on the next check halts the motors.
+pub struct PositionCheck {
+    last_check: Instant,
+    failures: u32,
+}
+
+impl PositionCheck {
+    pub fn new() -> PositionCheck {
+        PositionCheck {
+            last_check: Instant::now(),
+            failures: 0,
+        }
+    }
+
+    /// Whether the next check is due, the interval starts again with homing
+    pub fn due(&self) -> bool {
+        self.last_check.elapsed() >= CHECK_INTERVAL
+    }
+
+    pub fn homed(&mut self) {
+        self.last_check = Instant::now();
+    }
+
+    /// Records the result of a check, returns the event to handle for a lost position
+    pub fn record(&mut self, confirmed: bool) -> Option<Event> {
+        self.last_check = Instant::now();
+        if confirmed {
+            self.failures = 0;
+            return None;
+        }
+
+        self.failures += 1;
+        log::warn!(
+            "PositionCheck: Position lost {} times in a row",
+            self.failures
+        );
+        if self.failures > MAX_REHOMINGS {
+            Some(Event::Fault(Fault::Motor))
+        } else {
+            Some(Event::PositionLost)
+        }
+    }
+}
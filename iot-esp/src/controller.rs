@@ -0,0 +1,1496 @@
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime};
+
+use embedded_hal::adc::{Channel, OneShot};
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::command::{Axis, Command, CommandType};
+use crate::control::daycycle::{DayCycle, Phase};
+use crate::control::hybrid::PointingCorrection;
+use crate::control::kinematics::{AxisKinematics, EnvelopePolicy, Kinematics};
+use crate::control::lighttracking::{self, LightTrackingError, MotorAngles, PlatformTrait};
+use crate::control::mount::{MountCalibration, MountModel};
+use crate::control::schedule::{self, Schedule};
+use crate::control::solar::{self, SolarPosition};
+use crate::control::verification::PositionCheck;
+use crate::device::{DeviceInfo, DeviceStatus};
+use crate::networking::{provisioning, sntp};
+use crate::power::{PowerMode, RtcState};
+use crate::sensors::motor::Speed;
+use crate::state::{Event, Fault, State, StateMachine, TrackingMode};
+use crate::telemetry::buffer::TelemetryBuffer;
+use crate::telemetry::{DataPoint, TimeReference};
+use crate::transport::{CommandSource, TelemetrySink};
+
+// Datapoints sent per request after reconnecting to the edge
+const TELEMETRY_BATCH_SIZE: usize = 100;
+
+// Seconds between the control loop iterations while the motors do not move
+const IDLE_SLEEP_TIME: u32 = 10;
+// Seconds between the control loop iterations at night, only commands and telemetry are handled
+const NIGHT_SLEEP_TIME: u32 = 300;
+
+// 540 steps turn either axis of the platform by 360°, as measured on the platform, so the gearing
+// is part of the step count. The axes travel 225° and 103°.
+pub const KINEMATICS: Kinematics = Kinematics {
+    horizontal: AxisKinematics {
+        steps_per_revolution: 540,
+        gear_ratio: 1.0,
+        travel: 360.0 / 1.6,
+    },
+    vertical: AxisKinematics {
+        steps_per_revolution: 540,
+        gear_ratio: 1.0,
+        travel: 360.0 / 3.5,
+    },
+};
+// Targets outside the travel of the axes, e.g. the sun below the horizon, are approached closely
+const ENVELOPE_POLICY: EnvelopePolicy = EnvelopePolicy::Clamp;
+// Motor angles searched around the computed sun position in hybrid mode
+const HYBRID_SEARCH_SCOPE: MotorAngles = MotorAngles {
+    motor_hor: 20,
+    motor_ver: 10,
+};
+// Steps the reference mark may be off before the position counts as lost
+const POSITION_TOLERANCE: i32 = 5;
+
+/// Peripherals and services of the device used by the control cycle, besides the platform
+pub trait Board {
+    /// Keeps the wifi connection up, returns whether the device is online
+    fn maintain_connection(&mut self) -> bool;
+
+    /// Saves power while the platform is stowed for the night
+    fn set_power_save(&mut self, enabled: bool);
+
+    /// Signal strength of the wifi connection, 0 while disconnected
+    fn rssi(&self) -> i8;
+
+    /// Returns the step of the clock on its first synchronisation, see `TimeSync::poll`
+    fn poll_time_sync(&mut self) -> Option<Duration>;
+
+    fn last_sync(&self) -> Option<SystemTime>;
+
+    fn clock_drift(&self) -> Option<f32>;
+
+    fn temperature(&mut self) -> f32;
+
+    fn voltage(&mut self) -> u32;
+
+    fn current(&mut self) -> u32;
+
+    fn power(&mut self) -> u32;
+
+    /// Power mode since the previous datapoint
+    fn power_mode(&mut self) -> PowerMode;
+
+    /// Rolls back a new firmware that did not reach the edge in time
+    fn check_rollback(&mut self);
+
+    /// Confirms a new firmware and checks for updates, called once the datapoints were delivered
+    fn update_firmware(&mut self, telemetry_sink: &mut dyn TelemetrySink);
+}
+
+/// State of the control loop, one call of `run_cycle` per iteration
+///
+/// Commands and telemetry go through the uplink, the hardware is reached through the platform
+/// and the board. Waiting between the cycles is up to the caller, who hands pushed commands and
+/// button presses over.
+pub struct Controller {
+    device_info: DeviceInfo,
+    device_info_sent: bool,
+    status: DeviceStatus,
+    // Status last delivered, a status is only sent again once it changed
+    reported_status: Option<DeviceStatus>,
+    datapoints: TelemetryBuffer,
+    online: bool,
+
+    command: Command,
+    // Command the platform executes, differs from the received command for schedules
+    executed: Command,
+    pushed_command: Option<Command>,
+    world_angles_offset: MotorAngles,
+    initial_platform_offset: MotorAngles,
+    // Location of the panel for the solar position, set by provisioning or the edge
+    location: Option<(f32, f32)>,
+    day_cycle: DayCycle,
+    // Table of the last schedule command, kept for sites with poor connectivity
+    schedule: Option<Schedule>,
+    // Backlash of the gearboxes, measured once at the first light search
+    backlash: Option<MotorAngles>,
+    // Mounting error learned by the hybrid mode
+    correction: PointingCorrection,
+    // Orientation of the mount fitted from the light searches of several hours
+    calibration: MountCalibration,
+    mount: Option<MountModel>,
+    // Cross-checks the counted motor angles while tracking
+    position_check: PositionCheck,
+
+    state_machine: StateMachine,
+    events: VecDeque<Event>,
+}
+
+impl Controller {
+    /// Controller of a device booted without any stored configuration
+    pub fn new(device_info: DeviceInfo, datapoints: TelemetryBuffer) -> Controller {
+        let status = DeviceStatus {
+            device_id: device_info.device_id,
+            command: CommandType::Nop,
+            state: State::Booting,
+            initializing: false,
+            target_angles: None,
+            out_of_envelope: false,
+            current_angles: MotorAngles::default(),
+            last_fault: None,
+            last_sync: None,
+            clock_drift: None,
+        };
+        // TODO: Poll some time for edge and then start with default mode
+        let command = Command::default();
+        Controller {
+            device_info,
+            device_info_sent: false,
+            status,
+            reported_status: None,
+            datapoints,
+            online: false,
+            command,
+            executed: command,
+            pushed_command: None,
+            world_angles_offset: MotorAngles::default(),
+            initial_platform_offset: MotorAngles::default(),
+            location: None,
+            day_cycle: DayCycle::new(),
+            schedule: None,
+            backlash: None,
+            correction: PointingCorrection::default(),
+            calibration: MountCalibration::default(),
+            mount: None,
+            position_check: PositionCheck::new(),
+            state_machine: StateMachine::new(),
+            events: VecDeque::from(vec![Event::Boot]),
+        }
+    }
+
+    /// Loads the location, the last schedule and the calibrations from NVS
+    pub fn with_stored_configuration(mut self) -> Controller {
+        self.location = provisioning::load_location();
+        self.schedule = schedule::load_schedule();
+        self.backlash = lighttracking::load_backlash();
+        self.correction = PointingCorrection::load();
+        self.calibration = MountCalibration::load();
+        self.mount = MountModel::load();
+        self
+    }
+
+    /// Continues with the state kept in RTC memory during the deep sleep
+    pub fn resume(mut self, rtc_state: &RtcState, button_pressed: bool) -> Controller {
+        self.command = rtc_state.command;
+        self.executed = rtc_state.command;
+        self.world_angles_offset = rtc_state.world_angles_offset;
+        self.initial_platform_offset = rtc_state.initial_platform_offset;
+        self.day_cycle = rtc_state.day_cycle;
+        self.events.clear();
+        self.events.push_back(Event::Resume {
+            mode: TrackingMode::from_command(self.command.command),
+            night: self.day_cycle.is_night(),
+        });
+        if button_pressed {
+            self.events.push_back(Event::ButtonPressed);
+        }
+        self
+    }
+
+    /// Backlash measured before, the platform compensates it
+    pub fn backlash(&self) -> Option<&MotorAngles> {
+        self.backlash.as_ref()
+    }
+
+    pub fn state(&self) -> State {
+        self.state_machine.state()
+    }
+
+    /// Whether the device was online during the last cycle
+    pub fn is_online(&self) -> bool {
+        self.online
+    }
+
+    /// Executes a command pushed while waiting in the next cycle
+    pub fn push_command(&mut self, command: Command) {
+        self.pushed_command = Some(command);
+    }
+
+    pub fn press_button(&mut self) {
+        self.events.push_back(Event::ButtonPressed);
+    }
+
+    /// Persists the datapoints and returns the state to keep during the deep sleep
+    pub fn suspend(&mut self, motor_angles: MotorAngles) -> RtcState {
+        // New datapoints are on flash already, only removals are written
+        self.datapoints.persist();
+        RtcState {
+            motor_angles,
+            initial_platform_offset: self.initial_platform_offset,
+            world_angles_offset: self.world_angles_offset,
+            command: self.command,
+            day_cycle: self.day_cycle,
+        }
+    }
+
+    /// Receives a command, moves the platform and reports to the uplink, returns the time to
+    /// wait until the next cycle
+    pub fn run_cycle<
+        T,
+        Motor1Pin1: OutputPin,
+        Motor1Pin2: OutputPin,
+        Motor1Pin3: OutputPin,
+        Motor1Pin4: OutputPin,
+        Motor2Pin1: OutputPin,
+        Motor2Pin2: OutputPin,
+        Motor2Pin3: OutputPin,
+        Motor2Pin4: OutputPin,
+        Word: Copy + Into<u32> + PartialEq + PartialOrd,
+        Pin1,
+        Pin2,
+        Pin3,
+        const LENGTH: usize,
+        ADC,
+        Adc,
+    >(
+        &mut self,
+        platform1: &mut T,
+        adc: &mut Adc,
+        commands: &mut dyn CommandSource,
+        telemetry_sink: &mut dyn TelemetrySink,
+        board: &mut dyn Board,
+    ) -> Duration
+    where
+        Adc: OneShot<ADC, Word, Pin1> + OneShot<ADC, Word, Pin2> + OneShot<ADC, Word, Pin3>,
+        Pin1: Channel<ADC>,
+        Pin2: Channel<ADC>,
+        Pin3: Channel<ADC>,
+
+        T: PlatformTrait<
+            Motor1Pin1,
+            Motor1Pin2,
+            Motor1Pin3,
+            Motor1Pin4,
+            Motor2Pin1,
+            Motor2Pin2,
+            Motor2Pin3,
+            Motor2Pin4,
+            Word,
+            Pin1,
+            Pin2,
+            Pin3,
+            LENGTH,
+        >,
+    {
+        // Without wifi keep executing the last command and buffer the datapoints
+        let online = board.maintain_connection();
+        self.online = online;
+        board.check_rollback();
+        if let Some(offset) = board.poll_time_sync() {
+            self.datapoints.make_absolute(offset);
+        }
+
+        // Replace command only if received a new command that is not NOP
+        let mut new_command = match self.pushed_command.take().or_else(|| {
+            if !online {
+                return None;
+            }
+            commands
+                .request_command(&(&platform1.get_current_angles() - &self.initial_platform_offset))
+        }) {
+            Some(cmd) if cmd.command != CommandType::Nop => manual_command(
+                cmd,
+                &self.command,
+                &platform1.get_current_angles(),
+                &platform1.get_max_angles(),
+            )
+            .unwrap_or(self.command),
+            _ => self.command,
+        };
+
+        if let Some(received) = new_command.location.filter(|l| Some(*l) != self.location) {
+            log::info!("Location set to {:?}", received);
+            self.location = Some(received);
+            if let Err(e) = provisioning::store_location(received.0, received.1) {
+                log::warn!("Failed to store location: {:?}", e);
+            }
+            // The platform was moved, its mount needs to be calibrated again
+            self.calibration.clear();
+            self.mount = None;
+            if let Err(e) = self.calibration.store().and_then(|()| MountModel::erase()) {
+                log::warn!("Failed to reset the mount calibration: {:?}", e);
+            }
+        }
+        // Follow the sun on the device, so it keeps tracking if the edge becomes unreachable
+        if matches!(
+            new_command.command,
+            CommandType::Location | CommandType::Hybrid
+        ) && sntp::is_time_valid()
+        {
+            if let Some((latitude, longitude)) = self.location {
+                let sun = solar::position(SystemTime::now(), latitude, longitude);
+                new_command.azimuth = sun.azimuth;
+                new_command.altitude = sun.altitude;
+            }
+        }
+
+        // The table is fetched only once, the command just refers to it
+        if new_command.command == CommandType::Schedule
+            && online
+            && self.schedule.as_ref().map(Schedule::id) != Some(new_command.schedule_id)
+        {
+            match commands.fetch_schedule() {
+                Some(fetched) if fetched.id() == new_command.schedule_id => {
+                    if let Err(e) = schedule::store_schedule(&fetched) {
+                        log::warn!("Failed to store schedule: {:?}", e);
+                    }
+                    self.schedule = Some(fetched);
+                }
+                Some(fetched) => log::warn!(
+                    "Expected schedule {}, got {}",
+                    new_command.schedule_id,
+                    fetched.id()
+                ),
+                None => (),
+            }
+        }
+        self.command = new_command;
+
+        let now = SystemTime::now();
+        let previous_executed = self.executed.command;
+        self.executed = match self.command.command {
+            CommandType::Schedule => scheduled_command(&self.command, self.schedule.as_ref(), now),
+            _ => self.command,
+        };
+        if self.executed.command != previous_executed {
+            self.events.push_back(Event::Command(self.executed.command));
+        }
+
+        // Without a clock the night is not known, the platform keeps following the light
+        match self
+            .day_cycle
+            .update(now, self.location.filter(|_| sntp::is_time_valid()))
+        {
+            Some(Phase::Night) => self.events.push_back(Event::Sunset),
+            Some(Phase::Day) => self.events.push_back(Event::Sunrise),
+            None => (),
+        }
+
+        // Open-loop motors keep counting when the panel jams, the reference mark reveals it
+        let state = self.state_machine.state();
+        if matches!(state, State::Tracking(mode) if mode != TrackingMode::Manual)
+            && self.position_check.due()
+        {
+            match platform1.verify_position(adc, POSITION_TOLERANCE) {
+                Ok(confirmed) => {
+                    // The error state records the fault once re-homing did not help
+                    if let Some(event) = self.position_check.record(confirmed) {
+                        self.events.push_back(event);
+                    }
+                }
+                Err(e) => {
+                    log::error!("Verifying the position failed: {:?}", e);
+                    self.events.push_back(Event::Fault(Fault::Sensor));
+                }
+            }
+        }
+
+        // Enter the new states, which may raise further events
+        while let Some(event) = self.events.pop_front() {
+            let previous = self.state_machine.state();
+            let state = match self.state_machine.handle(event) {
+                Some(state) => state,
+                None => continue,
+            };
+
+            if previous == State::Stowing {
+                board.set_power_save(false);
+            }
+
+            // Homing and searching the light block the loop, report them beforehand
+            if online
+                && matches!(state, State::Homing | State::Tracking(_))
+                && state != State::Tracking(TrackingMode::Manual)
+            {
+                self.status.state = state;
+                self.status.initializing = true;
+                self.status.target_angles = None;
+                self.status.current_angles = platform1.get_current_angles();
+                report_status(telemetry_sink, self.status, &mut self.reported_status);
+            }
+
+            match state {
+                State::Booting | State::Idle => (),
+                State::Homing => match platform1.init_motors(adc) {
+                    Ok(()) => {
+                        self.position_check.homed();
+                        self.events.push_back(Event::Homed);
+                    }
+                    Err(e) => {
+                        log::error!("Homing failed: {:?}", e);
+                        self.events.push_back(Event::Fault(Fault::Homing));
+                    }
+                },
+                // Moved to the commanded angles right away
+                State::Tracking(TrackingMode::Manual) => (),
+                State::Tracking(mode) => {
+                    // Init the platform for the new command
+                    if let Err(e) = platform1.find_best_position(adc) {
+                        log::error!("Searching the light failed: {:?}", e);
+                        self.events.push_back(Event::Fault(Fault::Sensor));
+                        continue;
+                    }
+                    if self.backlash.is_none() {
+                        match platform1.calibrate_backlash(adc) {
+                            Ok(Some(measured)) => {
+                                if let Err(e) = lighttracking::store_backlash(&measured) {
+                                    log::warn!("Failed to store the backlash: {:?}", e);
+                                }
+                                self.backlash = Some(measured);
+                            }
+                            // Measured again when the tracking starts the next time
+                            Ok(None) => log::warn!("Backlash calibration did not find the light"),
+                            Err(e) => {
+                                log::error!("Measuring the backlash failed: {:?}", e);
+                                self.events.push_back(Event::Fault(Fault::Sensor));
+                                continue;
+                            }
+                        }
+                    }
+                    self.initial_platform_offset = platform1.get_current_angles();
+                    if let Some(model) = observe_sun(
+                        &mut self.calibration,
+                        &self.initial_platform_offset,
+                        self.location,
+                        now,
+                    ) {
+                        self.mount = Some(model);
+                    }
+
+                    if self.mount.is_some() {
+                        // The mount model replaces the offset derived from a single light search
+                        self.world_angles_offset = MotorAngles::default();
+                    } else if matches!(mode, TrackingMode::Location | TrackingMode::Hybrid) {
+                        self.world_angles_offset = platform1.get_current_angles();
+                        let (angle_offset_hor, angle_offset_ver) = convert_azimuth_altitude(
+                            self.executed.azimuth,
+                            self.executed.altitude,
+                            None,
+                        );
+                        self.world_angles_offset.motor_hor -= angle_offset_hor;
+                        self.world_angles_offset.motor_ver -= angle_offset_ver;
+                        // The light found includes the learned mounting error
+                        if mode == TrackingMode::Hybrid {
+                            self.world_angles_offset =
+                                &self.world_angles_offset - &self.correction.offset();
+                        }
+                    }
+                }
+                State::Stowing => {
+                    // Facing the sunrise the panel catches the first light of the next morning,
+                    // only a tracking platform knows where it is facing
+                    match (previous, self.day_cycle.stow_position()) {
+                        // Placed by hand, the panel stays where it was put
+                        (State::Tracking(TrackingMode::Manual), _) => (),
+                        (State::Tracking(mode), Some(stow_position)) => {
+                            let world_angles_offset =
+                                if matches!(mode, TrackingMode::Location | TrackingMode::Hybrid) {
+                                    pointing_offset(
+                                        mode,
+                                        &self.world_angles_offset,
+                                        &self.correction,
+                                    )
+                                } else {
+                                    // The panel followed the sun until it set
+                                    let (latitude, longitude) = self.location.unwrap_or_default();
+                                    let sun = solar::position(now, latitude, longitude);
+                                    let (angle_hor, angle_ver) = convert_azimuth_altitude(
+                                        sun.azimuth,
+                                        sun.altitude,
+                                        self.mount.as_ref(),
+                                    );
+                                    let current_angles = platform1.get_current_angles();
+                                    MotorAngles {
+                                        motor_hor: current_angles.motor_hor - angle_hor,
+                                        motor_ver: current_angles.motor_ver - angle_ver,
+                                    }
+                                };
+                            let stow_angles = world_to_motor_angles(
+                                &stow_position,
+                                &world_angles_offset,
+                                self.mount.as_ref(),
+                            );
+                            log::info!("Stowing the platform at {:?}", stow_angles);
+                            // Releases the coils of the motors afterwards
+                            platform1.rotate_to_angle(
+                                stow_angles.motor_ver,
+                                stow_angles.motor_hor,
+                                Speed::Medium,
+                            );
+                        }
+                        _ => (),
+                    }
+                    board.set_power_save(true);
+                }
+                State::Stopped => platform1.reset_motors_position(),
+                State::Error(fault) => {
+                    log::error!("Motors halted due to {:?}", fault);
+                    self.status.last_fault = Some(fault);
+                    // Resumed by the button, the next lost position is re-homed again
+                    self.position_check = PositionCheck::new();
+                }
+            }
+        }
+
+        // Platform is initialized for the command, now execute them
+        let sleep_time = match self.state_machine.state() {
+            State::Tracking(mode) => {
+                let pointing_offset =
+                    pointing_offset(mode, &self.world_angles_offset, &self.correction);
+                match control_platform(
+                    adc,
+                    platform1,
+                    mode,
+                    &self.executed,
+                    &pointing_offset,
+                    &self.initial_platform_offset,
+                    self.mount.as_ref(),
+                ) {
+                    Ok(sleep_time) => {
+                        // The search ended at the light, its distance to the target is the error
+                        let target = target_angles(
+                            mode,
+                            &self.executed,
+                            &pointing_offset,
+                            &self.initial_platform_offset,
+                            self.mount.as_ref(),
+                        );
+                        let found = platform1.get_current_angles();
+                        if let (TrackingMode::Hybrid, Some(target)) = (mode, target) {
+                            // Clamped targets say nothing about the pointing error
+                            let (target, reachable) = KINEMATICS.limit(&target);
+                            if reachable
+                                && self.correction.learn(&target, &found, &HYBRID_SEARCH_SCOPE)
+                            {
+                                if let Err(e) = self.correction.store() {
+                                    log::warn!("Failed to store pointing correction: {:?}", e);
+                                }
+                            }
+                        }
+                        // Only these modes end the cycle at the best light
+                        if matches!(mode, TrackingMode::LightTracking | TrackingMode::Hybrid) {
+                            if let Some(model) =
+                                observe_sun(&mut self.calibration, &found, self.location, now)
+                            {
+                                self.mount = Some(model);
+                                self.world_angles_offset = MotorAngles::default();
+                            }
+                        }
+                        sleep_time
+                    }
+                    Err(e) => {
+                        log::error!("Controlling the platform failed: {:?}", e);
+                        self.events.push_back(Event::Fault(Fault::Sensor));
+                        IDLE_SLEEP_TIME
+                    }
+                }
+            }
+            State::Stowing => self
+                .day_cycle
+                .wake_at()
+                .and_then(|wake_at| wake_at.duration_since(now).ok())
+                .map_or(NIGHT_SLEEP_TIME, |remaining| {
+                    NIGHT_SLEEP_TIME.min(remaining.as_secs() as u32 + 1)
+                }),
+            State::Booting | State::Homing | State::Idle | State::Stopped | State::Error(_) => {
+                IDLE_SLEEP_TIME
+            }
+        };
+
+        // Prepare datapoint to transfer
+        let datapoint = DataPoint {
+            timestamp: SystemTime::now(),
+            temperature: board.temperature(),
+            photoresitor: platform1.read_photoresistor(adc).unwrap_or_default(),
+            ir_sensor: platform1.read_ir(adc).unwrap_or_default(),
+            voltage: board.voltage(),
+            current: board.current(),
+            power: board.power(),
+            time_reference: if sntp::is_time_valid() {
+                TimeReference::Absolute
+            } else {
+                TimeReference::Relative
+            },
+            power_mode: board.power_mode(),
+            rssi: board.rssi(),
+        };
+        log::debug!("Adding {:?}", &datapoint);
+        self.datapoints.push(datapoint);
+
+        self.status.command = self.command.command;
+        self.status.state = self.state_machine.state();
+        self.status.initializing = false;
+        let target = match self.status.state {
+            State::Tracking(mode) => target_angles(
+                mode,
+                &self.executed,
+                &pointing_offset(mode, &self.world_angles_offset, &self.correction),
+                &self.initial_platform_offset,
+                self.mount.as_ref(),
+            ),
+            _ => None,
+        };
+        let limited = target.map(|target| KINEMATICS.limit(&target));
+        self.status.target_angles = limited.map(|(angles, _)| angles);
+        self.status.out_of_envelope = limited.map_or(false, |(_, reachable)| !reachable);
+        self.status.current_angles = platform1.get_current_angles();
+        self.status.last_sync = board.last_sync();
+        self.status.clock_drift = board.clock_drift();
+
+        if online {
+            if !self.device_info_sent {
+                self.device_info_sent = telemetry_sink.send_device_info(&self.device_info);
+            }
+            report_status(telemetry_sink, self.status, &mut self.reported_status);
+            if send_buffered_sensor_data(telemetry_sink, &mut self.datapoints) {
+                telemetry_sink.report_success();
+                // Reaching the edge or broker confirms a new firmware
+                board.update_firmware(telemetry_sink);
+            } else if telemetry_sink.report_failure() {
+                self.device_info_sent = false;
+                self.reported_status = None;
+            }
+        }
+
+        Duration::from_secs(sleep_time as u64)
+    }
+}
+
+/// Motor angles facing `azimuth` and `altitude`, relative to the offset of the world angles
+///
+/// A calibrated mount model corrects the orientation and tilt of the mount, the angles are then
+/// absolute and the offset of the world angles is 0. The angles are not limited to the travel of
+/// the axes yet.
+fn convert_azimuth_altitude(azimuth: f32, altitude: f32, mount: Option<&MountModel>) -> (i32, i32) {
+    let position = SolarPosition { azimuth, altitude };
+    let angles = match mount {
+        Some(mount) => KINEMATICS.motor_angles(&mount.apply(&position)),
+        None => KINEMATICS.motor_angles(&position),
+    };
+    (angles.motor_hor, angles.motor_ver)
+}
+
+/// Motor angles facing `position`, with the motor angles `world_angles_offset` facing south
+///
+/// The horizontal angle wraps around by full turns, positions outside the travel are clamped.
+fn world_to_motor_angles(
+    position: &SolarPosition,
+    world_angles_offset: &MotorAngles,
+    mount: Option<&MountModel>,
+) -> MotorAngles {
+    let (angle_hor, angle_ver) =
+        convert_azimuth_altitude(position.azimuth, position.altitude, mount);
+    let (angles, _) = KINEMATICS.limit(&MotorAngles {
+        motor_hor: angle_hor + world_angles_offset.motor_hor,
+        motor_ver: angle_ver + world_angles_offset.motor_ver,
+    });
+    angles
+}
+
+fn control_platform<
+    T,
+    Motor1Pin1: OutputPin,
+    Motor1Pin2: OutputPin,
+    Motor1Pin3: OutputPin,
+    Motor1Pin4: OutputPin,
+    Motor2Pin1: OutputPin,
+    Motor2Pin2: OutputPin,
+    Motor2Pin3: OutputPin,
+    Motor2Pin4: OutputPin,
+    Word: Copy + Into<u32> + PartialEq + PartialOrd,
+    Pin1,
+    Pin2,
+    Pin3,
+    const LENGTH: usize,
+    ADC,
+    Adc,
+>(
+    adc: &mut Adc,
+    platform1: &mut T,
+    mode: TrackingMode,
+    command: &Command,
+    world_angles_offset: &MotorAngles,
+    initial_platform_offset: &MotorAngles,
+    mount: Option<&MountModel>,
+) -> Result<u32, LightTrackingError>
+where
+    Adc: OneShot<ADC, Word, Pin1> + OneShot<ADC, Word, Pin2> + OneShot<ADC, Word, Pin3>,
+    Pin1: Channel<ADC>,
+    Pin2: Channel<ADC>,
+    Pin3: Channel<ADC>,
+
+    T: PlatformTrait<
+        Motor1Pin1,
+        Motor1Pin2,
+        Motor1Pin3,
+        Motor1Pin4,
+        Motor2Pin1,
+        Motor2Pin2,
+        Motor2Pin3,
+        Motor2Pin4,
+        Word,
+        Pin1,
+        Pin2,
+        Pin3,
+        LENGTH,
+    >,
+{
+    match target_angles(
+        mode,
+        command,
+        world_angles_offset,
+        initial_platform_offset,
+        mount,
+    ) {
+        Some(target) => {
+            let (limited, reachable) = KINEMATICS.limit(&target);
+            if !reachable {
+                log::warn!("Target {:?} is outside the mechanical envelope", target);
+                if ENVELOPE_POLICY == EnvelopePolicy::Refuse {
+                    return Ok(IDLE_SLEEP_TIME);
+                }
+            }
+            platform1.rotate_to_angle(limited.motor_ver, limited.motor_hor, Speed::Medium);
+            if mode == TrackingMode::Hybrid {
+                platform1.refine_position(
+                    adc,
+                    HYBRID_SEARCH_SCOPE.motor_hor,
+                    HYBRID_SEARCH_SCOPE.motor_ver,
+                )?;
+            }
+            // TODO: calc sleep_time similar to follow_light
+            Ok(10)
+        }
+        None => platform1.follow_light(adc),
+    }
+}
+
+/// Location command following the schedule, light tracking before and after the schedule
+///
+/// Without a valid clock the position in the schedule is unknown, the platform follows the light.
+fn scheduled_command(command: &Command, schedule: Option<&Schedule>, now: SystemTime) -> Command {
+    let position = schedule
+        .filter(|_| sntp::is_time_valid())
+        .and_then(|schedule| schedule.position(now));
+    match position {
+        Some(sun) => Command {
+            command: CommandType::Location,
+            azimuth: sun.azimuth,
+            altitude: sun.altitude,
+            ..*command
+        },
+        None => Command {
+            command: CommandType::LightTracking,
+            ..*command
+        },
+    }
+}
+
+/// Checks goto commands against the motor limits and resolves jog commands to absolute angles
+///
+/// Jogs add up while positioning by hand and stop at the motor limits. Returns `None` if the
+/// command is refused, other commands are returned unchanged.
+fn manual_command(
+    mut new_command: Command,
+    command: &Command,
+    current_angles: &MotorAngles,
+    max_angles: &MotorAngles,
+) -> Option<Command> {
+    match new_command.command {
+        CommandType::Goto => {
+            if !(0..=max_angles.motor_hor).contains(&new_command.angle_hor)
+                || !(0..=max_angles.motor_ver).contains(&new_command.angle_ver)
+            {
+                log::warn!(
+                    "Refusing goto to {}/{} beyond the motor limits {:?}",
+                    new_command.angle_hor,
+                    new_command.angle_ver,
+                    max_angles
+                );
+                return None;
+            }
+        }
+        CommandType::Jog => {
+            if command.command == CommandType::Jog
+                && command.jog_sequence == new_command.jog_sequence
+            {
+                // Already applied
+                return Some(*command);
+            }
+
+            let (angle_hor, angle_ver) = match command.command {
+                CommandType::Goto | CommandType::Jog => (command.angle_hor, command.angle_ver),
+                _ => (current_angles.motor_hor, current_angles.motor_ver),
+            };
+            let (angle, max_angle) = match new_command.axis {
+                Axis::Horizontal => (angle_hor, max_angles.motor_hor),
+                Axis::Vertical => (angle_ver, max_angles.motor_ver),
+            };
+            let target = angle.saturating_add(new_command.steps);
+            let limited = target.clamp(0, max_angle);
+            if limited != target {
+                log::warn!(
+                    "Jog of {:?} stopped at the motor limit {}",
+                    new_command.axis,
+                    limited
+                );
+            }
+
+            new_command.angle_hor = angle_hor;
+            new_command.angle_ver = angle_ver;
+            match new_command.axis {
+                Axis::Horizontal => new_command.angle_hor = limited,
+                Axis::Vertical => new_command.angle_ver = limited,
+            }
+        }
+        _ => (),
+    }
+    Some(new_command)
+}
+
+/// Motor angles the command moves the platform to, `None` while following the light
+fn target_angles(
+    mode: TrackingMode,
+    command: &Command,
+    world_angles_offset: &MotorAngles,
+    initial_platform_offset: &MotorAngles,
+    mount: Option<&MountModel>,
+) -> Option<MotorAngles> {
+    match mode {
+        TrackingMode::Follower => Some(MotorAngles {
+            motor_hor: initial_platform_offset.motor_hor + command.target_angle_offset_hor,
+            motor_ver: initial_platform_offset.motor_ver + command.target_angle_offset_ver,
+        }),
+        TrackingMode::LightTracking => None,
+        TrackingMode::Manual => Some(MotorAngles {
+            motor_hor: command.angle_hor,
+            motor_ver: command.angle_ver,
+        }),
+        TrackingMode::Location | TrackingMode::Hybrid => {
+            let (angle_hor, angle_ver) =
+                convert_azimuth_altitude(command.azimuth, command.altitude, mount);
+            Some(MotorAngles {
+                motor_hor: angle_hor + world_angles_offset.motor_hor,
+                motor_ver: angle_ver + world_angles_offset.motor_ver,
+            })
+        }
+    }
+}
+
+/// Takes the motor angles at the best light as an observation of the sun, returns the refitted
+/// mount model
+///
+/// Observations need a synchronised clock and the location of the platform.
+fn observe_sun(
+    calibration: &mut MountCalibration,
+    best_light: &MotorAngles,
+    location: Option<(f32, f32)>,
+    now: SystemTime,
+) -> Option<MountModel> {
+    let (latitude, longitude) = location.filter(|_| sntp::is_time_valid())?;
+    let sun = solar::position(now, latitude, longitude);
+    if !calibration.observe(now, KINEMATICS.position(best_light), sun) {
+        return None;
+    }
+    if let Err(e) = calibration.store() {
+        log::warn!("Failed to store the mount calibration: {:?}", e);
+    }
+
+    let model = calibration.fit()?;
+    if let Err(e) = model.store() {
+        log::warn!("Failed to store the mount model: {:?}", e);
+    }
+    Some(model)
+}
+
+/// Offset of the world angles corrected by the pointing error learned in hybrid mode
+fn pointing_offset(
+    mode: TrackingMode,
+    world_angles_offset: &MotorAngles,
+    correction: &PointingCorrection,
+) -> MotorAngles {
+    match mode {
+        TrackingMode::Hybrid => world_angles_offset + &correction.offset(),
+        _ => *world_angles_offset,
+    }
+}
+
+/// Sends the status unless the same status was delivered before
+fn report_status(
+    telemetry_sink: &mut dyn TelemetrySink,
+    status: DeviceStatus,
+    reported_status: &mut Option<DeviceStatus>,
+) {
+    if *reported_status != Some(status) && telemetry_sink.send_status(&status) {
+        *reported_status = Some(status);
+    }
+}
+
+/// Sends the buffered datapoints in chronological order, returns true once the buffer is empty
+fn send_buffered_sensor_data(
+    telemetry_sink: &mut dyn TelemetrySink,
+    datapoints: &mut TelemetryBuffer,
+) -> bool {
+    let mut delivered = true;
+    while !datapoints.is_empty() {
+        let batch = datapoints.oldest(TELEMETRY_BATCH_SIZE);
+        if !telemetry_sink.send_sensor_data(&batch, datapoints.dropped()) {
+            delivered = false;
+            break;
+        }
+        datapoints.remove_oldest(batch.len());
+        datapoints.reset_dropped();
+    }
+    // The file is rewritten once, not after each batch
+    datapoints.persist();
+    delivered
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::convert::Infallible;
+    use std::rc::Rc;
+
+    use adc_interpolator::AdcInterpolator;
+
+    use super::*;
+    use crate::sensors::motor::StepperMotor;
+    use crate::sensors::position::AxisSensor;
+    use crate::telemetry::buffer::OverflowPolicy;
+    use crate::transport::loopback::Loopback;
+
+    // Angles the light is found at by the fake platform
+    const LIGHT: MotorAngles = MotorAngles {
+        motor_hor: 120,
+        motor_ver: 40,
+    };
+
+    struct Pin;
+
+    impl OutputPin for Pin {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            Ok(())
+        }
+    }
+
+    struct Sensor;
+
+    struct Adc;
+
+    impl Channel<Adc> for Sensor {
+        type ID = u8;
+
+        fn channel() -> u8 {
+            0
+        }
+    }
+
+    impl OneShot<Adc, u16, Sensor> for Adc {
+        type Error = ();
+
+        fn read(&mut self, _pin: &mut Sensor) -> nb::Result<u16, ()> {
+            Ok(1000)
+        }
+    }
+
+    /// Platform moving instantly, the light is always found at `LIGHT`
+    #[derive(Default)]
+    struct FakePlatform {
+        angles: MotorAngles,
+        homings: u32,
+    }
+
+    impl PlatformTrait<Pin, Pin, Pin, Pin, Pin, Pin, Pin, Pin, u16, Sensor, Sensor, Sensor, 3>
+        for FakePlatform
+    {
+        fn new(
+            _stepper_motor_ver: StepperMotor<Pin, Pin, Pin, Pin>,
+            _stepper_motor_hor: StepperMotor<Pin, Pin, Pin, Pin>,
+            _interpolator_ir_sensor: AdcInterpolator<Sensor, u16, 3>,
+            _interpolator_photoresistor: AdcInterpolator<Sensor, u16, 3>,
+            _interpolator_button: AdcInterpolator<Sensor, u16, 3>,
+        ) -> Self {
+            FakePlatform::default()
+        }
+
+        fn with_position_sensor(self, _axis: Axis, _sensor: AxisSensor) -> Self {
+            self
+        }
+
+        fn reset_motors_position(&mut self) {
+            self.angles = MotorAngles::default();
+        }
+
+        fn is_button_pressed<A, ADC>(&mut self, _adc: &mut A) -> bool {
+            false
+        }
+
+        fn reset_if_button_pressed<A, ADC>(&mut self, _adc: &mut A) -> bool {
+            false
+        }
+
+        fn get_current_angles(&self) -> MotorAngles {
+            self.angles
+        }
+
+        fn get_max_angles(&self) -> MotorAngles {
+            MotorAngles {
+                motor_hor: KINEMATICS.horizontal.max_angle(),
+                motor_ver: KINEMATICS.vertical.max_angle(),
+            }
+        }
+
+        fn restore_angles(&mut self, angles: &MotorAngles) {
+            self.angles = *angles;
+        }
+
+        fn get_backlash(&self) -> MotorAngles {
+            MotorAngles::default()
+        }
+
+        fn set_backlash(&mut self, _backlash: &MotorAngles) {}
+
+        fn calibrate_backlash<ADC, A>(
+            &mut self,
+            _adc: &mut A,
+        ) -> Result<Option<MotorAngles>, LightTrackingError> {
+            Ok(None)
+        }
+
+        fn test_movement(&mut self) {}
+
+        fn rotate_to_angle(&mut self, ver_angle: i32, hor_angle: i32, _speed: Speed) {
+            self.angles = MotorAngles {
+                motor_hor: hor_angle,
+                motor_ver: ver_angle,
+            };
+        }
+
+        fn init_motors<A, ADC>(&mut self, _adc: &mut A) -> Result<(), LightTrackingError> {
+            self.homings += 1;
+            self.angles = MotorAngles::default();
+            Ok(())
+        }
+
+        fn verify_position<ADC, A>(
+            &mut self,
+            _adc: &mut A,
+            _tolerance: i32,
+        ) -> Result<bool, LightTrackingError> {
+            Ok(true)
+        }
+
+        fn find_best_position<ADC, A>(&mut self, _adc: &mut A) -> Result<(), LightTrackingError> {
+            self.angles = LIGHT;
+            Ok(())
+        }
+
+        fn search_scope<ADC, A>(
+            &mut self,
+            _adc: &mut A,
+            _speed: Speed,
+            _angle_hor: i32,
+            _angle_ver: i32,
+        ) -> Result<(), LightTrackingError> {
+            self.angles = LIGHT;
+            Ok(())
+        }
+
+        fn refine_position<ADC, A>(
+            &mut self,
+            _adc: &mut A,
+            _angle_hor: i32,
+            _angle_ver: i32,
+        ) -> Result<(), LightTrackingError> {
+            self.angles = LIGHT;
+            Ok(())
+        }
+
+        fn follow_light<ADC, A>(&mut self, _adc: &mut A) -> Result<u32, LightTrackingError> {
+            self.angles = LIGHT;
+            Ok(IDLE_SLEEP_TIME)
+        }
+
+        fn read_ir<A, ADC>(&mut self, _adc: &mut A) -> Result<u32, LightTrackingError> {
+            Ok(2000)
+        }
+
+        fn read_photoresistor<A, ADC>(&mut self, _adc: &mut A) -> Result<u32, LightTrackingError> {
+            Ok(1500)
+        }
+    }
+
+    struct FakeBoard {
+        online: bool,
+        firmware_checks: u32,
+    }
+
+    impl Board for FakeBoard {
+        fn maintain_connection(&mut self) -> bool {
+            self.online
+        }
+
+        fn set_power_save(&mut self, _enabled: bool) {}
+
+        fn rssi(&self) -> i8 {
+            if self.online {
+                -60
+            } else {
+                0
+            }
+        }
+
+        fn poll_time_sync(&mut self) -> Option<Duration> {
+            None
+        }
+
+        fn last_sync(&self) -> Option<SystemTime> {
+            None
+        }
+
+        fn clock_drift(&self) -> Option<f32> {
+            None
+        }
+
+        fn temperature(&mut self) -> f32 {
+            21.5
+        }
+
+        fn voltage(&mut self) -> u32 {
+            5000
+        }
+
+        fn current(&mut self) -> u32 {
+            120
+        }
+
+        fn power(&mut self) -> u32 {
+            600
+        }
+
+        fn power_mode(&mut self) -> PowerMode {
+            PowerMode::Active
+        }
+
+        fn check_rollback(&mut self) {}
+
+        fn update_firmware(&mut self, _telemetry_sink: &mut dyn TelemetrySink) {
+            self.firmware_checks += 1;
+        }
+    }
+
+    /// Controller wired to a loopback uplink, commands are replayed one per cycle
+    struct Device {
+        controller: Controller,
+        platform: FakePlatform,
+        board: FakeBoard,
+        loopback: Rc<RefCell<Loopback>>,
+    }
+
+    impl Device {
+        fn new() -> Device {
+            Device {
+                controller: controller(),
+                platform: FakePlatform::default(),
+                board: FakeBoard {
+                    online: true,
+                    firmware_checks: 0,
+                },
+                loopback: Rc::new(RefCell::new(Loopback::new())),
+            }
+        }
+
+        fn replay(&mut self, command: Command) -> Duration {
+            self.loopback.borrow_mut().push_command(command);
+            self.cycle()
+        }
+
+        fn cycle(&mut self) -> Duration {
+            let mut commands = self.loopback.clone();
+            let mut telemetry_sink = self.loopback.clone();
+            self.controller.run_cycle(
+                &mut self.platform,
+                &mut Adc,
+                &mut commands,
+                &mut telemetry_sink,
+                &mut self.board,
+            )
+        }
+
+        fn status(&self) -> DeviceStatus {
+            *self.loopback.borrow().status().expect("no status sent")
+        }
+    }
+
+    fn controller() -> Controller {
+        let device_info = DeviceInfo {
+            device_id: 7,
+            mac: [0x24, 0x0a, 0xc4, 0, 0, 7],
+            firmware_version: "0.1.0",
+            capabilities: 0,
+        };
+        Controller::new(
+            device_info,
+            TelemetryBuffer::new(10, OverflowPolicy::DropOldest),
+        )
+    }
+
+    fn command(command: CommandType) -> Command {
+        Command {
+            command,
+            ..Command::default()
+        }
+    }
+
+    fn goto(angle_hor: i32, angle_ver: i32) -> Command {
+        Command {
+            command: CommandType::Goto,
+            angle_hor,
+            angle_ver,
+            ..Command::default()
+        }
+    }
+
+    fn jog(axis: Axis, steps: i32, jog_sequence: u16) -> Command {
+        Command {
+            command: CommandType::Jog,
+            axis,
+            steps,
+            jog_sequence,
+            ..Command::default()
+        }
+    }
+
+    #[test]
+    fn homes_and_reports_after_booting() {
+        let mut device = Device::new();
+
+        assert_eq!(device.cycle(), Duration::from_secs(IDLE_SLEEP_TIME as u64));
+
+        assert_eq!(device.platform.homings, 1);
+        assert_eq!(device.controller.state(), State::Idle);
+        let loopback = device.loopback.borrow();
+        assert_eq!(loopback.device_info().map(|info| info.device_id), Some(7));
+        let status = loopback.status().unwrap();
+        assert_eq!(status.state, State::Idle);
+        assert_eq!(status.command, CommandType::Nop);
+        assert!(!status.initializing);
+        assert_eq!(status.target_angles, None);
+
+        let datapoints = loopback.datapoints();
+        assert_eq!(datapoints.len(), 1);
+        assert_eq!(datapoints[0].temperature, 21.5);
+        assert_eq!(datapoints[0].photoresitor, 1500);
+        assert_eq!(datapoints[0].ir_sensor, 2000);
+        assert_eq!(datapoints[0].power, 600);
+        assert_eq!(datapoints[0].rssi, -60);
+        assert_eq!(device.board.firmware_checks, 1);
+    }
+
+    #[test]
+    fn executes_replayed_commands() {
+        let mut device = Device::new();
+        device.cycle();
+
+        device.replay(command(CommandType::LightTracking));
+        assert_eq!(device.platform.angles, LIGHT);
+        let status = device.status();
+        assert_eq!(status.state, State::Tracking(TrackingMode::LightTracking));
+        assert_eq!(status.command, CommandType::LightTracking);
+        assert_eq!(status.current_angles, LIGHT);
+
+        device.replay(goto(100, 20));
+        let target = MotorAngles {
+            motor_hor: 100,
+            motor_ver: 20,
+        };
+        assert_eq!(device.platform.angles, target);
+        let status = device.status();
+        assert_eq!(status.state, State::Tracking(TrackingMode::Manual));
+        assert_eq!(status.target_angles, Some(target));
+        // Reported to the edge relative to the light found at the start of the tracking
+        device.cycle();
+        assert_eq!(device.loopback.borrow().angle_offset(), &target - &LIGHT);
+
+        device.replay(command(CommandType::Stop));
+        assert_eq!(device.platform.angles, MotorAngles::default());
+        let status = device.status();
+        assert_eq!(status.state, State::Stopped);
+        assert_eq!(status.command, CommandType::Stop);
+        assert_eq!(device.platform.homings, 1);
+    }
+
+    #[test]
+    fn keeps_the_command_for_nop() {
+        let mut device = Device::new();
+        device.cycle();
+        device.replay(command(CommandType::LightTracking));
+
+        device.replay(command(CommandType::Nop));
+        device.cycle();
+
+        let status = device.status();
+        assert_eq!(status.state, State::Tracking(TrackingMode::LightTracking));
+        assert_eq!(status.command, CommandType::LightTracking);
+    }
+
+    #[test]
+    fn resolves_jogs_to_absolute_angles() {
+        let mut device = Device::new();
+        device.cycle();
+        device.replay(goto(100, 20));
+
+        device.replay(jog(Axis::Horizontal, 15, 1));
+        assert_eq!(device.status().target_angles.unwrap().motor_hor, 115);
+        // Served again by the edge, applied once
+        device.replay(jog(Axis::Horizontal, 15, 1));
+        assert_eq!(device.status().target_angles.unwrap().motor_hor, 115);
+
+        device.replay(jog(Axis::Vertical, -200, 2));
+        let target = MotorAngles {
+            motor_hor: 115,
+            motor_ver: 0,
+        };
+        assert_eq!(device.status().target_angles, Some(target));
+        assert_eq!(device.platform.angles, target);
+    }
+
+    #[test]
+    fn refuses_goto_beyond_the_motor_limits() {
+        let mut device = Device::new();
+        device.cycle();
+        device.replay(goto(100, 20));
+
+        device.replay(goto(100, KINEMATICS.vertical.max_angle() + 1));
+
+        let status = device.status();
+        assert_eq!(status.state, State::Tracking(TrackingMode::Manual));
+        assert_eq!(
+            status.target_angles,
+            Some(MotorAngles {
+                motor_hor: 100,
+                motor_ver: 20,
+            })
+        );
+    }
+
+    #[test]
+    fn buffers_datapoints_while_offline() {
+        let mut device = Device::new();
+        device.board.online = false;
+        device.loopback.borrow_mut().push_command(goto(100, 20));
+        device.cycle();
+        device.cycle();
+
+        assert!(device.loopback.borrow().datapoints().is_empty());
+        assert!(device.loopback.borrow().status().is_none());
+        // Commands are only requested while online
+        assert_eq!(device.controller.state(), State::Idle);
+
+        device.board.online = true;
+        device.cycle();
+
+        let loopback = device.loopback.borrow();
+        let datapoints = loopback.datapoints();
+        assert_eq!(datapoints.len(), 3);
+        assert_eq!(datapoints[0].rssi, 0);
+        assert_eq!(datapoints[2].rssi, -60);
+        assert!(datapoints
+            .windows(2)
+            .all(|w| w[0].timestamp <= w[1].timestamp));
+        assert_eq!(loopback.status().unwrap().command, CommandType::Goto);
+    }
+
+    #[test]
+    fn keeps_undelivered_datapoints() {
+        let mut device = Device::new();
+        device.loopback.borrow_mut().set_offline(true);
+        device.cycle();
+        device.cycle();
+        assert!(device.loopback.borrow().datapoints().is_empty());
+        assert_eq!(device.board.firmware_checks, 0);
+
+        device.loopback.borrow_mut().set_offline(false);
+        device.cycle();
+
+        assert_eq!(device.loopback.borrow().datapoints().len(), 3);
+        assert_eq!(device.loopback.borrow().dropped(), 0);
+        assert_eq!(device.board.firmware_checks, 1);
+    }
+
+    #[test]
+    fn stops_on_button_press() {
+        let mut device = Device::new();
+        device.cycle();
+        device.replay(command(CommandType::LightTracking));
+
+        device.controller.press_button();
+        device.cycle();
+        assert_eq!(device.status().state, State::Stopped);
+
+        // Pressed again the motors are homed and the tracking resumes
+        device.controller.press_button();
+        device.cycle();
+        assert_eq!(device.platform.homings, 2);
+        assert_eq!(
+            device.status().state,
+            State::Tracking(TrackingMode::LightTracking)
+        );
+    }
+
+    #[test]
+    fn executes_pushed_commands() {
+        let mut device = Device::new();
+        device.cycle();
+
+        device.controller.push_command(goto(50, 10));
+        device.cycle();
+
+        assert_eq!(
+            device.platform.angles,
+            MotorAngles {
+                motor_hor: 50,
+                motor_ver: 10,
+            }
+        );
+    }
+
+    #[test]
+    fn resumes_without_homing() {
+        let mut device = Device::new();
+        device.cycle();
+        device.replay(goto(100, 20));
+        let rtc_state = device.controller.suspend(device.platform.angles);
+
+        let mut resumed = Device::new();
+        resumed.platform.restore_angles(&rtc_state.motor_angles);
+        resumed.controller = controller().resume(&rtc_state, false);
+        resumed.cycle();
+
+        assert_eq!(resumed.platform.homings, 0);
+        let status = resumed.status();
+        assert_eq!(status.state, State::Tracking(TrackingMode::Manual));
+        assert_eq!(status.command, CommandType::Goto);
+        assert_eq!(status.current_angles, rtc_state.motor_angles);
+    }
+}
@@ -0,0 +1,146 @@
+use std::convert::TryInto;
+use std::time::{Duration, SystemTime};
+
+use crate::command::CommandType;
+use crate::control::lighttracking::MotorAngles;
+use crate::state::{Fault, State};
+use crate::storage::nvs::Nvs;
+
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Hardware capabilities reported in the device info
+pub const CAPABILITY_MOTOR_HOR: u32 = 1 << 0;
+pub const CAPABILITY_MOTOR_VER: u32 = 1 << 1;
+pub const CAPABILITY_PHOTORESISTOR: u32 = 1 << 2;
+pub const CAPABILITY_IR_SENSOR: u32 = 1 << 3;
+pub const CAPABILITY_TEMPERATURE_SENSOR: u32 = 1 << 4;
+pub const CAPABILITY_POWER_SENSOR: u32 = 1 << 5;
+pub const CAPABILITY_OSCORE: u32 = 1 << 6;
+pub const CAPABILITY_POSITION_SENSOR: u32 = 1 << 7;
+
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    pub device_id: u32,
+    pub mac: [u8; 6],
+    pub firmware_version: &'static str,
+    pub capabilities: u32,
+}
+
+impl DeviceInfo {
+    pub fn new(device_id: u32, capabilities: u32) -> DeviceInfo {
+        DeviceInfo {
+            device_id,
+            mac: mac(),
+            firmware_version: FIRMWARE_VERSION,
+            capabilities,
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        // device_id + mac + capabilities + firmware version
+        let mut payload = Vec::with_capacity(4 + 6 + 4 + self.firmware_version.len());
+        payload.extend_from_slice(&self.device_id.to_le_bytes());
+        payload.extend_from_slice(&self.mac);
+        payload.extend_from_slice(&self.capabilities.to_le_bytes());
+        payload.extend_from_slice(self.firmware_version.as_bytes());
+        payload
+    }
+}
+
+/// Execution status of the device, the edge shows it instead of the commanded state
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeviceStatus {
+    pub device_id: u32,
+    /// Last command applied, acknowledges the command to the edge
+    pub command: CommandType,
+    pub state: State,
+    /// Homing or searching the light, the platform does not execute the command yet
+    pub initializing: bool,
+    /// Angles the platform is moved to, `None` unless the command defines them
+    pub target_angles: Option<MotorAngles>,
+    /// The target was outside the travel of the axes and was clamped or refused
+    pub out_of_envelope: bool,
+    pub current_angles: MotorAngles,
+    /// Most recent fault, kept after the device recovered from it
+    pub last_fault: Option<Fault>,
+    /// Time of the last SNTP sync since booting, the edge derives the time since the sync
+    pub last_sync: Option<SystemTime>,
+    /// Drift of the local clock in ppm between the last two syncs, positive if it runs fast
+    pub clock_drift: Option<f32>,
+}
+
+impl DeviceStatus {
+    pub fn serialize(&self) -> Vec<u8> {
+        let (state, mode) = match self.state {
+            State::Booting => (0u8, CommandType::Nop),
+            State::Homing => (1, CommandType::Nop),
+            State::Idle => (2, CommandType::Nop),
+            State::Tracking(mode) => (3, mode.command()),
+            State::Stowing => (4, CommandType::Nop),
+            State::Stopped => (5, CommandType::Nop),
+            State::Error(_) => (6, CommandType::Nop),
+        };
+        let fault = match self.last_fault {
+            None => 0u8,
+            Some(Fault::Homing) => 1,
+            Some(Fault::Sensor) => 2,
+            Some(Fault::Motor) => 3,
+        };
+        let flags = self.initializing as u8
+            | (self.target_angles.is_some() as u8) << 1
+            | (self.out_of_envelope as u8) << 2;
+        let target_angles = self.target_angles.unwrap_or_default();
+        // 0 and NaN if unknown
+        let last_sync = self.last_sync.map_or(0, |time| {
+            time.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or(Duration::from_secs(0))
+                .as_secs()
+        });
+        let clock_drift = self.clock_drift.unwrap_or(f32::NAN);
+
+        // device_id + command + state + mode + fault + flags + target and current angles
+        // + last sync + clock drift
+        let mut payload = Vec::with_capacity(4 + 5 + 4 * 4 + 8 + 4);
+        payload.extend_from_slice(&self.device_id.to_le_bytes());
+        payload.push(self.command as u8);
+        payload.push(state);
+        payload.push(mode as u8);
+        payload.push(fault);
+        payload.push(flags);
+        payload.extend_from_slice(&target_angles.motor_hor.to_le_bytes());
+        payload.extend_from_slice(&target_angles.motor_ver.to_le_bytes());
+        payload.extend_from_slice(&self.current_angles.motor_hor.to_le_bytes());
+        payload.extend_from_slice(&self.current_angles.motor_ver.to_le_bytes());
+        payload.extend_from_slice(&last_sync.to_le_bytes());
+        payload.extend_from_slice(&clock_drift.to_le_bytes());
+        payload
+    }
+}
+
+/// Factory MAC address burnt into the eFuses
+pub fn mac() -> [u8; 6] {
+    let mut mac = [0u8; 6];
+    unsafe { esp_idf_sys::esp_efuse_mac_get_default(mac.as_mut_ptr()) };
+    mac
+}
+
+/// Device id set in NVS ("device" namespace, key "id") or derived from the MAC address
+pub fn load_device_id() -> u32 {
+    match device_id_override() {
+        Some(device_id) => device_id,
+        None => default_device_id(&mac()),
+    }
+}
+
+pub fn device_id_override() -> Option<u32> {
+    Nvs::open("device")
+        .ok()
+        .and_then(|nvs| nvs.get_u32("id").ok().flatten())
+}
+
+/// The first three bytes are the OUI of Espressif, the last three are unique per board
+///
+/// The last OUI byte is included because Espressif uses several OUIs.
+pub fn default_device_id(mac: &[u8; 6]) -> u32 {
+    u32::from_be_bytes(mac[2..6].try_into().unwrap())
+}
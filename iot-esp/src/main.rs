@@ -1,19 +1,28 @@
+mod command;
 mod control;
+mod controller;
+mod device;
 mod networking;
+mod ota;
+mod power;
 mod sensors;
+mod state;
+mod storage;
+mod telemetry;
+mod transport;
 
-use std::convert::TryInto;
-use std::f32::consts::PI;
+use std::path::Path;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
 use adc_interpolator::AdcInterpolator;
-use coap_lite::RequestType;
-use control::lighttracking::{MotorAngles, PlatformTrait};
-use embedded_hal::adc::{Channel, OneShot};
-use embedded_hal::digital::v2::OutputPin;
+use command::Axis;
+use control::lighttracking::PlatformTrait;
+use controller::{Board, Controller, KINEMATICS};
+use device::DeviceInfo;
 use esp_idf_hal::adc;
-use esp_idf_hal::gpio::{Gpio32, Gpio34, Gpio35};
+use esp_idf_hal::gpio::{Gpio32, Gpio34, Gpio35, InputPin, OutputPin};
+use esp_idf_hal::i2c::I2c;
 use esp_idf_hal::prelude::Peripherals;
 
 use esp_idf_svc::netif::EspNetifStack;
@@ -22,59 +31,35 @@ use esp_idf_svc::sysloop::EspSysLoopStack;
 use esp_idf_sys::EspError;
 use esp_idf_sys::{self as _}; // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
 
-use networking::coap::Connection;
-use num_enum::TryFromPrimitive;
-use sensors::motor::{Speed, StepperMotor};
-
-#[derive(Clone, Copy, Debug)]
-struct DataPoint {
-    timestamp: std::time::SystemTime,
-    temperature: f32,
-    photoresitor: u32,
-    ir_sensor: u32,
-    voltage: u32,
-    current: u32,
-    power: u32,
-}
-
-#[derive(Clone, Copy, Debug, TryFromPrimitive, PartialEq)]
-#[repr(u8)]
-enum CommandType {
-    Nop,
-    Location,
-    LightTracking,
-    Follower,
-    Stop,
-}
-
-impl Default for CommandType {
-    fn default() -> Self {
-        Self::Nop
-    }
-}
-
-#[derive(Clone, Copy, Debug, Default)]
-struct Command {
-    command: CommandType,
-    target_angle_offset_hor: i32,
-    target_angle_offset_ver: i32,
-    azimuth: f32,
-    altitude: f32,
-}
-
-// 540 steps = 360°
-const FULL_ROTATION_ANGLE: i32 = 540;
-
-fn convert_azimuth_altitude(azimuth: f32, altitude: f32) -> (i32, i32) {
-    (
-        ((-azimuth + 2.0 * PI) / (2.0 * PI) * FULL_ROTATION_ANGLE as f32) as i32,
-        (altitude / (2.0 * PI) * FULL_ROTATION_ANGLE as f32) as i32,
-    )
-}
+use networking::sntp::TimeSync;
+use networking::uplink;
+use networking::wifi::WifiManager;
+use power::{PowerManager, PowerMode, WakeReason, BUTTON_POLL_INTERVAL};
+use sensors::motor::StepperMotor;
+use sensors::position::AxisSensor;
+use sensors::quadrature::QuadratureEncoder;
+use sensors::I2CDevices;
+use state::State;
+use telemetry::buffer::{OverflowPolicy, TelemetryBuffer};
+use transport::TelemetrySink;
+
+// Datapoints kept while the edge is unreachable, one datapoint per control loop iteration
+const TELEMETRY_BUFFER_CAPACITY: usize = 2000;
+const TELEMETRY_OVERFLOW_POLICY: OverflowPolicy = OverflowPolicy::Downsample;
+// Keep undelivered datapoints across reboots in flash
+const TELEMETRY_PERSISTENCE: bool = true;
+
+// Light sleep between the control cycles instead of keeping the CPU running
+const LIGHT_SLEEP: bool = true;
+// Power down between the control loop iterations at night, keeping the state in RTC memory
+const NIGHT_DEEP_SLEEP: bool = true;
+
+// AS5600 magnetic encoder on the horizontal axis, sharing the I2C bus, for closed-loop positioning
+const POSITION_SENSOR_AS5600: bool = false;
+// Counts per revolution of a quadrature encoder on the vertical axis at GPIO 25 (A) and 33 (B)
+const QUADRATURE_ENCODER_VER: Option<i32> = None;
 
 fn main() -> Result<(), EspError> {
-    let device_id: u32 = env!("esp_device_id").parse().unwrap();
-
     esp_idf_sys::link_patches();
 
     let peripherals = Peripherals::take().unwrap();
@@ -84,7 +69,7 @@ fn main() -> Result<(), EspError> {
     esp_idf_svc::log::EspLogger::initialize_default();
     //esp_idf_svc::log::EspLogger.set_target_level("rust-logging", esp_idf_svc::log::Level::Debug);
 
-    let mut i2c_sensors =
+    let i2c_sensors =
         sensors::I2CDevices::new(peripherals.i2c0, pins.gpio21, pins.gpio22, true, true)?;
 
     let stepper_motor_ver = StepperMotor::new(
@@ -92,7 +77,7 @@ fn main() -> Result<(), EspError> {
         pins.gpio17.into_output()?,
         pins.gpio18.into_output()?,
         pins.gpio19.into_output()?,
-        (FULL_ROTATION_ANGLE as f32 / 3.5) as i32,
+        KINEMATICS.vertical.max_angle(),
         1,
         0,
         true,
@@ -103,7 +88,7 @@ fn main() -> Result<(), EspError> {
         pins.gpio27.into_output()?,
         pins.gpio14.into_output()?,
         pins.gpio12.into_output()?,
-        (FULL_ROTATION_ANGLE as f32 / 1.6) as i32,
+        KINEMATICS.horizontal.max_angle(),
         1,
         1,
         false,
@@ -162,6 +147,24 @@ fn main() -> Result<(), EspError> {
         interpolator_photoresistor,
         interpolator_button_sensor,
     );
+    // Without position sensors the motors are positioned open-loop
+    if POSITION_SENSOR_AS5600 {
+        let sensor = AxisSensor::new(
+            Box::new(i2c_sensors.as5600()),
+            KINEMATICS.horizontal.steps_per_turn(),
+        );
+        platform1 = platform1.with_position_sensor(Axis::Horizontal, sensor);
+    }
+    if let Some(counts_per_turn) = QUADRATURE_ENCODER_VER {
+        let encoder = QuadratureEncoder::new(
+            esp_idf_sys::pcnt_unit_t_PCNT_UNIT_0,
+            pins.gpio25.into_input()?,
+            pins.gpio33.into_input()?,
+            counts_per_turn,
+        )?;
+        let sensor = AxisSensor::new(Box::new(encoder), KINEMATICS.vertical.steps_per_turn());
+        platform1 = platform1.with_position_sensor(Axis::Vertical, sensor);
+    }
 
     /*
     loop {
@@ -181,328 +184,190 @@ fn main() -> Result<(), EspError> {
     return Ok(());
     */
 
-    let _wifi = networking::wifi::wifi(
+    // Woken up from deep sleep the motors are still where they were
+    let rtc_state = power::restore_state();
+    let power = PowerManager::new(esp_idf_sys::gpio_num_t_GPIO_NUM_32, LIGHT_SLEEP);
+    if let Some(rtc_state) = &rtc_state {
+        platform1.restore_angles(&rtc_state.motor_angles);
+    }
+
+    let mut wifi = WifiManager::new(
         Arc::new(EspNetifStack::new()?),
         Arc::new(EspSysLoopStack::new()?),
         Arc::new(EspDefaultNvs::new()?),
-    );
+    )?;
 
-    platform1.init_motors(&mut powered_adc).unwrap();
-
-    let mut coap_conn = Connection::new();
-
-    let addr = "10.0.100.1:5683";
-
-    let mut datapoints = vec![];
-
-    // TODO: Poll some time for edge and then start with default mode
-    let mut command = Command::default();
-    let mut world_angles_offset = MotorAngles::default();
-    let mut initial_platform_offset = MotorAngles::default();
-
-    'stop_loop: loop {
-        'main_loop: loop {
-            // Replace command only if received a new command that is not NOP
-            let new_command = match request_command(
-                &mut coap_conn,
-                addr,
-                &(&platform1.get_current_angles() - &initial_platform_offset),
-                device_id,
-            ) {
-                Some(cmd) => cmd,
-                None => command,
-            };
-
-            if new_command.command != command.command {
-                // Received instruction to change command
-                // Init the platform for the new command
-                match new_command.command {
-                    CommandType::Nop => (),
-                    CommandType::Follower | CommandType::LightTracking => {
-                        platform1.find_best_position(&mut powered_adc).unwrap();
-                        initial_platform_offset = platform1.get_current_angles();
-                    }
-                    CommandType::Location => {
-                        platform1.find_best_position(&mut powered_adc).unwrap();
-                        initial_platform_offset = platform1.get_current_angles();
-
-                        world_angles_offset = platform1.get_current_angles();
-                        let (angle_offset_hor, angle_offset_ver) =
-                            convert_azimuth_altitude(new_command.azimuth, new_command.altitude);
-                        world_angles_offset.motor_hor -= angle_offset_hor;
-                        world_angles_offset.motor_ver -= angle_offset_ver;
-                    }
-                    CommandType::Stop => break 'main_loop,
-                }
-            }
+    // Unconfigured devices and devices booted with pressed button ask for their configuration
+    if !wifi.has_networks()
+        || (rtc_state.is_none() && platform1.is_button_pressed(&mut powered_adc))
+    {
+        log::info!("Starting provisioning mode");
+        networking::provisioning::run(&mut wifi);
+    }
+    let device_id = device::load_device_id();
+    log::info!("Device id: {}", device_id);
 
-            if new_command.command != CommandType::Nop {
-                command = new_command;
-            }
+    if !wifi.connect() {
+        log::warn!("No wifi connection, retrying in the background");
+    }
 
-            // Platform is initialized for the command, now execute them
-            let sleep_time = match command.command {
-                CommandType::Nop => 10,
-                CommandType::Follower | CommandType::Location | CommandType::LightTracking => {
-                    control_platform(
-                        &mut powered_adc,
-                        &mut platform1,
-                        &command,
-                        &world_angles_offset,
-                        &initial_platform_offset,
-                    )
-                }
-                CommandType::Stop => panic!("Requested to execute stop"),
-            };
-
-            // Prepare datapoint to transfer
-            let datapoint = DataPoint {
-                timestamp: std::time::SystemTime::now(),
-                temperature: i2c_sensors.get_temperature(),
-                photoresitor: platform1.read_photoresistor(&mut powered_adc).unwrap(),
-                ir_sensor: platform1.read_ir(&mut powered_adc).unwrap(),
-                voltage: i2c_sensors.get_voltage() as u32,
-                current: i2c_sensors.get_current() as u32,
-                power: i2c_sensors.get_power() as u32,
-            };
-            log::debug!("Adding {:?}", &datapoint);
-            datapoints.push(datapoint);
-
-            if send_sensor_data(&mut coap_conn, addr, &datapoints, device_id) {
-                datapoints.clear();
-            }
+    // Syncs in the background once wifi is up
+    let time_sync = TimeSync::start();
 
-            // Sleep 10x as often but 10x less time per sleep
-            for _ in 0..sleep_time * 10 {
-                if platform1.reset_if_button_pressed(&mut powered_adc) {
-                    break 'stop_loop;
-                }
-                std::thread::sleep(Duration::from_millis(100));
+    let mut datapoints = TelemetryBuffer::new(TELEMETRY_BUFFER_CAPACITY, TELEMETRY_OVERFLOW_POLICY);
+    if TELEMETRY_PERSISTENCE {
+        match telemetry::buffer::mount_storage() {
+            Ok(()) => {
+                datapoints = datapoints.with_persistence(
+                    Path::new(telemetry::buffer::STORAGE_BASE_PATH).join("telemetry.bin"),
+                )
             }
+            Err(e) => log::warn!("Telemetry persistence disabled: {:?}", e),
         }
+    }
+
+    // A replayed command file is read from the storage partition as well
+    let (mut commands, mut telemetry_sink) = uplink::create(device_id);
+    let updater = ota::Updater::new();
 
-        platform1.reset_motors_position();
+    let mut capabilities = device::CAPABILITY_MOTOR_HOR
+        | device::CAPABILITY_MOTOR_VER
+        | device::CAPABILITY_PHOTORESISTOR
+        | device::CAPABILITY_IR_SENSOR;
+    if i2c_sensors.has_temperature_sensor() {
+        capabilities |= device::CAPABILITY_TEMPERATURE_SENSOR;
+    }
+    if i2c_sensors.has_power_sensor() {
+        capabilities |= device::CAPABILITY_POWER_SENSOR;
+    }
+    if telemetry_sink.is_protected() {
+        capabilities |= device::CAPABILITY_OSCORE;
+    }
+    if POSITION_SENSOR_AS5600 || QUADRATURE_ENCODER_VER.is_some() {
+        capabilities |= device::CAPABILITY_POSITION_SENSOR;
+    }
+    let device_info = DeviceInfo::new(device_id, capabilities);
 
-        if platform1.reset_if_button_pressed(&mut powered_adc) {
-            break 'stop_loop;
+    let mut controller = Controller::new(device_info, datapoints).with_stored_configuration();
+    // Backlash of the gearboxes, measured once at the first light search
+    if let Some(backlash) = controller.backlash() {
+        platform1.set_backlash(backlash);
+    }
+    // A press is handled once, even if the button is held longer
+    let mut button_pressed = false;
+    if let Some(rtc_state) = &rtc_state {
+        button_pressed = power::wakeup_cause() == Some(WakeReason::Button);
+        controller = controller.resume(rtc_state, button_pressed);
+    }
+
+    let mut board = EspBoard {
+        wifi,
+        time_sync,
+        i2c_sensors,
+        power,
+        updater,
+    };
+    loop {
+        let sleep_duration = controller.run_cycle(
+            &mut platform1,
+            &mut powered_adc,
+            commands.as_mut(),
+            telemetry_sink.as_mut(),
+            &mut board,
+        );
+
+        if controller.state() == State::Stowing && NIGHT_DEEP_SLEEP {
+            let rtc_state = controller.suspend(platform1.get_current_angles());
+            board.power.deep_sleep(sleep_duration, rtc_state);
         }
-        std::thread::sleep(Duration::from_millis(10000));
 
-        // Motor stopped, now only try to delivery datapoints
-        while !datapoints.is_empty() {
-            if send_sensor_data(&mut coap_conn, addr, &datapoints, device_id) {
-                datapoints.clear();
+        let deadline = Instant::now() + sleep_duration;
+        while Instant::now() < deadline {
+            let pressed = platform1.is_button_pressed(&mut powered_adc);
+            if pressed && !button_pressed {
+                button_pressed = true;
+                controller.press_button();
+                break;
+            }
+            button_pressed = pressed;
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if controller.is_online() {
+                // Automatic light sleep keeps the connection while waiting for pushed commands
+                let pushed = commands.receive_pushed_command(remaining.min(BUTTON_POLL_INTERVAL));
+                if let Some(command) = pushed {
+                    controller.push_command(command);
+                    break;
+                }
+            } else {
+                // Nothing to wait for but the timer and the button
+                board.power.light_sleep(remaining);
             }
         }
     }
+}
 
-    Ok(())
+/// Wifi, clock, sensors and power management of the ESP32 used by the control cycle
+struct EspBoard<I2C: I2c, SDA: OutputPin + InputPin, SCL: OutputPin> {
+    wifi: WifiManager,
+    time_sync: TimeSync,
+    i2c_sensors: I2CDevices<I2C, SDA, SCL>,
+    power: PowerManager,
+    updater: ota::Updater,
 }
 
-fn control_platform<
-    T,
-    Motor1Pin1: OutputPin,
-    Motor1Pin2: OutputPin,
-    Motor1Pin3: OutputPin,
-    Motor1Pin4: OutputPin,
-    Motor2Pin1: OutputPin,
-    Motor2Pin2: OutputPin,
-    Motor2Pin3: OutputPin,
-    Motor2Pin4: OutputPin,
-    Word: Copy + Into<u32> + PartialEq + PartialOrd,
-    Pin1,
-    Pin2,
-    Pin3,
-    const LENGTH: usize,
-    ADC,
-    Adc,
->(
-    adc: &mut Adc,
-    platform1: &mut T,
-    command: &Command,
-    world_angles_offset: &MotorAngles,
-    initial_platform_offset: &MotorAngles,
-) -> u32
-where
-    Adc: OneShot<ADC, Word, Pin1> + OneShot<ADC, Word, Pin2> + OneShot<ADC, Word, Pin3>,
-    Pin1: Channel<ADC>,
-    Pin2: Channel<ADC>,
-    Pin3: Channel<ADC>,
-
-    T: PlatformTrait<
-        Motor1Pin1,
-        Motor1Pin2,
-        Motor1Pin3,
-        Motor1Pin4,
-        Motor2Pin1,
-        Motor2Pin2,
-        Motor2Pin3,
-        Motor2Pin4,
-        Word,
-        Pin1,
-        Pin2,
-        Pin3,
-        LENGTH,
-    >,
-{
-    match command.command {
-        CommandType::Nop | CommandType::Stop => {
-            panic!("Invalid CommandType in control_platform(): {:?}", command)
-        }
-        CommandType::Follower => {
-            platform1.rotate_to_angle(
-                initial_platform_offset.motor_ver + command.target_angle_offset_ver,
-                initial_platform_offset.motor_hor + command.target_angle_offset_hor,
-                Speed::Medium,
-            );
-            10
-        }
-        CommandType::LightTracking => platform1.follow_light(adc).unwrap(),
-        CommandType::Location => {
-            let (angle_hor, angle_ver) =
-                convert_azimuth_altitude(command.azimuth, command.altitude);
-            platform1.rotate_to_angle(
-                angle_ver + world_angles_offset.motor_ver,
-                angle_hor + world_angles_offset.motor_hor,
-                Speed::Medium,
-            );
-            // TODO: calc sleep_time similar to follow_light
-            10
+impl<I2C: I2c, SDA: OutputPin + InputPin, SCL: OutputPin> Board for EspBoard<I2C, SDA, SCL> {
+    fn maintain_connection(&mut self) -> bool {
+        self.wifi.maintain()
+    }
+
+    fn set_power_save(&mut self, enabled: bool) {
+        if let Err(e) = self.wifi.set_power_save(enabled) {
+            log::warn!("Failed to set wifi power save: {:?}", e);
         }
     }
-}
 
-fn request_command(
-    conn: &mut Connection,
-    addr: &str,
-    target_angle_offset: &MotorAngles,
-    device_id: u32,
-) -> Option<Command> {
-    let mut payload = vec![0; 12];
-    payload[0..4].copy_from_slice(&device_id.to_le_bytes());
-    payload[4..8].copy_from_slice(&target_angle_offset.motor_hor.to_le_bytes());
-    payload[8..12].copy_from_slice(&target_angle_offset.motor_ver.to_le_bytes());
-    match conn.request(RequestType::Get, addr, "/command", payload) {
-        Ok(response) => {
-            let mut payload_rest;
-
-            let command_bytes;
-            (command_bytes, payload_rest) =
-                response.message.payload.split_at(std::mem::size_of::<u8>());
-            let command = u8::from_le_bytes(command_bytes.try_into().unwrap())
-                .try_into()
-                .unwrap();
-
-            let mut target_angle_offset_hor = 0;
-            let mut target_angle_offset_ver = 0;
-
-            let mut azimuth = 0.0;
-            let mut altitude = 0.0;
-
-            if command == CommandType::Follower {
-                let target_angle_hor_bytes;
-                (target_angle_hor_bytes, payload_rest) =
-                    payload_rest.split_at(std::mem::size_of::<i32>());
-                target_angle_offset_hor =
-                    i32::from_le_bytes(target_angle_hor_bytes.try_into().unwrap());
-
-                let target_angle_ver_bytes;
-                (target_angle_ver_bytes, payload_rest) =
-                    payload_rest.split_at(std::mem::size_of::<i32>());
-                target_angle_offset_ver =
-                    i32::from_le_bytes(target_angle_ver_bytes.try_into().unwrap());
-            } else if command == CommandType::Location {
-                let azimuth_bytes;
-                (azimuth_bytes, payload_rest) = payload_rest.split_at(std::mem::size_of::<f32>());
-                azimuth = f32::from_le_bytes(azimuth_bytes.try_into().unwrap());
-
-                let altitude_bytes;
-                (altitude_bytes, payload_rest) = payload_rest.split_at(std::mem::size_of::<f32>());
-                altitude = f32::from_le_bytes(altitude_bytes.try_into().unwrap());
-            }
+    fn rssi(&self) -> i8 {
+        self.wifi.rssi().unwrap_or_default()
+    }
 
-            debug_assert_eq!(0, payload_rest.len());
+    fn poll_time_sync(&mut self) -> Option<Duration> {
+        self.time_sync.poll()
+    }
 
-            let res = Command {
-                command,
-                target_angle_offset_hor,
-                target_angle_offset_ver,
-                azimuth,
-                altitude,
-            };
+    fn last_sync(&self) -> Option<SystemTime> {
+        self.time_sync.last_sync()
+    }
 
-            log::info!("request_command(): Got command: {:?}", res);
+    fn clock_drift(&self) -> Option<f32> {
+        self.time_sync.drift_ppm()
+    }
 
-            Some(res)
-        }
-        Err(e) => {
-            log::warn!("request_command(): {:?}", e);
-            None
-        }
+    fn temperature(&mut self) -> f32 {
+        self.i2c_sensors.get_temperature()
+    }
+
+    fn voltage(&mut self) -> u32 {
+        self.i2c_sensors.get_voltage() as u32
     }
-}
 
-fn send_sensor_data(
-    conn: &mut Connection,
-    addr: &str,
-    datapoints: &[DataPoint],
-    device_id: u32,
-) -> bool {
-    // length_s + timestamp_s + datasets_length * (device_id + timestamp + temperature + photoresistor + IRsensor + voltage + current + power)
-    let mut payload = vec![0; 4 + 8 + datapoints.len() * (4 + 8 + 4 * 6)];
-
-    let mut index = 0;
-    // 4 bytes: Amount of datasets in payload
-    payload[index..index + 4].copy_from_slice(&(datapoints.len() as u32).to_le_bytes());
-    index += 4;
-    // 8 bytes: Placeholder for current SystemTime
-    payload[index..index + 8].copy_from_slice(&0u64.to_le_bytes());
-    index += 8;
-
-    // TODO: prevent fragementation
-    for datapoint in datapoints {
-        payload[index..index + 4].copy_from_slice(&device_id.to_le_bytes());
-        index += 4;
-        let unix_time = datapoint
-            .timestamp
-            .duration_since(std::time::SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        payload[index..index + 8].copy_from_slice(&unix_time.to_le_bytes());
-        index += 8;
-        payload[index..index + 4].copy_from_slice(&datapoint.temperature.to_le_bytes());
-        index += 4;
-        payload[index..index + 4].copy_from_slice(&datapoint.photoresitor.to_le_bytes());
-        index += 4;
-        payload[index..index + 4].copy_from_slice(&datapoint.ir_sensor.to_le_bytes());
-        index += 4;
-        payload[index..index + 4].copy_from_slice(&datapoint.voltage.to_le_bytes());
-        index += 4;
-        payload[index..index + 4].copy_from_slice(&datapoint.current.to_le_bytes());
-        index += 4;
-        payload[index..index + 4].copy_from_slice(&datapoint.power.to_le_bytes());
-        index += 4;
+    fn current(&mut self) -> u32 {
+        self.i2c_sensors.get_current() as u32
     }
 
-    debug_assert_eq!(index, payload.len());
+    fn power(&mut self) -> u32 {
+        self.i2c_sensors.get_power() as u32
+    }
 
-    // Set current SystemTime as reference for the other timestamps
-    payload[4..4 + 8].copy_from_slice(
-        &std::time::SystemTime::now()
-            .duration_since(std::time::SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-            .to_le_bytes(),
-    );
+    fn power_mode(&mut self) -> PowerMode {
+        self.power.report_mode()
+    }
 
-    match conn.request(RequestType::Post, addr, "/sensor/data", payload) {
-        Ok(_) => {
-            log::info!("send_sensor_data(): Sent {} datapoints", datapoints.len());
-            true
-        }
-        Err(e) => {
-            log::warn!("send_sensor_data(): {:?}", e);
-            false
-        }
+    fn check_rollback(&mut self) {
+        self.updater.check_rollback();
+    }
+
+    fn update_firmware(&mut self, telemetry_sink: &mut dyn TelemetrySink) {
+        self.updater.confirm();
+        telemetry_sink.poll_update(&mut self.updater);
     }
 }
@@ -1,14 +1,66 @@
-use coap_lite::{CoapRequest, CoapResponse, MessageType, Packet, RequestType};
+use coap_lite::{
+    CoapOption, CoapRequest, CoapResponse, MessageClass, MessageType, Packet, RequestType,
+    ResponseType,
+};
 use std::{
+    collections::VecDeque,
     io::ErrorKind,
     net::{SocketAddr, ToSocketAddrs, UdpSocket},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use super::oscore::{OscoreError, RequestBinding, SecurityContext};
+
+// Block size used for block-wise transfers: 2^(4 + 6) = 1024 bytes, fits into one datagram
+const BLOCK_SIZE_EXPONENT: u8 = 6;
+// Retransmissions of a confirmable message before giving up (MAX_RETRANSMIT of RFC 7252)
+const MAX_RETRANSMIT: u32 = 4;
+// The first transmission waits between ACK_TIMEOUT and ACK_TIMEOUT * ACK_RANDOM_FACTOR for the
+// acknowledgement, each retransmission twice as long (RFC 7252 section 4.2)
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+const ACK_RANDOM_FACTOR: f64 = 1.5;
+// Requests block the control loop, so the back-off is cut short instead of waiting up to 93 s
+// for an unreachable edge. Retransmissions stop once this much time has passed.
+const MAX_TRANSMIT_WAIT: Duration = Duration::from_secs(5);
+// Notifications older than this are always considered fresh (RFC 7641 section 3.4)
+const OBSERVE_SEQUENCE_TIMEOUT: Duration = Duration::from_secs(128);
+
 pub struct Connection {
     socket: UdpSocket,
     token: u16,
     message_id: u16,
+    timeout: Option<Duration>,
+    observation: Option<Observation>,
+    notifications: VecDeque<CoapResponse>,
+    security_context: Option<SecurityContext>,
+    // Source of the randomised acknowledgement timeouts
+    random: fn() -> u32,
+}
+
+/// Resource observed by this connection (RFC 7641)
+struct Observation {
+    token: Vec<u8>,
+    addr: SocketAddr,
+    path: String,
+    sequence: Option<u32>,
+    last_update: Instant,
+    binding: Option<RequestBinding>,
+    // Highest Partial IV of the protected notifications, the outer Observe option is not protected
+    partial_iv: Option<u64>,
+}
+
+impl Observation {
+    fn is_fresh(&self, sequence: u32) -> bool {
+        let last_sequence = match self.sequence {
+            Some(last_sequence) => last_sequence,
+            None => return true,
+        };
+
+        // Observe sequence numbers are 24 bit and wrap around
+        (last_sequence < sequence && sequence - last_sequence < 1 << 23)
+            || (last_sequence > sequence && last_sequence - sequence > 1 << 23)
+            || self.last_update.elapsed() > OBSERVE_SEQUENCE_TIMEOUT
+    }
 }
 
 #[derive(Debug)]
@@ -16,21 +68,102 @@ pub enum CoapError {
     ConnectionError(std::io::Error), // Socket error occured
     TimedOut,                        // Did not receive a response in time
     InvalidResponse,
+    UnexpectedResponse(MessageClass), // Block-wise transfer was aborted by the server
+    Oscore(OscoreError),              // Protecting the request or verifying the response failed
+    Aborted,                          // Download was aborted by the receiver of the blocks
+}
+
+/// Value of a Block1 or Block2 option (RFC 7959)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlockOption {
+    pub num: u32,
+    pub more: bool,
+    pub size_exponent: u8,
+}
+
+impl BlockOption {
+    pub fn new(num: u32, more: bool, size_exponent: u8) -> BlockOption {
+        BlockOption {
+            num,
+            more,
+            size_exponent,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        1 << (self.size_exponent + 4)
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let value = (self.num << 4) | ((self.more as u32) << 3) | self.size_exponent as u32;
+
+        // Options are encoded as unsigned integer with as few bytes as possible
+        value
+            .to_be_bytes()
+            .iter()
+            .skip_while(|byte| **byte == 0)
+            .copied()
+            .collect()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<BlockOption> {
+        if bytes.len() > 3 {
+            return None;
+        }
+        let value = bytes
+            .iter()
+            .fold(0u32, |value, byte| (value << 8) | *byte as u32);
+
+        let size_exponent = (value & 0x7) as u8;
+        // Size exponent 7 is reserved
+        if size_exponent == 7 {
+            return None;
+        }
+
+        Some(BlockOption {
+            num: value >> 4,
+            more: value & 0x8 != 0,
+            size_exponent,
+        })
+    }
+
+    fn from_packet(packet: &Packet, option: CoapOption) -> Option<BlockOption> {
+        packet
+            .get_option(option)
+            .and_then(|values| values.front())
+            .and_then(|value| BlockOption::from_bytes(value))
+    }
 }
 
 impl Connection {
-    pub fn new() -> Connection {
+    pub fn new(random: fn() -> u32) -> Connection {
         let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
         let mut con = Connection {
             socket,
             token: 0,
             message_id: 0,
+            timeout: None,
+            observation: None,
+            notifications: VecDeque::new(),
+            security_context: None,
+            random,
         };
 
         con.set_timeout(Some(Duration::from_secs(2)));
         con
     }
 
+    /// Protect all requests and responses with OSCORE
+    pub fn set_security_context(&mut self, security_context: SecurityContext) {
+        self.security_context = Some(security_context);
+    }
+
+    pub fn is_protected(&self) -> bool {
+        self.security_context.is_some()
+    }
+
+    /// Sends a request and waits for the response.
+    /// Payloads and responses larger than a single block are transferred block-wise.
     pub fn request<A: ToSocketAddrs>(
         &mut self,
         rtype: RequestType,
@@ -39,6 +172,251 @@ impl Connection {
         payload: Vec<u8>,
     ) -> Result<CoapResponse, CoapError> {
         let addr = addr.to_socket_addrs().unwrap().collect::<Vec<SocketAddr>>()[0];
+
+        let response = self.request_block1(rtype, addr, path, payload)?;
+        self.request_block2(rtype, addr, path, response)
+    }
+
+    /// Fetches a large resource block-wise and passes each block to `on_block` instead of buffering
+    /// the whole resource. `on_block` aborts the download by returning false.
+    pub fn download<A: ToSocketAddrs, F: FnMut(&[u8]) -> bool>(
+        &mut self,
+        addr: A,
+        path: &str,
+        mut on_block: F,
+    ) -> Result<(), CoapError> {
+        let addr = addr.to_socket_addrs().unwrap().collect::<Vec<SocketAddr>>()[0];
+
+        let mut request = self.new_request(RequestType::Get, path, vec![]);
+        request.message.add_option(
+            CoapOption::Block2,
+            BlockOption::new(0, false, BLOCK_SIZE_EXPONENT).to_bytes(),
+        );
+        let response = self.exchange(request, addr)?;
+        if response.message.header.code != MessageClass::Response(ResponseType::Content) {
+            return Err(CoapError::UnexpectedResponse(response.message.header.code));
+        }
+
+        if !on_block(&response.message.payload) {
+            return Err(CoapError::Aborted);
+        }
+        self.fetch_remaining_blocks(RequestType::Get, addr, path, &response, on_block)
+    }
+
+    /// Registers as observer of the resource, or updates the registration if it is already observed.
+    /// The server does not support observing the resource if `is_observing()` is false afterwards.
+    pub fn observe<A: ToSocketAddrs>(
+        &mut self,
+        addr: A,
+        path: &str,
+        payload: Vec<u8>,
+    ) -> Result<CoapResponse, CoapError> {
+        let addr = addr.to_socket_addrs().unwrap().collect::<Vec<SocketAddr>>()[0];
+
+        let mut request = self.new_request(RequestType::Get, path, payload);
+        // Re-registration uses the token of the existing observation
+        let reregister = match &self.observation {
+            Some(observation) if observation.addr == addr && observation.path == path => {
+                request.message.set_token(observation.token.clone());
+                true
+            }
+            _ => false,
+        };
+        request.message.set_observe_value(0);
+        let token = request.message.get_token().to_vec();
+
+        let (response, binding, partial_iv) = match self.exchange_protected(request, addr) {
+            Ok(res) => res,
+            Err(e) => {
+                self.observation = None;
+                return Err(e);
+            }
+        };
+
+        match response.message.get_observe_value() {
+            Some(Ok(sequence)) => {
+                log::debug!(
+                    "{} observation of {}",
+                    if reregister { "Renewed" } else { "Started" },
+                    path
+                );
+                self.observation = Some(Observation {
+                    token,
+                    addr,
+                    path: path.to_string(),
+                    sequence: Some(sequence),
+                    last_update: Instant::now(),
+                    binding,
+                    partial_iv,
+                });
+            }
+            _ => {
+                log::debug!("Server does not support observing {}", path);
+                self.observation = None;
+            }
+        }
+        // Notifications of a previous registration are outdated now
+        self.notifications.clear();
+
+        Ok(response)
+    }
+
+    pub fn is_observing(&self) -> bool {
+        self.observation.is_some()
+    }
+
+    /// Time since the last registration or notification of the observation
+    pub fn observation_age(&self) -> Option<Duration> {
+        self.observation
+            .as_ref()
+            .map(|observation| observation.last_update.elapsed())
+    }
+
+    /// Forgets the observation, further notifications are rejected with a reset
+    pub fn cancel_observation(&mut self) {
+        self.observation = None;
+        self.notifications.clear();
+    }
+
+    /// Waits up to `timeout` for a notification of the observed resource
+    pub fn poll_notification(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<CoapResponse>, CoapError> {
+        if let Some(notification) = self.notifications.pop_front() {
+            return Ok(Some(notification));
+        }
+        let addr = match &self.observation {
+            Some(observation) => observation.addr,
+            None => return Ok(None),
+        };
+
+        let deadline = Instant::now() + timeout;
+        let restore_timeout = self.timeout;
+        let result = loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break Ok(None);
+            }
+            self.set_timeout(Some(remaining));
+
+            match self.recv(addr) {
+                Ok(packet) => {
+                    self.handle_unexpected(packet, addr);
+                    if let Some(notification) = self.notifications.pop_front() {
+                        break Ok(Some(notification));
+                    }
+                }
+                Err(CoapError::TimedOut) => break Ok(None),
+                Err(e) => break Err(e),
+            }
+        };
+        self.set_timeout(restore_timeout);
+
+        result
+    }
+
+    fn request_block1(
+        &mut self,
+        rtype: RequestType,
+        addr: SocketAddr,
+        path: &str,
+        payload: Vec<u8>,
+    ) -> Result<CoapResponse, CoapError> {
+        let mut size_exponent = BLOCK_SIZE_EXPONENT;
+        if payload.len() <= BlockOption::new(0, false, size_exponent).size() {
+            let request = self.new_request(rtype, path, payload);
+            return self.exchange(request, addr);
+        }
+
+        let mut offset = 0;
+        loop {
+            let block_size = BlockOption::new(0, false, size_exponent).size();
+            let end = (offset + block_size).min(payload.len());
+            let block = BlockOption::new(
+                (offset / block_size) as u32,
+                end < payload.len(),
+                size_exponent,
+            );
+
+            let mut request = self.new_request(rtype, path, payload[offset..end].to_vec());
+            request
+                .message
+                .add_option(CoapOption::Block1, block.to_bytes());
+            let response = self.exchange(request, addr)?;
+            log::debug!("Sent block {} of {} bytes", block.num, payload.len());
+
+            if !block.more {
+                return Ok(response);
+            }
+            if response.message.header.code != MessageClass::Response(ResponseType::Continue) {
+                return Err(CoapError::UnexpectedResponse(response.message.header.code));
+            }
+
+            // Server may ask for smaller blocks, the current offset is still aligned to them
+            if let Some(ack) = BlockOption::from_packet(&response.message, CoapOption::Block1) {
+                size_exponent = size_exponent.min(ack.size_exponent);
+            }
+            offset = end;
+        }
+    }
+
+    fn request_block2(
+        &mut self,
+        rtype: RequestType,
+        addr: SocketAddr,
+        path: &str,
+        mut response: CoapResponse,
+    ) -> Result<CoapResponse, CoapError> {
+        let mut response_payload = std::mem::take(&mut response.message.payload);
+        self.fetch_remaining_blocks(rtype, addr, path, &response, |payload| {
+            response_payload.extend_from_slice(payload);
+            log::debug!("Payload length: {}", response_payload.len());
+            true
+        })?;
+        response.message.payload = response_payload;
+
+        Ok(response)
+    }
+
+    /// Fetches the blocks following the first response, `on_block` aborts by returning false
+    fn fetch_remaining_blocks<F: FnMut(&[u8]) -> bool>(
+        &mut self,
+        rtype: RequestType,
+        addr: SocketAddr,
+        path: &str,
+        first_response: &CoapResponse,
+        mut on_block: F,
+    ) -> Result<(), CoapError> {
+        // Response spans multiple blocks, fetch the remaining ones
+        let mut block2 = BlockOption::from_packet(&first_response.message, CoapOption::Block2);
+        while let Some(block) = block2.filter(|block| block.more) {
+            let next_block = BlockOption::new(block.num + 1, false, block.size_exponent);
+            let mut request = self.new_request(rtype, path, vec![]);
+            request
+                .message
+                .add_option(CoapOption::Block2, next_block.to_bytes());
+            let next_response = self.exchange(request, addr)?;
+
+            block2 = BlockOption::from_packet(&next_response.message, CoapOption::Block2);
+            if block2.map(|block| block.num) != Some(next_block.num) {
+                return Err(CoapError::InvalidResponse);
+            }
+            log::debug!("Received block {}", next_block.num);
+            if !on_block(&next_response.message.payload) {
+                return Err(CoapError::Aborted);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn new_request(
+        &mut self,
+        rtype: RequestType,
+        path: &str,
+        payload: Vec<u8>,
+    ) -> CoapRequest<SocketAddr> {
         let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
 
         request.set_method(rtype);
@@ -52,22 +430,92 @@ impl Connection {
         request.message.header.set_type(MessageType::Confirmable);
 
         request.message.payload = payload;
+        request
+    }
+
+    fn exchange(
+        &mut self,
+        request: CoapRequest<SocketAddr>,
+        addr: SocketAddr,
+    ) -> Result<CoapResponse, CoapError> {
+        self.exchange_protected(request, addr)
+            .map(|(response, _, _)| response)
+    }
+
+    /// Exchanges the request, protected with OSCORE if configured
+    ///
+    /// Returns the binding of a protected request and the Partial IV of its response.
+    fn exchange_protected(
+        &mut self,
+        mut request: CoapRequest<SocketAddr>,
+        addr: SocketAddr,
+    ) -> Result<(CoapResponse, Option<RequestBinding>, Option<u64>), CoapError> {
+        let binding = match &mut self.security_context {
+            Some(security_context) => Some(
+                security_context
+                    .protect_request(&mut request.message)
+                    .map_err(CoapError::Oscore)?,
+            ),
+            None => None,
+        };
+
+        let restore_timeout = self.timeout;
+        let result = self.transmit(&request, addr);
+        self.set_timeout(restore_timeout);
 
+        let mut response = result?;
+        let partial_iv = match (&self.security_context, &binding) {
+            (Some(security_context), Some(binding)) => security_context
+                .unprotect_response(&mut response.message, binding)
+                .map_err(CoapError::Oscore)?,
+            _ => None,
+        };
+        Ok((response, binding, partial_iv))
+    }
+
+    /// Sends the request until it is acknowledged, with exponential back-off
+    fn transmit(
+        &mut self,
+        request: &CoapRequest<SocketAddr>,
+        addr: SocketAddr,
+    ) -> Result<CoapResponse, CoapError> {
+        // Retransmissions reuse the protected packet
         let packet = request.message.to_bytes().unwrap();
-        self.socket
-            .send_to(&packet[..], addr)
-            .map_err(CoapError::ConnectionError)?;
-        log::debug!("Sent request packet");
+        let timeouts = ack_timeouts((self.random)());
+        for (transmission, timeout) in timeouts.iter().enumerate() {
+            if transmission > 0 {
+                log::debug!(
+                    "Retransmitting message {} ({}/{})",
+                    request.message.header.message_id,
+                    transmission,
+                    timeouts.len() - 1
+                );
+            }
+            self.socket
+                .send_to(&packet[..], addr)
+                .map_err(CoapError::ConnectionError)?;
+            log::debug!("Sent request packet");
+            self.set_timeout(Some(*timeout));
 
-        self.wait_for_response(request, addr)
+            match self.wait_for_response(request, addr) {
+                Err(CoapError::TimedOut) => (),
+                result => return result,
+            }
+        }
+        Err(CoapError::TimedOut)
     }
 
     fn send_ack(&mut self, resp: &CoapResponse, addr: SocketAddr) {
+        self.send_empty(resp, MessageType::Acknowledgement, addr);
+    }
+
+    fn send_reset(&mut self, resp: &CoapResponse, addr: SocketAddr) {
+        self.send_empty(resp, MessageType::Reset, addr);
+    }
+
+    fn send_empty(&mut self, resp: &CoapResponse, mtype: MessageType, addr: SocketAddr) {
         let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
-        request
-            .message
-            .header
-            .set_type(MessageType::Acknowledgement);
+        request.message.header.set_type(mtype);
         request.message.header.message_id = resp.message.header.message_id;
 
         let packet = request.message.to_bytes().unwrap();
@@ -78,7 +526,7 @@ impl Connection {
 
     fn wait_for_response(
         &mut self,
-        req: CoapRequest<SocketAddr>,
+        req: &CoapRequest<SocketAddr>,
         addr: SocketAddr,
     ) -> Result<CoapResponse, CoapError> {
         loop {
@@ -97,26 +545,210 @@ impl Connection {
                 );
                 return Ok(res);
             }
+
+            self.handle_unexpected(res, addr);
         }
     }
 
-    fn recv(&mut self, _addr: SocketAddr) -> Result<CoapResponse, CoapError> {
-        let mut buf = [0; 1500];
+    /// Queues notifications of the observed resource, rejects other confirmable messages
+    fn handle_unexpected(&mut self, mut packet: CoapResponse, addr: SocketAddr) {
+        let observation = match &mut self.observation {
+            Some(observation) if observation.token == packet.message.get_token() => observation,
+            _ => {
+                if packet.message.header.get_type() == MessageType::Confirmable {
+                    self.send_reset(&packet, addr);
+                }
+                return;
+            }
+        };
 
-        // Src not checked since for private WiFi network and without authentication this doesn't matter
-        let (nread, _src) = match self.socket.recv_from(&mut buf) {
-            Ok(res) => res,
-            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
-                return Err(CoapError::TimedOut)
+        // Anyone can send a higher outer Observe number, only the Partial IV orders protected
+        // notifications
+        let protected = match (&self.security_context, &observation.binding) {
+            (Some(security_context), Some(binding)) => {
+                match security_context.unprotect_response(&mut packet.message, binding) {
+                    // None orders before any Partial IV
+                    Ok(Some(partial_iv)) if observation.partial_iv < Some(partial_iv) => {
+                        observation.partial_iv = Some(partial_iv);
+                    }
+                    Ok(partial_iv) => {
+                        log::warn!(
+                            "Dropping notification without newer Partial IV: {:?}",
+                            partial_iv
+                        );
+                        return;
+                    }
+                    Err(e) => {
+                        log::warn!("Dropping notification: {:?}", e);
+                        return;
+                    }
+                }
+                true
             }
-            Err(e) => return Err(CoapError::ConnectionError(e)),
+            _ => false,
         };
 
-        let packet = Packet::from_bytes(&buf[..nread]).map_err(|_| CoapError::InvalidResponse)?;
-        Ok(CoapResponse { message: packet })
+        let fresh = match packet.message.get_observe_value() {
+            Some(Ok(sequence)) if protected || observation.is_fresh(sequence) => {
+                observation.sequence = Some(sequence);
+                observation.last_update = Instant::now();
+                true
+            }
+            Some(Ok(_)) => false,
+            // Without Observe option the server ended the observation
+            _ => {
+                self.observation = None;
+                false
+            }
+        };
+
+        if packet.message.header.get_type() == MessageType::Confirmable {
+            self.send_ack(&packet, addr);
+        }
+        if fresh {
+            log::debug!(
+                "Received notification: {}",
+                packet.message.header.message_id
+            );
+            self.notifications.push_back(packet);
+        }
+    }
+
+    fn recv(&mut self, addr: SocketAddr) -> Result<CoapResponse, CoapError> {
+        let mut buf = [0; 1500];
+
+        loop {
+            let (nread, src) = match self.socket.recv_from(&mut buf) {
+                Ok(res) => res,
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    return Err(CoapError::TimedOut)
+                }
+                Err(e) => return Err(CoapError::ConnectionError(e)),
+            };
+
+            // Only the peer of the request is allowed to answer, everything else is spoofed or stray
+            if src != addr {
+                log::warn!("Ignoring packet from unexpected source {}", src);
+                continue;
+            }
+
+            // Malformed datagrams say nothing about the request in progress
+            match Packet::from_bytes(&buf[..nread]) {
+                Ok(packet) => return Ok(CoapResponse { message: packet }),
+                Err(_) => log::warn!("Ignoring malformed packet from {}", src),
+            }
+        }
     }
 
     pub fn set_timeout(&mut self, dur: Option<Duration>) {
+        self.timeout = dur;
         self.socket.set_read_timeout(dur).unwrap();
     }
 }
+
+/// Time to wait for the acknowledgement after `retransmissions`, randomised by `random`
+fn ack_timeout(random: u32, retransmissions: u32) -> Duration {
+    let factor = 1.0 + (ACK_RANDOM_FACTOR - 1.0) * random as f64 / u32::MAX as f64;
+    ACK_TIMEOUT.mul_f64(factor) * (1 << retransmissions)
+}
+
+/// Waits after each transmission of a request, limited to `MAX_TRANSMIT_WAIT` in total
+fn ack_timeouts(random: u32) -> Vec<Duration> {
+    let mut timeouts = Vec::new();
+    let mut total = Duration::ZERO;
+    for retransmissions in 0..=MAX_RETRANSMIT {
+        let timeout = ack_timeout(random, retransmissions).min(MAX_TRANSMIT_WAIT - total);
+        if timeout.is_zero() {
+            break;
+        }
+        timeouts.push(timeout);
+        total += timeout;
+    }
+    timeouts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ack_timeout_is_randomised_and_doubles() {
+        assert_eq!(ack_timeout(0, 0), Duration::from_secs(2));
+        assert_eq!(ack_timeout(u32::MAX, 0), Duration::from_secs(3));
+        assert_eq!(ack_timeout(0, 1), Duration::from_secs(4));
+        assert_eq!(
+            ack_timeout(u32::MAX, MAX_RETRANSMIT),
+            Duration::from_secs(48)
+        );
+        let timeout = ack_timeout(u32::MAX / 2, 2);
+        assert!(timeout > Duration::from_secs(8) && timeout < Duration::from_secs(12));
+    }
+
+    #[test]
+    fn ack_timeouts_are_limited_in_total() {
+        assert_eq!(
+            ack_timeouts(0),
+            vec![Duration::from_secs(2), Duration::from_secs(3)]
+        );
+        assert_eq!(
+            ack_timeouts(u32::MAX),
+            vec![Duration::from_secs(3), Duration::from_secs(2)]
+        );
+        for &random in &[1, u32::MAX / 3, u32::MAX / 2, u32::MAX - 1] {
+            let timeouts = ack_timeouts(random);
+            assert!(timeouts.len() >= 2);
+            assert_eq!(timeouts[0], ack_timeout(random, 0));
+            assert_eq!(timeouts.iter().sum::<Duration>(), MAX_TRANSMIT_WAIT);
+        }
+    }
+
+    #[test]
+    fn block_option_encodes_size_exponent_and_more_flag() {
+        assert_eq!(BlockOption::new(0, false, 6).to_bytes(), vec![0x06]);
+        assert_eq!(BlockOption::new(0, true, 2).to_bytes(), vec![0x0a]);
+        assert_eq!(BlockOption::new(1, true, 6).to_bytes(), vec![0x1e]);
+        // The value 0 is encoded with zero bytes
+        assert_eq!(BlockOption::new(0, false, 0).to_bytes(), Vec::<u8>::new());
+        assert_eq!(BlockOption::new(0, false, 6).size(), 1024);
+        assert_eq!(BlockOption::new(0, false, 0).size(), 16);
+    }
+
+    #[test]
+    fn block_option_encodes_num_in_one_to_three_bytes() {
+        assert_eq!(BlockOption::new(15, false, 6).to_bytes(), vec![0xf6]);
+        assert_eq!(BlockOption::new(16, false, 6).to_bytes(), vec![0x01, 0x06]);
+        assert_eq!(BlockOption::new(4095, true, 6).to_bytes(), vec![0xff, 0xfe]);
+        assert_eq!(
+            BlockOption::new(4096, false, 6).to_bytes(),
+            vec![0x01, 0x00, 0x06]
+        );
+        assert_eq!(
+            BlockOption::new((1 << 20) - 1, true, 5).to_bytes(),
+            vec![0xff, 0xff, 0xfd]
+        );
+    }
+
+    #[test]
+    fn block_option_decodes_what_it_encodes() {
+        for &num in &[0, 1, 15, 16, 4095, 4096, (1 << 20) - 1] {
+            for &more in &[false, true] {
+                for size_exponent in 0..7 {
+                    let block = BlockOption::new(num, more, size_exponent);
+                    assert_eq!(BlockOption::from_bytes(&block.to_bytes()), Some(block));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn block_option_rejects_invalid_values() {
+        // Size exponent 7 is reserved
+        assert_eq!(BlockOption::from_bytes(&[0x07]), None);
+        // NUM has at most 20 bits
+        assert_eq!(BlockOption::from_bytes(&[0x01, 0x00, 0x00, 0x06]), None);
+        assert_eq!(
+            BlockOption::from_bytes(&[]),
+            Some(BlockOption::new(0, false, 0))
+        );
+    }
+}
@@ -0,0 +1,275 @@
+use std::ffi::{CStr, CString};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+use coap_lite::{CoapOption, MessageClass, MessageType, Packet, RequestType};
+use esp_idf_sys::esp;
+
+use crate::storage::nvs::Nvs;
+
+// Used if the edge is neither configured nor discoverable, address of the edge access point
+const DEFAULT_EDGE_ADDR: &str = "10.0.100.1:5683";
+
+const COAP_PORT: u16 = 5683;
+
+// IPv4 "All CoAP Nodes" multicast group, RFC 7252 section 12.8
+const ALL_COAP_NODES: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 187);
+
+const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Consecutive failed exchanges after which the edge is searched again
+const MAX_FAILURES: u32 = 5;
+
+// Resources the edge has to publish in /.well-known/core
+const EDGE_RESOURCES: [&str; 2] = ["</command>", "</sensor/data>"];
+
+/// Keeps track of the edge address
+///
+/// The address is taken from the NVS namespace "edge", key "addr" if configured. Otherwise the last
+/// address the edge was reachable at ("cached") is used, or the edge is discovered by mDNS
+/// (`_coap._udp` service) or a multicast CoAP query of `/.well-known/core`. A configured address
+/// is never replaced by a discovered one, any host on the network could answer the discovery.
+pub struct EdgeLocator {
+    nvs: Option<Nvs>,
+    configured: Option<SocketAddr>,
+    cached: Option<SocketAddr>,
+    current: SocketAddr,
+    failures: u32,
+}
+
+impl EdgeLocator {
+    pub fn new() -> EdgeLocator {
+        let nvs = match Nvs::open("edge") {
+            Ok(nvs) => Some(nvs),
+            Err(e) => {
+                log::warn!("EdgeLocator: Failed to open NVS: {:?}", e);
+                None
+            }
+        };
+
+        let configured = nvs.as_ref().and_then(|nvs| load_addr(nvs, "addr"));
+        let cached = nvs.as_ref().and_then(|nvs| load_addr(nvs, "cached"));
+
+        let current = match configured.or(cached).or_else(discover) {
+            Some(addr) => addr,
+            None => DEFAULT_EDGE_ADDR.parse().unwrap(),
+        };
+        log::info!("EdgeLocator: Using edge at {}", current);
+
+        EdgeLocator {
+            nvs,
+            configured,
+            cached,
+            current,
+            failures: 0,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.current
+    }
+
+    /// Remembers the current address as last good address
+    pub fn report_success(&mut self) {
+        self.failures = 0;
+
+        if self.cached == Some(self.current) {
+            return;
+        }
+        if let Some(nvs) = &mut self.nvs {
+            match nvs.set_str("cached", &self.current.to_string()) {
+                Ok(()) => self.cached = Some(self.current),
+                Err(e) => log::warn!("EdgeLocator: Failed to cache address: {:?}", e),
+            }
+        }
+    }
+
+    /// Searches the edge again after repeated failures, returns true if the address changed
+    pub fn report_failure(&mut self) -> bool {
+        self.failures += 1;
+        if self.failures < MAX_FAILURES {
+            return false;
+        }
+        self.failures = 0;
+
+        // Only the configured address is trusted to send commands, it is kept while unreachable
+        if self.configured.is_some() {
+            log::warn!(
+                "EdgeLocator: Configured edge at {} unreachable",
+                self.current
+            );
+            return false;
+        }
+
+        log::info!(
+            "EdgeLocator: Edge at {} unreachable, rediscovering",
+            self.current
+        );
+        let previous = self.current;
+        self.current = match discover() {
+            Some(addr) => addr,
+            None => self.cached.unwrap_or(previous),
+        };
+
+        if self.current != previous {
+            log::info!("EdgeLocator: Switched edge to {}", self.current);
+        }
+        self.current != previous
+    }
+}
+
+/// Configured or last good address of the edge without searching it, the default otherwise
+pub fn known_edge_addr() -> SocketAddr {
+    Nvs::open("edge")
+        .ok()
+        .and_then(|nvs| load_addr(&nvs, "addr").or_else(|| load_addr(&nvs, "cached")))
+        .unwrap_or_else(|| DEFAULT_EDGE_ADDR.parse().unwrap())
+}
+
+fn load_addr(nvs: &Nvs, key: &str) -> Option<SocketAddr> {
+    let value = nvs.get_str(key).ok().flatten()?;
+    match value.parse() {
+        Ok(addr) => Some(addr),
+        Err(_) => {
+            log::warn!(
+                "EdgeLocator: Ignoring invalid address {:?} in {}",
+                value,
+                key
+            );
+            None
+        }
+    }
+}
+
+/// Searches the edge by mDNS first and by a CoAP multicast request second
+pub fn discover() -> Option<SocketAddr> {
+    let addr = match query_mdns() {
+        Some(addr) => Some(addr),
+        None => query_multicast(),
+    };
+    match addr {
+        Some(addr) => log::info!("discover(): Found edge at {}", addr),
+        None => log::warn!("discover(): No edge found"),
+    }
+    addr
+}
+
+fn query_mdns() -> Option<SocketAddr> {
+    // Initialising twice is reported as invalid state
+    match esp!(unsafe { esp_idf_sys::mdns_init() }) {
+        Ok(()) => (),
+        Err(e) if e.code() == esp_idf_sys::ESP_ERR_INVALID_STATE as esp_idf_sys::esp_err_t => (),
+        Err(e) => {
+            log::warn!("query_mdns(): Failed to initialise mDNS: {:?}", e);
+            return None;
+        }
+    }
+
+    let service = CString::new("_coap").unwrap();
+    let proto = CString::new("_udp").unwrap();
+    let mut results: *mut esp_idf_sys::mdns_result_t = std::ptr::null_mut();
+    let res = esp!(unsafe {
+        esp_idf_sys::mdns_query_ptr(
+            service.as_ptr(),
+            proto.as_ptr(),
+            DISCOVERY_TIMEOUT.as_millis() as u32,
+            4,
+            &mut results,
+        )
+    });
+    if let Err(e) = res {
+        log::warn!("query_mdns(): Query failed: {:?}", e);
+        return None;
+    }
+
+    let mut found = None;
+    let mut result = results;
+    while found.is_none() && !result.is_null() {
+        let service = unsafe { &*result };
+        let mut ip = service.addr;
+        while !ip.is_null() {
+            let ip_addr = unsafe { &*ip };
+            if ip_addr.addr.type_ == esp_idf_sys::ESP_IPADDR_TYPE_V4 as u8 {
+                // lwIP stores the address in network byte order
+                let octets = unsafe { ip_addr.addr.u_addr.ip4.addr }.to_le_bytes();
+                found = Some(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(octets),
+                    service.port,
+                )));
+                if !service.instance_name.is_null() {
+                    log::info!("query_mdns(): Found instance {:?}", unsafe {
+                        CStr::from_ptr(service.instance_name)
+                    });
+                }
+                break;
+            }
+            ip = ip_addr.next;
+        }
+        result = service.next;
+    }
+
+    unsafe { esp_idf_sys::mdns_query_results_free(results) };
+    found
+}
+
+fn query_multicast() -> Option<SocketAddr> {
+    let socket = match UdpSocket::bind("0.0.0.0:0") {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::warn!("query_multicast(): Failed to bind socket: {}", e);
+            return None;
+        }
+    };
+
+    // Multicast requests must be non-confirmable
+    let mut request = Packet::new();
+    request.header.set_type(MessageType::NonConfirmable);
+    request.header.code = MessageClass::Request(RequestType::Get);
+    request.header.message_id = rand_u16();
+    let token = rand_u16().to_le_bytes().to_vec();
+    request.set_token(token.clone());
+    request.add_option(CoapOption::UriPath, b".well-known".to_vec());
+    request.add_option(CoapOption::UriPath, b"core".to_vec());
+
+    let group = SocketAddr::V4(SocketAddrV4::new(ALL_COAP_NODES, COAP_PORT));
+    if let Err(e) = socket.send_to(&request.to_bytes().unwrap(), group) {
+        log::warn!("query_multicast(): Failed to send request: {}", e);
+        return None;
+    }
+
+    let deadline = Instant::now() + DISCOVERY_TIMEOUT;
+    let mut buf = [0; 1500];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return None;
+        }
+        socket.set_read_timeout(Some(remaining)).unwrap();
+
+        let (nread, src) = match socket.recv_from(&mut buf) {
+            Ok(res) => res,
+            Err(_) => return None,
+        };
+        let response = match Packet::from_bytes(&buf[..nread]) {
+            Ok(response) => response,
+            Err(_) => continue,
+        };
+        if response.get_token() != &token[..] {
+            continue;
+        }
+
+        // Any CoAP node may answer, only the edge offers the command and sensor resources
+        let links = String::from_utf8_lossy(&response.payload);
+        if EDGE_RESOURCES
+            .iter()
+            .all(|resource| links.contains(resource))
+        {
+            return Some(src);
+        }
+        log::debug!("query_multicast(): Ignoring {} without edge resources", src);
+    }
+}
+
+fn rand_u16() -> u16 {
+    (unsafe { esp_idf_sys::esp_random() }) as u16
+}
@@ -1,2 +1,8 @@
-pub mod wifi;
 pub mod coap;
+pub mod discovery;
+pub mod mqtt;
+pub mod oscore;
+pub mod provisioning;
+pub mod sntp;
+pub mod uplink;
+pub mod wifi;
@@ -0,0 +1,289 @@
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use embedded_svc::mqtt::client::utils::ConnState;
+use embedded_svc::mqtt::client::{
+    Client, Connection, Event, Message, MessageId, MessageImpl, Publish, QoS,
+};
+use esp_idf_svc::mqtt::client::{EspMqttClient, MqttClientConfiguration};
+use esp_idf_sys::{esp, EspError};
+
+use crate::command::{parse_command, Command, CommandType};
+use crate::control::lighttracking::MotorAngles;
+use crate::device::{DeviceInfo, DeviceStatus};
+use crate::telemetry::{self, DataPoint};
+use crate::transport::{CommandSource, TelemetrySink};
+
+// Time for the broker to acknowledge a publication
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Broker URL and optional credentials, `mqtts://` brokers are verified with the CA certificate
+#[derive(Clone, Debug)]
+pub struct MqttSettings {
+    pub broker: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub ca_certificate: Option<String>,
+}
+
+/// Events forwarded by the thread driving the MQTT connection
+#[derive(Debug)]
+enum Incoming {
+    Connected,
+    Disconnected,
+    Published(MessageId),
+    Received(Vec<u8>),
+}
+
+/// Exchanges commands and telemetry with an MQTT broker
+///
+/// Datapoints are published to `sensors/<device_id>` in the format of the edge's /sensor/data
+/// resource, the device info is retained at `devices/<device_id>` and the status at
+/// `status/<device_id>`. Commands are received from `commands/<device_id>` in the format of the
+/// edge's /command resource. They should be published retained, so the device receives the
+/// current command on each subscription. Angle offsets are not reported, follower commands have
+/// to carry the offsets themselves. Schedule commands are refused, only the edge serves their
+/// tables.
+pub struct MqttUplink {
+    client: EspMqttClient<ConnState<MessageImpl, EspError>>,
+    incoming: Receiver<Incoming>,
+    connected: bool,
+    command: Option<Command>,
+    device_id: u32,
+    command_topic: String,
+}
+
+impl MqttUplink {
+    pub fn new(settings: &MqttSettings, device_id: u32) -> Result<MqttUplink, EspError> {
+        let tls = settings.broker.starts_with("mqtts://");
+        if tls {
+            // Without a CA certificate the broker could not be verified
+            let ca_certificate = match &settings.ca_certificate {
+                Some(ca_certificate) => ca_certificate,
+                None => {
+                    log::error!(
+                        "MqttUplink: No CA certificate to verify {}",
+                        settings.broker
+                    );
+                    return Err(EspError::from(
+                        esp_idf_sys::ESP_ERR_INVALID_ARG as esp_idf_sys::esp_err_t,
+                    )
+                    .unwrap());
+                }
+            };
+            // The PEM is passed with its terminating zero
+            let pem = format!("{}\0", ca_certificate);
+            esp!(unsafe {
+                esp_idf_sys::esp_tls_set_global_ca_store(pem.as_ptr(), pem.len() as u32)
+            })?;
+        } else {
+            log::warn!("MqttUplink: Traffic to the broker is unencrypted");
+        }
+        if settings.username.is_none() {
+            log::warn!("MqttUplink: No credentials for the broker");
+        }
+
+        let client_id = format!("solar-panel-{}", device_id);
+        let conf = MqttClientConfiguration {
+            client_id: Some(&client_id),
+            username: settings.username.as_deref(),
+            password: settings.password.as_deref(),
+            use_global_ca_store: tls,
+            ..Default::default()
+        };
+
+        // The client reconnects on its own, the connection has to be driven by a separate thread
+        let (client, mut connection) = EspMqttClient::new_with_conn(&settings.broker, &conf)?;
+        let (sender, incoming) = mpsc::channel();
+        std::thread::spawn(move || {
+            while let Some(event) = connection.next() {
+                let event = match event {
+                    Ok(Event::Connected(_)) => Incoming::Connected,
+                    Ok(Event::Disconnected) => Incoming::Disconnected,
+                    Ok(Event::Published(id)) => Incoming::Published(id),
+                    Ok(Event::Received(message)) => Incoming::Received(message.data().to_vec()),
+                    Ok(_) => continue,
+                    Err(e) => {
+                        log::warn!("MqttUplink: {:?}", e);
+                        continue;
+                    }
+                };
+                if sender.send(event).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(MqttUplink {
+            client,
+            incoming,
+            connected: false,
+            command: None,
+            device_id,
+            command_topic: format!("commands/{}", device_id),
+        })
+    }
+
+    /// Handles the events received within `timeout`, returns early once `until` returns true
+    fn process_events<F: FnMut(&Incoming) -> bool>(&mut self, timeout: Duration, mut until: F) {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let event = match self
+                .incoming
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => return,
+                Err(RecvTimeoutError::Disconnected) => {
+                    std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                    return;
+                }
+            };
+
+            match &event {
+                Incoming::Connected => {
+                    log::info!("MqttUplink: Connected, subscribing {}", self.command_topic);
+                    self.connected = true;
+                    // The broker sends the retained command after subscribing
+                    if let Err(e) = self
+                        .client
+                        .subscribe(self.command_topic.as_str(), QoS::AtLeastOnce)
+                    {
+                        log::warn!("MqttUplink: Failed to subscribe: {:?}", e);
+                    }
+                }
+                Incoming::Disconnected => {
+                    log::warn!("MqttUplink: Disconnected");
+                    self.connected = false;
+                }
+                Incoming::Published(_) => (),
+                Incoming::Received(payload) => {
+                    let command = parse_received(payload);
+                    log::info!("MqttUplink: Got command: {:?}", command);
+                    if command.is_some() {
+                        self.command = command;
+                    }
+                }
+            }
+
+            if until(&event) {
+                return;
+            }
+        }
+    }
+
+    /// Publishes with QoS 1 and waits for the acknowledgement of the broker
+    fn publish(&mut self, topic: &str, retain: bool, payload: &[u8]) -> bool {
+        self.process_events(Duration::from_millis(0), |_| false);
+        if !self.connected {
+            return false;
+        }
+
+        let id = match self
+            .client
+            .publish(topic, QoS::AtLeastOnce, retain, payload)
+        {
+            Ok(id) => id,
+            Err(e) => {
+                log::warn!("MqttUplink: Failed to publish to {}: {:?}", topic, e);
+                return false;
+            }
+        };
+
+        let mut acknowledged = false;
+        self.process_events(PUBLISH_TIMEOUT, |event| {
+            acknowledged = matches!(event, Incoming::Published(published) if *published == id);
+            acknowledged || matches!(event, Incoming::Disconnected)
+        });
+        if !acknowledged {
+            log::warn!("MqttUplink: Publication to {} not acknowledged", topic);
+        }
+        acknowledged
+    }
+}
+
+impl CommandSource for MqttUplink {
+    fn request_command(&mut self, _target_angle_offset: &MotorAngles) -> Option<Command> {
+        self.process_events(Duration::from_millis(0), |_| false);
+        self.command.take()
+    }
+
+    fn receive_pushed_command(&mut self, timeout: Duration) -> Option<Command> {
+        self.process_events(timeout, |event| matches!(event, Incoming::Received(_)));
+        self.command.take()
+    }
+}
+
+impl TelemetrySink for MqttUplink {
+    fn send_sensor_data(&mut self, datapoints: &[DataPoint], dropped: u32) -> bool {
+        let topic = format!("sensors/{}", self.device_id);
+        let payload = telemetry::serialize_sensor_data(datapoints, dropped, self.device_id);
+        if !self.publish(&topic, false, &payload) {
+            return false;
+        }
+        log::info!(
+            "send_sensor_data(): Published {} datapoints, {} dropped",
+            datapoints.len(),
+            dropped
+        );
+        true
+    }
+
+    fn send_device_info(&mut self, device_info: &DeviceInfo) -> bool {
+        let topic = format!("devices/{}", self.device_id);
+        if !self.publish(&topic, true, &device_info.serialize()) {
+            return false;
+        }
+        log::info!("send_device_info(): Published {:?}", device_info);
+        true
+    }
+
+    fn send_status(&mut self, status: &DeviceStatus) -> bool {
+        let topic = format!("status/{}", self.device_id);
+        if !self.publish(&topic, true, &status.serialize()) {
+            return false;
+        }
+        log::info!("send_status(): Published {:?}", status);
+        true
+    }
+}
+
+/// Parses a command received from the broker, `None` for malformed payloads and schedules
+///
+/// The device keeps executing the previous command instead of a schedule without its table.
+fn parse_received(payload: &[u8]) -> Option<Command> {
+    let command = parse_command(payload)?;
+    if command.command == CommandType::Schedule {
+        log::warn!("MqttUplink: Refusing schedule command, tables are only served by the edge");
+        return None;
+    }
+    Some(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_received_commands() {
+        let command = parse_received(&[CommandType::LightTracking as u8]).unwrap();
+        assert_eq!(command.command, CommandType::LightTracking);
+
+        let mut goto = vec![CommandType::Goto as u8];
+        goto.extend_from_slice(&270i32.to_le_bytes());
+        goto.extend_from_slice(&40i32.to_le_bytes());
+        let command = parse_received(&goto).unwrap();
+        assert_eq!((command.angle_hor, command.angle_ver), (270, 40));
+
+        assert!(parse_received(&[]).is_none());
+        assert!(parse_received(&[CommandType::Goto as u8, 0]).is_none());
+    }
+
+    #[test]
+    fn refuses_schedules() {
+        let mut schedule = vec![CommandType::Schedule as u8];
+        schedule.extend_from_slice(&1_656_000_000u64.to_le_bytes());
+        assert!(parse_command(&schedule).is_some());
+        assert!(parse_received(&schedule).is_none());
+    }
+}
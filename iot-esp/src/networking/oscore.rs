@@ -0,0 +1,474 @@
+use aes::Aes128;
+use ccm::{
+    aead::{generic_array::GenericArray, Aead, KeyInit, Payload},
+    consts::{U13, U8},
+    Ccm,
+};
+use coap_lite::{CoapOption, MessageClass, Packet, RequestType};
+use hkdf::Hkdf;
+use sha2::Sha256;
+
+use crate::storage::nvs::Nvs;
+
+// AES-CCM-16-64-128, the mandatory to implement algorithm of OSCORE (RFC 8613)
+type AesCcm16_64_128 = Ccm<Aes128, U8, U13>;
+const ALG_AEAD: u64 = 10;
+const KEY_LEN: usize = 16;
+const NONCE_LEN: usize = 13;
+const MAX_ID_LEN: usize = NONCE_LEN - 6;
+const MAX_SEQUENCE_NUMBER: u64 = (1 << 40) - 1;
+
+// Sequence numbers reserved in flash at once, these are skipped after a reboot
+const SEQUENCE_NUMBER_WINDOW: u64 = 100;
+
+// Options that are encrypted (class E), sorted by option number
+const INNER_OPTIONS: [(CoapOption, u16); 16] = [
+    (CoapOption::IfMatch, 1),
+    (CoapOption::ETag, 4),
+    (CoapOption::IfNoneMatch, 5),
+    (CoapOption::Observe, 6),
+    (CoapOption::LocationPath, 8),
+    (CoapOption::UriPath, 11),
+    (CoapOption::ContentFormat, 12),
+    (CoapOption::MaxAge, 14),
+    (CoapOption::UriQuery, 15),
+    (CoapOption::Accept, 17),
+    (CoapOption::LocationQuery, 20),
+    (CoapOption::Block2, 23),
+    (CoapOption::Block1, 27),
+    (CoapOption::Size2, 28),
+    (CoapOption::Size1, 60),
+    (CoapOption::NoResponse, 258),
+];
+
+// Option number and value
+type Options = Vec<(u16, Vec<u8>)>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OscoreError {
+    MissingOption,           // Message is not protected
+    InvalidOption,           // OSCORE option or inner options are malformed
+    DecryptionFailed,        // Wrong key or the message was tampered with
+    SequenceNumberExhausted, // Security context must be renewed
+}
+
+/// Persists the sender sequence number, so that no nonce is reused after a reboot
+pub trait SequenceNumberStore {
+    fn load(&mut self) -> Option<u64>;
+    fn store(&mut self, sequence_number: u64);
+}
+
+/// Parameters of a protected request needed to verify its responses
+#[derive(Clone, Debug)]
+pub struct RequestBinding {
+    kid: Vec<u8>,
+    piv: Vec<u8>,
+    nonce: [u8; NONCE_LEN],
+}
+
+/// OSCORE security context of a client without ID context
+pub struct SecurityContext {
+    sender_id: Vec<u8>,
+    recipient_id: Vec<u8>,
+    sender_key: [u8; KEY_LEN],
+    recipient_key: [u8; KEY_LEN],
+    common_iv: [u8; NONCE_LEN],
+    sequence_number: u64,
+    sequence_number_store: Option<Box<dyn SequenceNumberStore>>,
+    reserved_sequence_number: u64,
+}
+
+impl SecurityContext {
+    pub fn new(
+        master_secret: &[u8],
+        master_salt: &[u8],
+        sender_id: &[u8],
+        recipient_id: &[u8],
+    ) -> SecurityContext {
+        assert!(sender_id.len() <= MAX_ID_LEN && recipient_id.len() <= MAX_ID_LEN);
+
+        let hkdf = Hkdf::<Sha256>::new(Some(master_salt), master_secret);
+        let derive = |id: &[u8], key_type: &str, okm: &mut [u8]| {
+            let mut info = vec![];
+            cbor_array(5, &mut info);
+            cbor_bytes(id, &mut info);
+            cbor_nil(&mut info);
+            cbor_uint(ALG_AEAD, &mut info);
+            cbor_text(key_type, &mut info);
+            cbor_uint(okm.len() as u64, &mut info);
+            hkdf.expand(&info, okm).unwrap();
+        };
+
+        let mut sender_key = [0; KEY_LEN];
+        derive(sender_id, "Key", &mut sender_key);
+        let mut recipient_key = [0; KEY_LEN];
+        derive(recipient_id, "Key", &mut recipient_key);
+        let mut common_iv = [0; NONCE_LEN];
+        derive(&[], "IV", &mut common_iv);
+
+        SecurityContext {
+            sender_id: sender_id.to_vec(),
+            recipient_id: recipient_id.to_vec(),
+            sender_key,
+            recipient_key,
+            common_iv,
+            sequence_number: 0,
+            sequence_number_store: None,
+            reserved_sequence_number: 0,
+        }
+    }
+
+    /// Continue with the sequence number reserved before the last reboot
+    pub fn with_sequence_number_store(
+        mut self,
+        mut store: Box<dyn SequenceNumberStore>,
+    ) -> SecurityContext {
+        self.sequence_number = store.load().unwrap_or(0);
+        self.reserved_sequence_number = self.sequence_number;
+        self.sequence_number_store = Some(store);
+        self
+    }
+
+    fn next_sequence_number(&mut self) -> Result<u64, OscoreError> {
+        if self.sequence_number > MAX_SEQUENCE_NUMBER {
+            return Err(OscoreError::SequenceNumberExhausted);
+        }
+
+        if let Some(store) = &mut self.sequence_number_store {
+            if self.sequence_number >= self.reserved_sequence_number {
+                self.reserved_sequence_number = self.sequence_number + SEQUENCE_NUMBER_WINDOW;
+                store.store(self.reserved_sequence_number);
+            }
+        }
+
+        let sequence_number = self.sequence_number;
+        self.sequence_number += 1;
+        Ok(sequence_number)
+    }
+
+    /// Encrypts the request in place and replaces it by a POST (or FETCH for observations)
+    pub fn protect_request(&mut self, packet: &mut Packet) -> Result<RequestBinding, OscoreError> {
+        let piv = encode_partial_iv(self.next_sequence_number()?);
+        let nonce = self.nonce(&self.sender_id, &piv);
+        let aad = aad(&self.sender_id, &piv);
+
+        let mut plaintext = vec![u8::from(packet.header.code)];
+        let mut inner_options = vec![];
+        for (option, number) in INNER_OPTIONS {
+            if let Some(values) = packet.get_option(option) {
+                for value in values {
+                    inner_options.push((number, value.clone()));
+                }
+            }
+        }
+        encode_options(&inner_options, &mut plaintext);
+        if !packet.payload.is_empty() {
+            plaintext.push(0xFF);
+            plaintext.extend_from_slice(&packet.payload);
+        }
+
+        let ciphertext = AesCcm16_64_128::new(GenericArray::from_slice(&self.sender_key))
+            .encrypt(
+                GenericArray::from_slice(&nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: &aad,
+                },
+            )
+            .unwrap();
+
+        // Observe is needed by the server to handle the observation, it is both inner and outer
+        let observe = packet.get_option(CoapOption::Observe).is_some();
+        for (option, _) in INNER_OPTIONS {
+            if option != CoapOption::Observe {
+                packet.clear_option(option);
+            }
+        }
+        packet.header.code = MessageClass::Request(if observe {
+            RequestType::Fetch
+        } else {
+            RequestType::Post
+        });
+
+        // Flags (Partial IV length and kid present), Partial IV, kid
+        let mut option_value = vec![piv.len() as u8 | 0x08];
+        option_value.extend_from_slice(&piv);
+        option_value.extend_from_slice(&self.sender_id);
+        packet.add_option(CoapOption::Oscore, option_value);
+        packet.payload = ciphertext;
+
+        Ok(RequestBinding {
+            kid: self.sender_id.clone(),
+            piv,
+            nonce,
+        })
+    }
+
+    /// Decrypts a response to the request of `binding` in place
+    ///
+    /// Returns the Partial IV of the response as sequence number of the server, if it carries one.
+    /// Notifications always do, the client rejects replays by it (RFC 8613 section 7.4.1).
+    pub fn unprotect_response(
+        &self,
+        packet: &mut Packet,
+        binding: &RequestBinding,
+    ) -> Result<Option<u64>, OscoreError> {
+        let option_value = packet
+            .get_option(CoapOption::Oscore)
+            .and_then(|values| values.front())
+            .ok_or(OscoreError::MissingOption)?
+            .clone();
+
+        // Responses reuse the nonce of the request unless they carry their own Partial IV
+        let partial_iv = match option_value.first() {
+            None => None,
+            Some(flags) => {
+                let piv_len = (flags & 0x07) as usize;
+                if flags & 0xE0 != 0 || piv_len > 5 || option_value.len() < 1 + piv_len {
+                    return Err(OscoreError::InvalidOption);
+                }
+                if piv_len == 0 {
+                    None
+                } else {
+                    Some(&option_value[1..1 + piv_len])
+                }
+            }
+        };
+        let nonce = match partial_iv {
+            Some(piv) => self.nonce(&self.recipient_id, piv),
+            None => binding.nonce,
+        };
+
+        let plaintext = AesCcm16_64_128::new(GenericArray::from_slice(&self.recipient_key))
+            .decrypt(
+                GenericArray::from_slice(&nonce),
+                Payload {
+                    msg: &packet.payload,
+                    aad: &aad(&binding.kid, &binding.piv),
+                },
+            )
+            .map_err(|_| OscoreError::DecryptionFailed)?;
+
+        let (code, rest) = plaintext.split_first().ok_or(OscoreError::InvalidOption)?;
+        let (inner_options, payload) = decode_options(rest)?;
+
+        packet.clear_option(CoapOption::Oscore);
+        for (option, _) in INNER_OPTIONS {
+            if option != CoapOption::Observe {
+                packet.clear_option(option);
+            }
+        }
+        for (number, value) in inner_options {
+            match INNER_OPTIONS.iter().find(|(_, n)| *n == number) {
+                // The outer Observe option is the one relevant for the client
+                Some((CoapOption::Observe, _)) => (),
+                Some((option, _)) => packet.add_option(*option, value),
+                // Unknown critical options must not be ignored
+                None if number % 2 == 1 => return Err(OscoreError::InvalidOption),
+                None => (),
+            }
+        }
+        packet.header.code = MessageClass::from(*code);
+        packet.payload = payload.to_vec();
+
+        Ok(partial_iv.map(|piv| {
+            piv.iter()
+                .fold(0u64, |sequence, byte| (sequence << 8) | *byte as u64)
+        }))
+    }
+
+    fn nonce(&self, id_piv: &[u8], piv: &[u8]) -> [u8; NONCE_LEN] {
+        // Length of ID_PIV, ID_PIV padded to 7 bytes, Partial IV padded to 5 bytes
+        let mut nonce = [0; NONCE_LEN];
+        nonce[0] = id_piv.len() as u8;
+        nonce[NONCE_LEN - 5 - id_piv.len()..NONCE_LEN - 5].copy_from_slice(id_piv);
+        nonce[NONCE_LEN - piv.len()..].copy_from_slice(piv);
+
+        for (byte, iv) in nonce.iter_mut().zip(self.common_iv.iter()) {
+            *byte ^= iv;
+        }
+        nonce
+    }
+}
+
+/// Sequence number encoded with as few bytes as possible, but at least one
+fn encode_partial_iv(sequence_number: u64) -> Vec<u8> {
+    let bytes = sequence_number.to_be_bytes();
+    let start = bytes
+        .iter()
+        .position(|byte| *byte != 0)
+        .unwrap_or(bytes.len() - 1);
+    bytes[start..].to_vec()
+}
+
+fn aad(request_kid: &[u8], request_piv: &[u8]) -> Vec<u8> {
+    // external_aad = [oscore_version, [alg_aead], request_kid, request_piv, options]
+    let mut external_aad = vec![];
+    cbor_array(5, &mut external_aad);
+    cbor_uint(1, &mut external_aad);
+    cbor_array(1, &mut external_aad);
+    cbor_uint(ALG_AEAD, &mut external_aad);
+    cbor_bytes(request_kid, &mut external_aad);
+    cbor_bytes(request_piv, &mut external_aad);
+    cbor_bytes(&[], &mut external_aad);
+
+    // Enc_structure = ["Encrypt0", h'', external_aad]
+    let mut aad = vec![];
+    cbor_array(3, &mut aad);
+    cbor_text("Encrypt0", &mut aad);
+    cbor_bytes(&[], &mut aad);
+    cbor_bytes(&external_aad, &mut aad);
+    aad
+}
+
+fn encode_options(options: &Options, out: &mut Vec<u8>) {
+    let nibble = |value: usize| -> (u8, Vec<u8>) {
+        if value < 13 {
+            (value as u8, vec![])
+        } else if value < 269 {
+            (13, vec![(value - 13) as u8])
+        } else {
+            (14, ((value - 269) as u16).to_be_bytes().to_vec())
+        }
+    };
+
+    let mut last_number = 0;
+    for (number, value) in options {
+        let (delta, delta_ext) = nibble((number - last_number) as usize);
+        let (length, length_ext) = nibble(value.len());
+        out.push(delta << 4 | length);
+        out.extend_from_slice(&delta_ext);
+        out.extend_from_slice(&length_ext);
+        out.extend_from_slice(value);
+        last_number = *number;
+    }
+}
+
+fn decode_options(mut bytes: &[u8]) -> Result<(Options, &[u8]), OscoreError> {
+    fn extended(nibble: u8, bytes: &mut &[u8]) -> Result<usize, OscoreError> {
+        let (value, len) = match nibble {
+            0..=12 => (nibble as usize, 0),
+            13 if !bytes.is_empty() => (bytes[0] as usize + 13, 1),
+            14 if bytes.len() >= 2 => (u16::from_be_bytes([bytes[0], bytes[1]]) as usize + 269, 2),
+            _ => return Err(OscoreError::InvalidOption),
+        };
+        *bytes = &bytes[len..];
+        Ok(value)
+    }
+
+    let mut options = vec![];
+    let mut number = 0;
+    while let Some((header, rest)) = bytes.split_first() {
+        if *header == 0xFF {
+            if rest.is_empty() {
+                return Err(OscoreError::InvalidOption);
+            }
+            return Ok((options, rest));
+        }
+
+        bytes = rest;
+        let delta = extended(header >> 4, &mut bytes)?;
+        let length = extended(header & 0x0F, &mut bytes)?;
+        if bytes.len() < length {
+            return Err(OscoreError::InvalidOption);
+        }
+        number += delta as u16;
+        options.push((number, bytes[..length].to_vec()));
+        bytes = &bytes[length..];
+    }
+
+    Ok((options, &[]))
+}
+
+fn cbor_header(major_type: u8, value: u64, out: &mut Vec<u8>) {
+    let major_type = major_type << 5;
+    if value < 24 {
+        out.push(major_type | value as u8);
+    } else if value <= u8::MAX as u64 {
+        out.push(major_type | 24);
+        out.push(value as u8);
+    } else if value <= u16::MAX as u64 {
+        out.push(major_type | 25);
+        out.extend_from_slice(&(value as u16).to_be_bytes());
+    } else if value <= u32::MAX as u64 {
+        out.push(major_type | 26);
+        out.extend_from_slice(&(value as u32).to_be_bytes());
+    } else {
+        out.push(major_type | 27);
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+fn cbor_uint(value: u64, out: &mut Vec<u8>) {
+    cbor_header(0, value, out);
+}
+
+fn cbor_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    cbor_header(2, bytes.len() as u64, out);
+    out.extend_from_slice(bytes);
+}
+
+fn cbor_text(text: &str, out: &mut Vec<u8>) {
+    cbor_header(3, text.len() as u64, out);
+    out.extend_from_slice(text.as_bytes());
+}
+
+fn cbor_array(len: u64, out: &mut Vec<u8>) {
+    cbor_header(4, len, out);
+}
+
+fn cbor_nil(out: &mut Vec<u8>) {
+    out.push(0xF6);
+}
+
+impl SequenceNumberStore for Nvs {
+    fn load(&mut self) -> Option<u64> {
+        self.get_u64("ssn").unwrap_or_else(|e| {
+            log::warn!("OSCORE: Failed to load sequence number: {:?}", e);
+            None
+        })
+    }
+
+    fn store(&mut self, sequence_number: u64) {
+        if let Err(e) = self.set_u64("ssn", sequence_number) {
+            log::error!("OSCORE: Failed to store sequence number: {:?}", e);
+        }
+    }
+}
+
+/// Loads the per-device keys provisioned into the "oscore" NVS namespace
+pub fn load_security_context() -> Option<SecurityContext> {
+    let nvs = match Nvs::open("oscore") {
+        Ok(nvs) => nvs,
+        Err(e) => {
+            log::warn!("OSCORE: No security context provisioned: {:?}", e);
+            return None;
+        }
+    };
+
+    let load = |key: &str| nvs.get_blob(key).ok().flatten();
+    let (master_secret, sender_id, recipient_id) =
+        match (load("secret"), load("sender_id"), load("recipient_id")) {
+            (Some(secret), Some(sender_id), Some(recipient_id)) => {
+                (secret, sender_id, recipient_id)
+            }
+            _ => {
+                log::warn!("OSCORE: Incomplete security context provisioned");
+                return None;
+            }
+        };
+    let master_salt = load("salt").unwrap_or_default();
+
+    if sender_id.len() > MAX_ID_LEN || recipient_id.len() > MAX_ID_LEN {
+        log::error!(
+            "OSCORE: Sender and recipient id must not exceed {} bytes",
+            MAX_ID_LEN
+        );
+        return None;
+    }
+
+    Some(
+        SecurityContext::new(&master_secret, &master_salt, &sender_id, &recipient_id)
+            .with_sequence_number_store(Box::new(nvs)),
+    )
+}
@@ -0,0 +1,500 @@
+use std::convert::TryInto;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::time::{Duration, Instant};
+
+use esp_idf_sys::EspError;
+
+use super::wifi::{self, Network, WifiManager};
+use crate::device;
+use crate::storage::nvs::Nvs;
+
+// Gateway of the provisioning access point, every DNS name resolves to it
+const AP_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 4, 1);
+
+// Fits a form with an URL encoded CA certificate
+const MAX_REQUEST_SIZE: usize = 8192;
+
+// Configured devices go back to station mode if nobody used the page for this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+// Characters of the access point key, without ones that are easily confused on a label
+const KEY_CHARS: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+const KEY_LENGTH: usize = 12;
+
+const FORM: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta name="viewport" content="width=device-width, initial-scale=1"><title>Solar panel setup</title></head>
+<body>
+<h1>Solar panel setup</h1>
+<p>{message}</p>
+<form method="post" action="/save">
+<p>WiFi SSID<br><input name="ssid" required></p>
+<p>WiFi password<br><input name="password" type="password"></p>
+<p>Device id (optional, derived from the MAC address if empty)<br><input name="device_id" type="number" min="0" value="{device_id}" placeholder="{default_device_id}"></p>
+<p>Edge address (optional, discovered if empty)<br><input name="edge_addr" placeholder="10.0.100.1:5683"></p>
+<p>MQTT broker (optional, replaces CoAP to the edge)<br><input name="mqtt_broker" placeholder="mqtts://10.0.100.1:8883"></p>
+<p>MQTT username (optional)<br><input name="mqtt_username"></p>
+<p>MQTT password (optional)<br><input name="mqtt_password" type="password"></p>
+<p>MQTT CA certificate (PEM, required for mqtts)<br><textarea name="mqtt_ca" rows="6" cols="40"></textarea></p>
+<p>Latitude (optional)<br><input name="latitude" type="number" step="any" min="-90" max="90"></p>
+<p>Longitude (optional)<br><input name="longitude" type="number" step="any" min="-180" max="180"></p>
+<p><input type="submit" value="Save and reboot"></p>
+</form>
+</body>
+</html>
+"#;
+
+/// Configuration entered on the provisioning page
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub network: Network,
+    pub device_id: Option<u32>,
+    pub edge_addr: Option<SocketAddr>,
+    pub mqtt_broker: Option<String>,
+    pub mqtt_username: Option<String>,
+    pub mqtt_password: Option<String>,
+    pub mqtt_ca: Option<String>,
+    pub location: Option<(f32, f32)>,
+}
+
+/// Latitude and longitude in degrees from NVS ("location" namespace)
+pub fn load_location() -> Option<(f32, f32)> {
+    let nvs = Nvs::open("location").ok()?;
+    let load = |key: &str| -> Option<f32> {
+        let bytes = nvs.get_blob(key).ok().flatten()?;
+        Some(f32::from_le_bytes(bytes.as_slice().try_into().ok()?))
+    };
+    Some((load("latitude")?, load("longitude")?))
+}
+
+pub fn store_location(latitude: f32, longitude: f32) -> Result<(), EspError> {
+    let mut nvs = Nvs::open("location")?;
+    nvs.set_blob("latitude", &latitude.to_le_bytes())?;
+    nvs.set_blob("longitude", &longitude.to_le_bytes())
+}
+
+pub fn erase_location() -> Result<(), EspError> {
+    let mut nvs = Nvs::open("location")?;
+    nvs.erase_key("latitude")?;
+    nvs.erase_key("longitude")
+}
+
+/// WPA2 key of the access point from NVS ("provisioning" namespace, key "ap_key")
+///
+/// Generated randomly on first use and only shown on the serial console, so it has to be read
+/// there to print it on a label of the device. It cannot be derived from the label or the MAC
+/// address. A key stored in NVS before the first boot is used instead.
+pub fn access_point_key() -> Result<String, EspError> {
+    let mut nvs = Nvs::open("provisioning")?;
+    if let Some(key) = nvs.get_str("ap_key")? {
+        return Ok(key);
+    }
+
+    let key = (0..KEY_LENGTH)
+        .map(|_| {
+            let random = unsafe { esp_idf_sys::esp_random() } as usize;
+            KEY_CHARS[random % KEY_CHARS.len()] as char
+        })
+        .collect::<String>();
+    nvs.set_str("ap_key", &key)?;
+    Ok(key)
+}
+
+/// Opens an access point with a configuration page, stores the entered settings and reboots
+///
+/// Reboots into station mode after `IDLE_TIMEOUT` without requests if networks are configured.
+pub fn run(wifi: &mut WifiManager) -> ! {
+    let mac = device::mac();
+    let ssid = format!("solar-panel-{:02x}{:02x}", mac[4], mac[5]);
+    let key = match access_point_key() {
+        Ok(key) => key,
+        Err(e) => {
+            log::error!(
+                "provisioning::run(): Failed to load access point key: {:?}",
+                e
+            );
+            restart();
+        }
+    };
+    if let Err(e) = wifi.start_access_point(&ssid, &key, AP_IP) {
+        log::error!("provisioning::run(): Failed to start access point: {:?}", e);
+        restart();
+    }
+
+    // Resolving every name to the device makes phones show the page as captive portal
+    std::thread::spawn(run_dns);
+
+    // Polled to notice the idle timeout
+    let listener = match TcpListener::bind("0.0.0.0:80").and_then(|listener| {
+        listener.set_nonblocking(true)?;
+        Ok(listener)
+    }) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("provisioning::run(): Failed to bind HTTP server: {}", e);
+            restart();
+        }
+    };
+    log::info!(
+        "provisioning::run(): Connect to {} with key {} and open http://{}/",
+        ssid,
+        key,
+        AP_IP
+    );
+
+    let mut last_request = Instant::now();
+    loop {
+        if last_request.elapsed() > IDLE_TIMEOUT && wifi.has_networks() {
+            log::info!("provisioning::run(): No requests, rebooting into station mode");
+            restart();
+        }
+
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+            Err(e) => {
+                log::warn!("provisioning::run(): Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        last_request = Instant::now();
+        if let Err(e) = stream.set_nonblocking(false) {
+            log::warn!("provisioning::run(): {}", e);
+            continue;
+        }
+
+        let settings = match handle_connection(stream) {
+            Ok(Some(settings)) => settings,
+            Ok(None) => continue,
+            Err(e) => {
+                log::warn!("provisioning::run(): {}", e);
+                continue;
+            }
+        };
+
+        match store(&settings) {
+            Ok(()) => {
+                // The settings are not logged, they contain passwords
+                log::info!(
+                    "provisioning::run(): Stored settings for {}, rebooting",
+                    settings.network.ssid
+                );
+                // Let the response reach the browser
+                std::thread::sleep(Duration::from_secs(1));
+                restart();
+            }
+            Err(e) => log::error!("provisioning::run(): Failed to store settings: {:?}", e),
+        }
+    }
+}
+
+fn store(settings: &Settings) -> Result<(), EspError> {
+    wifi::store_network(&settings.network)?;
+
+    // Without an override the device id is derived from the MAC address
+    let mut nvs = Nvs::open("device")?;
+    match settings.device_id {
+        Some(device_id) => nvs.set_u32("id", device_id)?,
+        None => nvs.erase_key("id")?,
+    }
+
+    // Empty fields remove earlier settings, the edge is discovered again
+    let mut nvs = Nvs::open("edge")?;
+    match settings.edge_addr {
+        Some(edge_addr) => nvs.set_str("addr", &edge_addr.to_string())?,
+        None => nvs.erase_key("addr")?,
+    }
+
+    // Without a broker the uplink is CoAP to the edge
+    let mut nvs = Nvs::open("uplink")?;
+    let mqtt = [
+        ("mqtt", &settings.mqtt_broker),
+        ("mqtt_user", &settings.mqtt_username),
+        ("mqtt_pass", &settings.mqtt_password),
+        ("mqtt_ca", &settings.mqtt_ca),
+    ];
+    for (key, value) in mqtt.iter() {
+        match value {
+            Some(value) => nvs.set_str(key, value)?,
+            None => nvs.erase_key(key)?,
+        }
+    }
+
+    // Without a location the edge sends it with a location command
+    match settings.location {
+        Some((latitude, longitude)) => store_location(latitude, longitude)?,
+        None => erase_location()?,
+    }
+
+    Ok(())
+}
+
+/// Serves the form, returns the settings once they were submitted successfully
+fn handle_connection(mut stream: TcpStream) -> Result<Option<Settings>, std::io::Error> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let (method, path, body) = match read_request(&mut stream)? {
+        Some(request) => request,
+        None => return respond(&mut stream, "400 Bad Request", "Bad request").map(|_| None),
+    };
+    log::info!("handle_connection(): {} {}", method, path);
+
+    let form = FORM
+        .replace(
+            "{device_id}",
+            &device::device_id_override()
+                .map(|id| id.to_string())
+                .unwrap_or_default(),
+        )
+        .replace(
+            "{default_device_id}",
+            &device::default_device_id(&device::mac()).to_string(),
+        );
+    if method != "POST" || path != "/save" {
+        // Every other request is answered with the form, including captive portal checks
+        let page = form.replace("{message}", "");
+        return respond(&mut stream, "200 OK", &page).map(|_| None);
+    }
+
+    match parse_settings(&body) {
+        Ok(settings) => {
+            respond(
+                &mut stream,
+                "200 OK",
+                "Settings saved, the panel reboots and connects to the network.",
+            )?;
+            Ok(Some(settings))
+        }
+        Err(message) => {
+            let page = form.replace("{message}", message);
+            respond(&mut stream, "400 Bad Request", &page).map(|_| None)
+        }
+    }
+}
+
+/// Returns method, path and body of a HTTP request
+fn read_request(
+    stream: &mut TcpStream,
+) -> Result<Option<(String, String, String)>, std::io::Error> {
+    let mut request = Vec::new();
+    let mut buf = [0; 512];
+
+    // Read the header
+    let header_end = loop {
+        if let Some(pos) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if request.len() > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+        let nread = stream.read(&mut buf)?;
+        if nread == 0 {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..nread]);
+    };
+
+    let header = String::from_utf8_lossy(&request[..header_end]).into_owned();
+    let mut lines = header.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+
+    let content_length = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    if header_end + content_length > MAX_REQUEST_SIZE {
+        return Ok(None);
+    }
+
+    // Read the rest of the body
+    while request.len() < header_end + content_length {
+        let nread = stream.read(&mut buf)?;
+        if nread == 0 {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..nread]);
+    }
+    let body = String::from_utf8_lossy(&request[header_end..header_end + content_length]);
+
+    Ok(Some((method, path, body.into_owned())))
+}
+
+fn respond(stream: &mut TcpStream, status: &str, content: &str) -> Result<(), std::io::Error> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content.len(),
+        content
+    )?;
+    stream.flush()
+}
+
+fn parse_settings(body: &str) -> Result<Settings, &'static str> {
+    let field = |name: &str| -> String {
+        body.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| url_decode(value))
+            .unwrap_or_default()
+    };
+
+    let ssid = field("ssid");
+    if ssid.is_empty() || ssid.len() > 32 {
+        return Err("The SSID must have 1 to 32 characters.");
+    }
+    let password = field("password");
+    if password.len() > 64 {
+        return Err("The password must not exceed 64 characters.");
+    }
+
+    let device_id = match field("device_id").trim() {
+        "" => None,
+        device_id => Some(
+            device_id
+                .parse()
+                .map_err(|_| "The device id must be a positive number.")?,
+        ),
+    };
+
+    let edge_addr = match field("edge_addr").trim() {
+        "" => None,
+        addr => Some(
+            addr.parse()
+                .map_err(|_| "The edge address must look like 10.0.100.1:5683.")?,
+        ),
+    };
+
+    let mqtt_broker = match field("mqtt_broker").trim() {
+        "" => None,
+        url if url.starts_with("mqtt://") || url.starts_with("mqtts://") => Some(url.to_string()),
+        _ => return Err("The MQTT broker must look like mqtts://10.0.100.1:8883."),
+    };
+    let optional = |name: &str| Some(field(name)).filter(|value| !value.is_empty());
+    let mqtt_username = optional("mqtt_username");
+    let mqtt_password = optional("mqtt_password");
+    if mqtt_password.is_some() && mqtt_username.is_none() {
+        return Err("The MQTT password needs a username.");
+    }
+    let mqtt_ca = match field("mqtt_ca").trim() {
+        "" => None,
+        pem if pem.starts_with("-----BEGIN CERTIFICATE-----") => Some(pem.to_string()),
+        _ => return Err("The MQTT CA certificate must be PEM encoded."),
+    };
+    let tls = mqtt_broker
+        .as_deref()
+        .map_or(false, |url| url.starts_with("mqtts://"));
+    if tls && mqtt_ca.is_none() {
+        return Err("An mqtts broker needs the CA certificate to verify it.");
+    }
+
+    let location = match (field("latitude").trim(), field("longitude").trim()) {
+        ("", "") => None,
+        (latitude, longitude) => {
+            let latitude: f32 = latitude.parse().map_err(|_| "Invalid latitude.")?;
+            let longitude: f32 = longitude.parse().map_err(|_| "Invalid longitude.")?;
+            if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+                return Err("The location is out of range.");
+            }
+            Some((latitude, longitude))
+        }
+    };
+
+    Ok(Settings {
+        network: Network { ssid, password },
+        device_id,
+        edge_addr,
+        mqtt_broker,
+        mqtt_username,
+        mqtt_password,
+        mqtt_ca,
+        location,
+    })
+}
+
+/// Decodes application/x-www-form-urlencoded values
+fn url_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    Err(_) => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Answers every DNS query of type A with the address of the access point
+fn run_dns() {
+    let socket = match UdpSocket::bind("0.0.0.0:53") {
+        Ok(socket) => socket,
+        Err(e) => {
+            log::warn!("run_dns(): Failed to bind DNS server: {}", e);
+            return;
+        }
+    };
+
+    let mut buf = [0; 512];
+    loop {
+        let (nread, src) = match socket.recv_from(&mut buf) {
+            Ok(res) => res,
+            Err(_) => continue,
+        };
+        // Header of 12 bytes, only standard queries with a single question
+        if nread < 12 || buf[2] & 0xf8 != 0 || buf[4..6] != [0, 1] {
+            continue;
+        }
+
+        // Skip the question name to find its end
+        let mut end = 12;
+        while end < nread && buf[end] != 0 {
+            end += buf[end] as usize + 1;
+        }
+        // Zero byte + type + class
+        end += 5;
+        if end > nread {
+            continue;
+        }
+
+        let mut response = Vec::with_capacity(end + 16);
+        response.extend_from_slice(&buf[0..2]); // id
+        response.extend_from_slice(&[0x81, 0x80]); // response, recursion desired + available
+        response.extend_from_slice(&[0, 1, 0, 1, 0, 0, 0, 0]); // 1 question, 1 answer
+        response.extend_from_slice(&buf[12..end]);
+        response.extend_from_slice(&[0xc0, 0x0c]); // name points to the question
+        response.extend_from_slice(&[0, 1, 0, 1]); // type A, class IN
+        response.extend_from_slice(&60u32.to_be_bytes()); // ttl
+        response.extend_from_slice(&4u16.to_be_bytes());
+        response.extend_from_slice(&AP_IP.octets());
+
+        if let Err(e) = socket.send_to(&response, src) {
+            log::warn!("run_dns(): Failed to respond: {}", e);
+        }
+    }
+}
+
+fn restart() -> ! {
+    unsafe { esp_idf_sys::esp_restart() }
+}
@@ -0,0 +1,130 @@
+use std::ffi::CString;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use super::discovery;
+use crate::storage::nvs::Nvs;
+
+// A clock before 2022-01-01 was not set since the last power loss
+const MIN_VALID_TIME: Duration = Duration::from_secs(1_640_995_200);
+
+const SYNC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Incremented by lwIP on every sync
+static SYNC_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Whether the system time is wall clock time
+///
+/// The clock keeps running across software resets, but starts at 1970 after a power loss.
+pub fn is_time_valid() -> bool {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(false, |time| time >= MIN_VALID_TIME)
+}
+
+/// Keeps the system time synchronised by SNTP and tracks the drift of the local clock
+///
+/// The server is taken from the NVS namespace "time", key "server", and defaults to the edge.
+pub struct TimeSync {
+    // lwIP keeps a pointer to the server name
+    _server: CString,
+    syncs: u32,
+    last_sync: Option<(SystemTime, Instant)>,
+    // Reading of the clock before it was set, to convert the timestamps taken in the meantime
+    unset_clock: Option<(SystemTime, Instant)>,
+    drift_ppm: Option<f32>,
+}
+
+impl TimeSync {
+    pub fn start() -> TimeSync {
+        let server = load_server();
+        log::info!("TimeSync: Synchronising with {}", server);
+        let server = CString::new(server).unwrap_or_default();
+
+        unsafe {
+            esp_idf_sys::sntp_setoperatingmode(esp_idf_sys::SNTP_OPMODE_POLL as u8);
+            esp_idf_sys::sntp_setservername(0, server.as_ptr());
+            esp_idf_sys::sntp_set_sync_interval(SYNC_INTERVAL.as_millis() as u32);
+            esp_idf_sys::sntp_set_time_sync_notification_cb(Some(on_sync));
+            esp_idf_sys::sntp_init();
+        }
+
+        let unset_clock = if is_time_valid() {
+            log::info!("TimeSync: Clock kept from before the reset");
+            None
+        } else {
+            Some((SystemTime::now(), Instant::now()))
+        };
+
+        TimeSync {
+            _server: server,
+            syncs: SYNC_COUNT.load(Ordering::Relaxed),
+            last_sync: None,
+            unset_clock,
+            drift_ppm: None,
+        }
+    }
+
+    /// Handles new syncs, returns the step of the clock on the first sync of an unset clock
+    ///
+    /// Relative timestamps taken before have to be shifted by the step to become absolute.
+    pub fn poll(&mut self) -> Option<Duration> {
+        let syncs = SYNC_COUNT.load(Ordering::Relaxed);
+        if syncs == self.syncs {
+            return None;
+        }
+        self.syncs = syncs;
+
+        // The offset between system time and monotonic clock only changes with a sync
+        let (time, instant) = (SystemTime::now(), Instant::now());
+        match self.last_sync {
+            Some((last_time, last_instant)) => {
+                let local = instant.duration_since(last_instant).as_secs_f64();
+                let server = match time.duration_since(last_time) {
+                    Ok(server) => server.as_secs_f64(),
+                    Err(e) => -e.duration().as_secs_f64(),
+                };
+                if server > 0.0 {
+                    // Positive if the local clock runs fast
+                    let drift_ppm = ((local - server) / server * 1e6) as f32;
+                    log::info!(
+                        "TimeSync: Synchronised, clock off by {:.0} ms after {:.0} s ({:.1} ppm)",
+                        (local - server) * 1000.0,
+                        server,
+                        drift_ppm
+                    );
+                    self.drift_ppm = Some(drift_ppm);
+                }
+            }
+            None => log::info!("TimeSync: Synchronised, time is {:?}", time),
+        }
+        self.last_sync = Some((time, instant));
+
+        let (unset_time, unset_instant) = self.unset_clock.take()?;
+        let unset_now = unset_time + instant.duration_since(unset_instant);
+        time.duration_since(unset_now).ok()
+    }
+
+    /// Time of the last sync since the device booted, the status reports it instead of the age
+    /// so it only changes with a sync
+    pub fn last_sync(&self) -> Option<SystemTime> {
+        self.last_sync.map(|(time, _)| time)
+    }
+
+    /// Drift of the local clock measured between the last two syncs
+    pub fn drift_ppm(&self) -> Option<f32> {
+        self.drift_ppm
+    }
+}
+
+fn load_server() -> String {
+    Nvs::open("time")
+        .ok()
+        .and_then(|nvs| nvs.get_str("server").ok().flatten())
+        .filter(|server| !server.is_empty())
+        .unwrap_or_else(|| discovery::known_edge_addr().ip().to_string())
+}
+
+unsafe extern "C" fn on_sync(_tv: *mut esp_idf_sys::timeval) {
+    SYNC_COUNT.fetch_add(1, Ordering::Relaxed);
+}
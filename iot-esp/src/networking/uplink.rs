@@ -0,0 +1,303 @@
+use std::time::Duration;
+
+use coap_lite::RequestType;
+
+use super::coap::Connection;
+use super::discovery::EdgeLocator;
+use super::mqtt::{MqttSettings, MqttUplink};
+use crate::command::{parse_command, Command};
+use crate::control::lighttracking::MotorAngles;
+use crate::control::schedule::Schedule;
+use crate::device::{DeviceInfo, DeviceStatus};
+use crate::ota::Updater;
+use crate::storage::nvs::Nvs;
+use crate::telemetry::{self, DataPoint};
+use crate::transport::guard::UnprotectedGuard;
+use crate::transport::replay::ReplaySource;
+use crate::transport::{self as transport, CommandSource, TelemetrySink};
+
+// The edge forgets observers it has not heard of for LEADER_CONNECTION_TIMEOUT_SECONDS
+const OBSERVE_REREGISTER_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Creates the transports configured in the NVS namespace "uplink"
+///
+/// The key "mqtt" selects MQTT with the broker URL it holds, e.g. `mqtt://10.0.100.1:1883`, with
+/// the optional credentials "mqtt_user" and "mqtt_pass" and the CA certificate "mqtt_ca" (PEM) that
+/// verifies `mqtts://` brokers.
+/// Otherwise CoAP to the edge is used. The key "replay" replaces the command source by a replay of
+/// the commands in the file it names, or of the serial console if it is "-".
+///
+/// Commands moving the motors are refused from CoAP without OSCORE and MQTT without TLS, unless
+/// the key "unprotected" (u32) is 1.
+pub fn create(device_id: u32) -> (Box<dyn CommandSource>, Box<dyn TelemetrySink>) {
+    let nvs = Nvs::open("uplink").ok();
+    let load = |key: &str| {
+        nvs.as_ref()
+            .and_then(|nvs| nvs.get_str(key).ok().flatten())
+            .filter(|value| !value.is_empty())
+    };
+
+    let mut uplink = None;
+    let mut tls = false;
+    if let Some(broker) = load("mqtt") {
+        let settings = MqttSettings {
+            broker,
+            username: load("mqtt_user"),
+            password: load("mqtt_pass"),
+            ca_certificate: load("mqtt_ca"),
+        };
+        match MqttUplink::new(&settings, device_id) {
+            Ok(mqtt) => {
+                log::info!("create(): Using MQTT broker {}", settings.broker);
+                tls = settings.broker.starts_with("mqtts://");
+                uplink = Some(transport::split(mqtt));
+            }
+            Err(e) => log::error!(
+                "create(): Failed to create MQTT client, using CoAP: {:?}",
+                e
+            ),
+        }
+    }
+    let (mut commands, telemetry) =
+        uplink.unwrap_or_else(|| transport::split(CoapUplink::new(device_id)));
+
+    // Anyone on the network could move the platform otherwise
+    let allow_unprotected = nvs
+        .as_ref()
+        .and_then(|nvs| nvs.get_u32("unprotected").ok().flatten())
+        == Some(1);
+    if !(telemetry.is_protected() || tls) {
+        if allow_unprotected {
+            log::warn!("create(): Accepting all commands from the unprotected transport");
+        } else {
+            log::warn!(
+                "create(): Refusing commands moving the motors from the unprotected transport"
+            );
+            commands = Box::new(UnprotectedGuard::new(commands));
+        }
+    }
+
+    let replay: Box<dyn CommandSource> = match load("replay").as_deref() {
+        None => return (commands, telemetry),
+        Some("-") => Box::new(ReplaySource::serial()),
+        Some(path) => match ReplaySource::open(path) {
+            Ok(replay) => Box::new(replay),
+            Err(e) => {
+                log::error!("create(): Failed to open replay {}: {}", path, e);
+                return (commands, telemetry);
+            }
+        },
+    };
+    log::info!("create(): Replaying commands instead of receiving them");
+    (replay, telemetry)
+}
+
+/// Whether the edge pushes commands by an observation of /command, or they have to be polled
+#[derive(Clone, Copy, Debug, Default)]
+struct CommandObservation {
+    unsupported: bool,
+    reported_angle_offset: MotorAngles,
+}
+
+/// Exchanges commands and telemetry with the edge over CoAP
+pub struct CoapUplink {
+    conn: Connection,
+    edge: EdgeLocator,
+    device_id: u32,
+    observation: CommandObservation,
+}
+
+impl CoapUplink {
+    pub fn new(device_id: u32) -> CoapUplink {
+        let mut conn = Connection::new(|| unsafe { esp_idf_sys::esp_random() });
+        // The default NVS partition was initialised by the wifi setup
+        if let Some(security_context) = super::oscore::load_security_context() {
+            conn.set_security_context(security_context);
+        } else {
+            log::warn!("CoapUplink: CoAP traffic to the edge is unprotected");
+        }
+
+        CoapUplink {
+            conn,
+            edge: EdgeLocator::new(),
+            device_id,
+            observation: CommandObservation::default(),
+        }
+    }
+}
+
+impl CommandSource for CoapUplink {
+    fn request_command(&mut self, target_angle_offset: &MotorAngles) -> Option<Command> {
+        // Registration is up to date, the edge pushes command changes
+        if self.conn.is_observing()
+            && self.observation.reported_angle_offset == *target_angle_offset
+            && self.conn.observation_age() < Some(OBSERVE_REREGISTER_INTERVAL)
+        {
+            return self.receive_pushed_command(Duration::from_millis(10));
+        }
+
+        let mut payload = vec![0; 12];
+        payload[0..4].copy_from_slice(&self.device_id.to_le_bytes());
+        payload[4..8].copy_from_slice(&target_angle_offset.motor_hor.to_le_bytes());
+        payload[8..12].copy_from_slice(&target_angle_offset.motor_ver.to_le_bytes());
+
+        // (Re-)register as observer, the offsets are reported with each registration
+        let addr = self.edge.addr();
+        let response = if self.observation.unsupported {
+            self.conn
+                .request(RequestType::Get, addr, "/command", payload)
+        } else {
+            self.conn.observe(addr, "/command", payload)
+        };
+
+        match response {
+            Ok(response) => {
+                if !self.observation.unsupported && !self.conn.is_observing() {
+                    log::info!(
+                        "request_command(): Edge does not support observe, polling commands"
+                    );
+                    self.observation.unsupported = true;
+                }
+                self.observation.reported_angle_offset = *target_angle_offset;
+
+                let res = parse_command(&response.message.payload);
+                log::info!("request_command(): Got command: {:?}", res);
+
+                res
+            }
+            Err(e) => {
+                log::warn!("request_command(): {:?}", e);
+                None
+            }
+        }
+    }
+
+    fn receive_pushed_command(&mut self, timeout: Duration) -> Option<Command> {
+        if !self.conn.is_observing() {
+            std::thread::sleep(timeout);
+            return None;
+        }
+
+        match self.conn.poll_notification(timeout) {
+            Ok(Some(notification)) => {
+                let res = parse_command(&notification.message.payload);
+                log::info!("receive_pushed_command(): Got command: {:?}", res);
+
+                res
+            }
+            Ok(None) => None,
+            Err(e) => {
+                log::warn!("receive_pushed_command(): {:?}", e);
+                None
+            }
+        }
+    }
+
+    fn fetch_schedule(&mut self) -> Option<Schedule> {
+        // Tables of a day exceed a single datagram and are transferred block-wise
+        match self
+            .conn
+            .request(RequestType::Get, self.edge.addr(), "/schedule", Vec::new())
+        {
+            Ok(response) => {
+                let schedule = Schedule::parse(&response.message.payload);
+                match &schedule {
+                    Some(schedule) => log::info!(
+                        "fetch_schedule(): Got schedule {} with {} bytes",
+                        schedule.id(),
+                        response.message.payload.len()
+                    ),
+                    None => log::warn!("fetch_schedule(): Invalid schedule"),
+                }
+                schedule
+            }
+            Err(e) => {
+                log::warn!("fetch_schedule(): {:?}", e);
+                None
+            }
+        }
+    }
+}
+
+impl TelemetrySink for CoapUplink {
+    fn send_sensor_data(&mut self, datapoints: &[DataPoint], dropped: u32) -> bool {
+        // Payloads exceeding a single datagram are split by the connection into blocks
+        let payload = telemetry::serialize_sensor_data(datapoints, dropped, self.device_id);
+
+        match self
+            .conn
+            .request(RequestType::Post, self.edge.addr(), "/sensor/data", payload)
+        {
+            Ok(_) => {
+                log::info!(
+                    "send_sensor_data(): Sent {} datapoints, {} dropped",
+                    datapoints.len(),
+                    dropped
+                );
+                true
+            }
+            Err(e) => {
+                log::warn!("send_sensor_data(): {:?}", e);
+                false
+            }
+        }
+    }
+
+    fn send_device_info(&mut self, device_info: &DeviceInfo) -> bool {
+        match self.conn.request(
+            RequestType::Post,
+            self.edge.addr(),
+            "/device/info",
+            device_info.serialize(),
+        ) {
+            Ok(_) => {
+                log::info!("send_device_info(): Sent {:?}", device_info);
+                true
+            }
+            Err(e) => {
+                log::warn!("send_device_info(): {:?}", e);
+                false
+            }
+        }
+    }
+
+    fn send_status(&mut self, status: &DeviceStatus) -> bool {
+        match self.conn.request(
+            RequestType::Post,
+            self.edge.addr(),
+            "/device/status",
+            status.serialize(),
+        ) {
+            Ok(_) => {
+                log::info!("send_status(): Sent {:?}", status);
+                true
+            }
+            Err(e) => {
+                log::warn!("send_status(): {:?}", e);
+                false
+            }
+        }
+    }
+
+    fn report_success(&mut self) {
+        self.edge.report_success();
+    }
+
+    fn report_failure(&mut self) -> bool {
+        if !self.edge.report_failure() {
+            return false;
+        }
+        // The observation belongs to the previous edge
+        self.conn.cancel_observation();
+        self.observation = CommandObservation::default();
+        true
+    }
+
+    fn poll_update(&mut self, updater: &mut Updater) {
+        updater.poll(&mut self.conn, self.edge.addr());
+    }
+
+    fn is_protected(&self) -> bool {
+        self.conn.is_protected()
+    }
+}
@@ -1,75 +1,386 @@
 use embedded_svc::{
     ipv4,
-    ping::Ping,
+    ping::{self, Ping},
     wifi::{
-        ApStatus, ClientConfiguration, ClientConnectionStatus, ClientIpStatus, ClientStatus,
-        Configuration, Status, Wifi,
+        AccessPointConfiguration, AuthMethod, ClientConfiguration, ClientConnectionStatus,
+        ClientIpStatus, ClientStatus, Configuration, Status, Wifi,
     },
 };
-use esp_idf_sys::EspError;
+use esp_idf_sys::{esp, EspError};
 use log::info;
 
 use esp_idf_svc::netif::EspNetifStack;
 use esp_idf_svc::nvs::EspDefaultNvs;
-use esp_idf_svc::ping;
+use esp_idf_svc::ping::EspPing;
 use esp_idf_svc::sysloop::EspSysLoopStack;
 use esp_idf_svc::wifi::EspWifi;
 
+use std::net::Ipv4Addr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-const SSID: &str = env!("esp_wifi_ssid");
-const PASS: &str = env!("esp_wifi_pass");
+use crate::storage::nvs::Nvs;
 
-pub fn wifi(
-    netif_stack: Arc<EspNetifStack>,
-    sys_loop_stack: Arc<EspSysLoopStack>,
-    default_nvs: Arc<EspDefaultNvs>,
-) -> Result<Box<EspWifi>, EspError> {
-    let mut wifi = Box::new(EspWifi::new(netif_stack, sys_loop_stack, default_nvs)?);
+// Optional network compiled into the firmware, otherwise the network is set by provisioning
+const SSID: Option<&str> = option_env!("esp_wifi_ssid");
+const PASS: Option<&str> = option_env!("esp_wifi_pass");
 
-    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-        ssid: SSID.into(),
-        password: PASS.into(),
-        channel: None,
-        ..Default::default()
-    }))?;
+// Additional networks are stored as "ssid0"/"pass0", "ssid1"/"pass1", ... in the NVS namespace "wifi"
+const MAX_STORED_NETWORKS: usize = 4;
+
+// Time to associate with an access point and obtain an IP per network
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+
+const MIN_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+// Pinging the gateway detects links that are dead without a disconnect event
+const LINK_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+// A single ping blocks the main loop for this long at most
+const PING_TIMEOUT: Duration = Duration::from_millis(500);
+// Lost pings in a row, one per call of `maintain`, until the link counts as dead
+const MAX_LOST_PINGS: u32 = 3;
 
-    info!("Wifi configuration set");
-    Ok(wifi)
+#[derive(Clone, Debug)]
+pub struct Network {
+    pub ssid: String,
+    pub password: String,
 }
 
-pub fn ping(ip_settings: &ipv4::ClientSettings) -> Result<(), EspError> {
-    info!("About to do some pings for {:?}", ip_settings);
-
-    let ping_summary =
-        ping::EspPing::default().ping(ip_settings.subnet.gateway, &Default::default())?;
-    if ping_summary.transmitted != ping_summary.received {
-        todo!(
-            // TODO
-            "Pinging gateway {} resulted in timeouts",
-            ip_settings.subnet.gateway
-        )
+/// Network `maintain` is connecting to in the background
+struct Attempt {
+    index: usize,
+    deadline: Instant,
+}
+
+/// Keeps the station connected to the first reachable network of an ordered list
+pub struct WifiManager {
+    wifi: Box<EspWifi>,
+    networks: Vec<Network>,
+    backoff: Duration,
+    next_attempt: Instant,
+    attempt: Option<Attempt>,
+    last_link_check: Instant,
+    lost_pings: u32,
+}
+
+impl WifiManager {
+    pub fn new(
+        netif_stack: Arc<EspNetifStack>,
+        sys_loop_stack: Arc<EspSysLoopStack>,
+        default_nvs: Arc<EspDefaultNvs>,
+    ) -> Result<WifiManager, EspError> {
+        let wifi = Box::new(EspWifi::new(netif_stack, sys_loop_stack, default_nvs)?);
+
+        // EspDefaultNvs initialised the NVS partition
+        let networks = load_networks();
+        info!(
+            "WifiManager: Configured networks: {:?}",
+            networks.iter().map(|n| &n.ssid).collect::<Vec<_>>()
+        );
+
+        Ok(WifiManager {
+            wifi,
+            networks,
+            backoff: MIN_BACKOFF,
+            next_attempt: Instant::now(),
+            attempt: None,
+            last_link_check: Instant::now(),
+            lost_pings: 0,
+        })
     }
 
-    info!("Pinging done");
+    /// Tries the networks in order until one provides an IP, returns whether it succeeded
+    ///
+    /// Blocks for up to `CONNECT_TIMEOUT` per network, the main loop reconnects with `maintain`.
+    pub fn connect(&mut self) -> bool {
+        // Prefer networks seen by a scan, but still try hidden ones afterwards
+        let visible = match self.wifi.scan() {
+            Ok(access_points) => access_points
+                .into_iter()
+                .map(|ap| ap.ssid.to_string())
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                log::warn!("WifiManager: Scan failed: {:?}", e);
+                Vec::new()
+            }
+        };
+        let mut networks = self.networks.clone();
+        networks.sort_by_key(|network| !visible.iter().any(|ssid| *ssid == network.ssid));
 
-    Ok(())
-}
+        for network in &networks {
+            info!("WifiManager: Connecting to {}", network.ssid);
+            if let Err(e) =
+                self.wifi
+                    .set_configuration(&Configuration::Client(ClientConfiguration {
+                        ssid: network.ssid.as_str().into(),
+                        password: network.password.as_str().into(),
+                        channel: None,
+                        ..Default::default()
+                    }))
+            {
+                log::warn!("WifiManager: Failed to set configuration: {:?}", e);
+                continue;
+            }
+
+            if let Some(ip_settings) = self.wait_for_ip(CONNECT_TIMEOUT) {
+                info!(
+                    "WifiManager: Connected to {} with {:?}, RSSI {:?} dBm",
+                    network.ssid,
+                    ip_settings.ip,
+                    self.rssi()
+                );
+                self.backoff = MIN_BACKOFF;
+                self.last_link_check = Instant::now();
+                return true;
+            }
+            log::warn!("WifiManager: No IP from {}", network.ssid);
+        }
 
-pub fn check_status(wifi: Box<EspWifi>) -> Result<(), EspError> {
-    let status = wifi.get_status();
+        false
+    }
+
+    /// Reconnects with exponential backoff if the link is down, returns whether the link is up
+    ///
+    /// Called regularly by the main loop, network operations should be skipped while this is false.
+    /// The networks are tried one after another in the background without blocking the loop.
+    pub fn maintain(&mut self) -> bool {
+        if let Some(ip_settings) = self.ip_settings() {
+            if let Some(attempt) = self.attempt.take() {
+                info!(
+                    "WifiManager: Connected to {} with {:?}, RSSI {:?} dBm",
+                    self.networks[attempt.index].ssid,
+                    ip_settings.ip,
+                    self.rssi()
+                );
+                self.backoff = MIN_BACKOFF;
+                self.last_link_check = Instant::now();
+                return true;
+            }
+            if self.lost_pings == 0 && self.last_link_check.elapsed() < LINK_CHECK_INTERVAL {
+                return true;
+            }
+            self.last_link_check = Instant::now();
+            match ping(&ip_settings) {
+                Ok(true) => {
+                    info!("WifiManager: Link up, RSSI {:?} dBm", self.rssi());
+                    self.lost_pings = 0;
+                    return true;
+                }
+                Ok(false) => self.lost_pings += 1,
+                Err(e) => {
+                    log::warn!("WifiManager: Ping failed: {:?}", e);
+                    self.lost_pings += 1;
+                }
+            }
+            // Checked again on the next call
+            if self.lost_pings < MAX_LOST_PINGS {
+                return true;
+            }
+            log::warn!("WifiManager: Gateway unreachable, reconnecting");
+            self.lost_pings = 0;
+        }
+
+        let next_index = match &self.attempt {
+            Some(attempt) if Instant::now() < attempt.deadline => return false,
+            Some(attempt) => {
+                log::warn!(
+                    "WifiManager: No IP from {}",
+                    self.networks[attempt.index].ssid
+                );
+                attempt.index + 1
+            }
+            None if Instant::now() < self.next_attempt || self.networks.is_empty() => return false,
+            None => 0,
+        };
+
+        if next_index < self.networks.len() {
+            self.start_attempt(next_index);
+            return false;
+        }
+
+        log::warn!(
+            "WifiManager: All networks failed, retrying in {:?}",
+            self.backoff
+        );
+        self.attempt = None;
+        self.next_attempt = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        false
+    }
+
+    /// Starts connecting to the network without waiting for the IP
+    fn start_attempt(&mut self, index: usize) {
+        let network = &self.networks[index];
+        info!("WifiManager: Connecting to {}", network.ssid);
+        let configuration = Configuration::Client(ClientConfiguration {
+            ssid: network.ssid.as_str().into(),
+            password: network.password.as_str().into(),
+            channel: None,
+            ..Default::default()
+        });
+        let deadline = match self.wifi.set_configuration(&configuration) {
+            Ok(()) => Instant::now() + CONNECT_TIMEOUT,
+            Err(e) => {
+                log::warn!("WifiManager: Failed to set configuration: {:?}", e);
+                // Moves on to the next network on the next call
+                Instant::now()
+            }
+        };
+        self.attempt = Some(Attempt { index, deadline });
+    }
+
+    /// Lets the modem sleep between beacons for the longest listen interval, at the cost of latency
+    ///
+    /// Otherwise the modem sleeps between DTIM beacons, which automatic light sleep relies on.
+    pub fn set_power_save(&mut self, enabled: bool) -> Result<(), EspError> {
+        let mode = if enabled {
+            esp_idf_sys::wifi_ps_type_t_WIFI_PS_MAX_MODEM
+        } else {
+            // Default of esp-idf
+            esp_idf_sys::wifi_ps_type_t_WIFI_PS_MIN_MODEM
+        };
+        esp!(unsafe { esp_idf_sys::esp_wifi_set_ps(mode) })?;
+        info!(
+            "WifiManager: Power save {}",
+            if enabled { "on" } else { "off" }
+        );
+        Ok(())
+    }
+
+    pub fn has_networks(&self) -> bool {
+        !self.networks.is_empty()
+    }
 
-    if let Status(
-        ClientStatus::Started(ClientConnectionStatus::Connected(ClientIpStatus::Done(ip_settings))),
-        ApStatus::Stopped,
-    ) = status
-    {
-        info!("Wifi connected");
+    /// Stops the station and opens a WPA2 access point with `ip` as gateway and DNS server
+    pub fn start_access_point(
+        &mut self,
+        ssid: &str,
+        key: &str,
+        ip: Ipv4Addr,
+    ) -> Result<(), EspError> {
+        self.wifi
+            .set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+                ssid: ssid.into(),
+                channel: 1,
+                auth_method: AuthMethod::WPA2Personal,
+                password: key.into(),
+                ip_conf: Some(ipv4::RouterConfiguration {
+                    subnet: ipv4::Subnet {
+                        gateway: ip,
+                        mask: ipv4::Mask(24),
+                    },
+                    dhcp_enabled: true,
+                    dns: Some(ip),
+                    secondary_dns: None,
+                }),
+                ..Default::default()
+            }))?;
 
-        ping(&ip_settings)?;
+        info!("WifiManager: Access point {} started", ssid);
         Ok(())
-    } else {
-        const ESP_ERR_INVALID_STATE: esp_idf_sys::esp_err_t = 0x103;
-        Err(EspError::from(ESP_ERR_INVALID_STATE).unwrap())
     }
+
+    pub fn ip_settings(&self) -> Option<ipv4::ClientSettings> {
+        match self.wifi.get_status() {
+            Status(
+                ClientStatus::Started(ClientConnectionStatus::Connected(ClientIpStatus::Done(
+                    ip_settings,
+                ))),
+                _,
+            ) => Some(ip_settings),
+            _ => None,
+        }
+    }
+
+    /// Signal strength of the current access point in dBm
+    pub fn rssi(&self) -> Option<i8> {
+        let mut ap_info: esp_idf_sys::wifi_ap_record_t = unsafe { std::mem::zeroed() };
+        esp!(unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut ap_info) })
+            .ok()
+            .map(|_| ap_info.rssi)
+    }
+
+    fn wait_for_ip(&self, timeout: Duration) -> Option<ipv4::ClientSettings> {
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            if let Some(ip_settings) = self.ip_settings() {
+                return Some(ip_settings);
+            }
+            std::thread::sleep(Duration::from_millis(250));
+        }
+        None
+    }
+}
+
+/// Networks stored in NVS followed by the network compiled into the firmware
+fn load_networks() -> Vec<Network> {
+    let mut networks = match Nvs::open("wifi") {
+        Ok(nvs) => load_stored_networks(&nvs),
+        Err(e) => {
+            log::warn!("load_networks(): Failed to open NVS: {:?}", e);
+            Vec::new()
+        }
+    };
+
+    if let Some(ssid) = SSID.filter(|ssid| !ssid.is_empty()) {
+        if !networks.iter().any(|network| network.ssid == ssid) {
+            networks.push(Network {
+                ssid: ssid.into(),
+                password: PASS.unwrap_or_default().into(),
+            });
+        }
+    }
+
+    networks
+}
+
+fn load_stored_networks(nvs: &Nvs) -> Vec<Network> {
+    let mut networks = Vec::new();
+    for i in 0..MAX_STORED_NETWORKS {
+        let ssid = nvs.get_str(&format!("ssid{}", i)).ok().flatten();
+        let password = nvs.get_str(&format!("pass{}", i)).ok().flatten();
+        if let Some(ssid) = ssid {
+            networks.push(Network {
+                ssid,
+                password: password.unwrap_or_default(),
+            });
+        }
+    }
+    networks
+}
+
+/// Stores the network in NVS with the highest priority, pushing back the other stored networks
+pub fn store_network(network: &Network) -> Result<(), EspError> {
+    let mut nvs = Nvs::open("wifi")?;
+
+    let mut networks = load_stored_networks(&nvs);
+    networks.retain(|stored| stored.ssid != network.ssid);
+    networks.insert(0, network.clone());
+    networks.truncate(MAX_STORED_NETWORKS);
+
+    for (i, network) in networks.iter().enumerate() {
+        nvs.set_str(&format!("ssid{}", i), &network.ssid)?;
+        nvs.set_str(&format!("pass{}", i), &network.password)?;
+    }
+    Ok(())
+}
+
+/// Pings the gateway once, returns false if the ping was lost
+pub fn ping(ip_settings: &ipv4::ClientSettings) -> Result<bool, EspError> {
+    let configuration = ping::Configuration {
+        count: 1,
+        timeout: PING_TIMEOUT,
+        ..Default::default()
+    };
+    let ping_summary = EspPing::default().ping(ip_settings.subnet.gateway, &configuration)?;
+    if ping_summary.received == 0 {
+        log::warn!(
+            "ping(): No reply from gateway {} within {:?}",
+            ip_settings.subnet.gateway,
+            PING_TIMEOUT
+        );
+    }
+
+    Ok(ping_summary.received > 0)
 }
@@ -0,0 +1,55 @@
+use std::convert::{TryFrom, TryInto};
+
+use ed25519_dalek::{PublicKey, Signature, Verifier};
+
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Description of the firmware image offered by the edge
+///
+/// Serialized as version length (u8), version, image size (u32), SHA-256 of the image and an Ed25519
+/// signature of all preceding bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Manifest {
+    pub version: String,
+    pub size: u32,
+    pub sha256: [u8; 32],
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ManifestError {
+    Truncated,
+    InvalidVersion,
+    InvalidSignature,
+}
+
+impl Manifest {
+    /// Parses the manifest after checking its signature
+    pub fn verify(payload: &[u8], public_key: &PublicKey) -> Result<Manifest, ManifestError> {
+        if payload.len() < 1 + SIGNATURE_LEN {
+            return Err(ManifestError::Truncated);
+        }
+        let (signed, signature) = payload.split_at(payload.len() - SIGNATURE_LEN);
+
+        let signature =
+            Signature::try_from(signature).map_err(|_| ManifestError::InvalidSignature)?;
+        public_key
+            .verify(signed, &signature)
+            .map_err(|_| ManifestError::InvalidSignature)?;
+
+        let version_len = signed[0] as usize;
+        // version length + version + size + sha256
+        if signed.len() != 1 + version_len + 4 + 32 {
+            return Err(ManifestError::Truncated);
+        }
+        let version = std::str::from_utf8(&signed[1..1 + version_len])
+            .map_err(|_| ManifestError::InvalidVersion)?
+            .to_string();
+        let rest = &signed[1 + version_len..];
+
+        Ok(Manifest {
+            version,
+            size: u32::from_le_bytes(rest[0..4].try_into().unwrap()),
+            sha256: rest[4..36].try_into().unwrap(),
+        })
+    }
+}
@@ -0,0 +1,281 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use coap_lite::RequestType;
+use ed25519_dalek::PublicKey;
+use esp_idf_sys::{esp, EspError};
+use sha2::{Digest, Sha256};
+
+use self::manifest::{Manifest, ManifestError};
+use self::version::Version;
+use crate::device::FIRMWARE_VERSION;
+use crate::networking::coap::{CoapError, Connection};
+use crate::storage::nvs::Nvs;
+
+pub mod manifest;
+pub mod version;
+
+// Hex encoded Ed25519 key verifying the manifests, updates are disabled without it
+const PUBLIC_KEY: Option<&str> = option_env!("esp_ota_public_key");
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// A new firmware that does not reach the edge within this time is rolled back
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug)]
+pub enum OtaError {
+    Coap(CoapError),
+    Esp(EspError),
+    Manifest(ManifestError),
+    SizeMismatch,
+    HashMismatch,
+}
+
+/// Pulls firmware updates from the edge and supervises the first boot of a new firmware
+///
+/// The NVS namespace "ota" keeps the version written to the inactive partition ("pending"), and the
+/// last version that was rolled back ("rejected") to not download it again.
+pub struct Updater {
+    public_key: Option<PublicKey>,
+    nvs: Option<Nvs>,
+    rejected: Option<String>,
+    pending_verify: bool,
+    boot: Instant,
+    last_check: Option<Instant>,
+}
+
+impl Updater {
+    pub fn new() -> Updater {
+        let public_key = PUBLIC_KEY.and_then(|key| {
+            let key = decode_hex(key).and_then(|key| PublicKey::from_bytes(&key).ok());
+            if key.is_none() {
+                log::error!("Updater: Invalid public key, updates disabled");
+            }
+            key
+        });
+        if PUBLIC_KEY.is_none() {
+            log::warn!("Updater: No public key compiled in, updates disabled");
+        }
+
+        let mut nvs = match Nvs::open("ota") {
+            Ok(nvs) => Some(nvs),
+            Err(e) => {
+                log::warn!("Updater: Failed to open NVS: {:?}", e);
+                None
+            }
+        };
+
+        let load = |nvs: &Option<Nvs>, key: &str| {
+            nvs.as_ref()
+                .and_then(|nvs| nvs.get_str(key).ok().flatten())
+                .filter(|version| !version.is_empty())
+        };
+        let mut rejected = load(&nvs, "rejected");
+        match load(&nvs, "pending") {
+            // The bootloader went back to the previous partition
+            Some(pending) if pending != FIRMWARE_VERSION => {
+                log::warn!("Updater: Firmware {} was rolled back", pending);
+                if let Some(nvs) = &mut nvs {
+                    let res = nvs
+                        .set_str("rejected", &pending)
+                        .and_then(|_| nvs.erase_key("pending"));
+                    if let Err(e) = res {
+                        log::warn!("Updater: Failed to update NVS: {:?}", e);
+                    }
+                }
+                rejected = Some(pending);
+            }
+            _ => (),
+        }
+
+        let pending_verify =
+            running_state() == Some(esp_idf_sys::esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY);
+        if pending_verify {
+            log::info!(
+                "Updater: Firmware {} boots the first time, waiting for the edge",
+                FIRMWARE_VERSION
+            );
+        }
+
+        Updater {
+            public_key,
+            nvs,
+            rejected,
+            pending_verify,
+            boot: Instant::now(),
+            last_check: None,
+        }
+    }
+
+    /// Marks a new firmware valid, called once the edge was reached
+    pub fn confirm(&mut self) {
+        if !self.pending_verify {
+            return;
+        }
+        match esp!(unsafe { esp_idf_sys::esp_ota_mark_app_valid_cancel_rollback() }) {
+            Ok(()) => {
+                log::info!("Updater: Firmware {} marked valid", FIRMWARE_VERSION);
+                self.pending_verify = false;
+                if let Some(nvs) = &mut self.nvs {
+                    if let Err(e) = nvs.erase_key("pending") {
+                        log::warn!("Updater: Failed to update NVS: {:?}", e);
+                    }
+                }
+            }
+            Err(e) => log::error!("Updater: Failed to mark firmware valid: {:?}", e),
+        }
+    }
+
+    /// Rolls back to the previous firmware if the new one did not reach the edge in time
+    pub fn check_rollback(&self) {
+        if self.pending_verify && self.boot.elapsed() > VERIFY_TIMEOUT {
+            log::error!(
+                "Updater: Firmware {} did not reach the edge, rolling back",
+                FIRMWARE_VERSION
+            );
+            // Only returns if there is no previous firmware
+            let err = unsafe { esp_idf_sys::esp_ota_mark_app_invalid_rollback_and_reboot() };
+            log::error!("Updater: Rollback failed: {}", err);
+        }
+    }
+
+    /// Checks the manifest on the edge from time to time and installs a newer firmware
+    ///
+    /// Only versions greater than the running one are installed, so an old signed manifest can not
+    /// downgrade the device. Reboots into the new firmware on success.
+    pub fn poll(&mut self, conn: &mut Connection, addr: SocketAddr) {
+        let public_key = match &self.public_key {
+            Some(public_key) => *public_key,
+            None => return,
+        };
+        // An unconfirmed firmware must not be replaced, it could not roll back anymore
+        if self.pending_verify
+            || self
+                .last_check
+                .map_or(false, |last_check| last_check.elapsed() < CHECK_INTERVAL)
+        {
+            return;
+        }
+        self.last_check = Some(Instant::now());
+
+        let manifest = match fetch_manifest(conn, addr, &public_key) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                log::warn!("Updater: Failed to fetch manifest: {:?}", e);
+                return;
+            }
+        };
+        let offered = match Version::parse(&manifest.version) {
+            Some(offered) => offered,
+            None => {
+                log::warn!("Updater: Invalid version {} offered", manifest.version);
+                return;
+            }
+        };
+        if Version::parse(FIRMWARE_VERSION).map_or(true, |running| offered <= running)
+            || self.rejected.as_deref() == Some(manifest.version.as_str())
+        {
+            log::debug!("Updater: No update, edge offers {}", manifest.version);
+            return;
+        }
+
+        log::info!(
+            "Updater: Updating from {} to {} ({} bytes)",
+            FIRMWARE_VERSION,
+            manifest.version,
+            manifest.size
+        );
+        if let Err(e) = install(conn, addr, &manifest) {
+            log::error!("Updater: Update to {} failed: {:?}", manifest.version, e);
+            return;
+        }
+
+        if let Some(nvs) = &mut self.nvs {
+            if let Err(e) = nvs.set_str("pending", &manifest.version) {
+                log::warn!("Updater: Failed to store pending version: {:?}", e);
+            }
+        }
+        log::info!("Updater: Rebooting into {}", manifest.version);
+        unsafe { esp_idf_sys::esp_restart() }
+    }
+}
+
+fn fetch_manifest(
+    conn: &mut Connection,
+    addr: SocketAddr,
+    public_key: &PublicKey,
+) -> Result<Manifest, OtaError> {
+    let response = conn
+        .request(RequestType::Get, addr, "/firmware/manifest", vec![])
+        .map_err(OtaError::Coap)?;
+    Manifest::verify(&response.message.payload, public_key).map_err(OtaError::Manifest)
+}
+
+/// Writes the image into the inactive OTA partition and makes it the boot partition
+fn install(conn: &mut Connection, addr: SocketAddr, manifest: &Manifest) -> Result<(), OtaError> {
+    let partition = unsafe { esp_idf_sys::esp_ota_get_next_update_partition(std::ptr::null()) };
+    if partition.is_null() {
+        return Err(OtaError::Esp(
+            EspError::from(esp_idf_sys::ESP_ERR_NOT_FOUND as esp_idf_sys::esp_err_t).unwrap(),
+        ));
+    }
+
+    let mut handle: esp_idf_sys::esp_ota_handle_t = 0;
+    esp!(unsafe { esp_idf_sys::esp_ota_begin(partition, manifest.size as usize, &mut handle) })
+        .map_err(OtaError::Esp)?;
+
+    let mut hasher = Sha256::new();
+    let mut written = 0;
+    let mut write_error = None;
+    let res = conn.download(addr, "/firmware/image", |block| {
+        written += block.len();
+        if written > manifest.size as usize {
+            return false;
+        }
+        hasher.update(block);
+        match esp!(unsafe {
+            esp_idf_sys::esp_ota_write(handle, block.as_ptr() as *const _, block.len() as _)
+        }) {
+            Ok(()) => true,
+            Err(e) => {
+                write_error = Some(e);
+                false
+            }
+        }
+    });
+
+    let verified = match (res, write_error) {
+        (_, Some(e)) => Err(OtaError::Esp(e)),
+        (Err(e), None) if written <= manifest.size as usize => Err(OtaError::Coap(e)),
+        _ if written != manifest.size as usize => Err(OtaError::SizeMismatch),
+        _ if hasher.finalize().as_slice() != manifest.sha256 => Err(OtaError::HashMismatch),
+        _ => Ok(()),
+    };
+    if let Err(e) = verified {
+        unsafe { esp_idf_sys::esp_ota_abort(handle) };
+        return Err(e);
+    }
+
+    // Validates the image header and checksum
+    esp!(unsafe { esp_idf_sys::esp_ota_end(handle) }).map_err(OtaError::Esp)?;
+    esp!(unsafe { esp_idf_sys::esp_ota_set_boot_partition(partition) }).map_err(OtaError::Esp)
+}
+
+fn running_state() -> Option<esp_idf_sys::esp_ota_img_states_t> {
+    let partition = unsafe { esp_idf_sys::esp_ota_get_running_partition() };
+    let mut state = 0;
+    esp!(unsafe { esp_idf_sys::esp_ota_get_state_partition(partition, &mut state) })
+        .ok()
+        .map(|_| state)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
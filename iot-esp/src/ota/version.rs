@@ -0,0 +1,167 @@
+use std::cmp::Ordering;
+
+/// Semantic version as used by Cargo, build metadata is ignored
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Version {
+    pub major: u64,
+    pub minor: u64,
+    pub patch: u64,
+    pub pre: Vec<String>,
+}
+
+impl Version {
+    /// Parses `MAJOR.MINOR.PATCH[-PRE][+BUILD]`
+    pub fn parse(version: &str) -> Option<Version> {
+        let version = version.split('+').next()?;
+        let (core, pre) = match version.split_once('-') {
+            Some((core, pre)) => (core, Some(pre)),
+            None => (version, None),
+        };
+
+        let mut numbers = core.split('.').map(parse_number);
+        let (major, minor, patch) = (numbers.next()??, numbers.next()??, numbers.next()??);
+        if numbers.next().is_some() {
+            return None;
+        }
+
+        let pre = match pre {
+            Some(pre) => pre
+                .split('.')
+                .map(|identifier| {
+                    let valid = !identifier.is_empty()
+                        && identifier
+                            .bytes()
+                            .all(|b| b.is_ascii_alphanumeric() || b == b'-');
+                    if valid {
+                        Some(identifier.to_string())
+                    } else {
+                        None
+                    }
+                })
+                .collect::<Option<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+
+        Some(Version {
+            major,
+            minor,
+            patch,
+            pre,
+        })
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Version) -> Ordering {
+        let core =
+            (self.major, self.minor, self.patch).cmp(&(other.major, other.minor, other.patch));
+        if core != Ordering::Equal {
+            return core;
+        }
+
+        // A pre-release precedes its release
+        match (self.pre.is_empty(), other.pre.is_empty()) {
+            (true, true) => return Ordering::Equal,
+            (true, false) => return Ordering::Greater,
+            (false, true) => return Ordering::Less,
+            (false, false) => (),
+        }
+        for (a, b) in self.pre.iter().zip(&other.pre) {
+            // Numeric identifiers compare numerically and precede alphanumeric ones
+            let ordering = match (parse_number(a), parse_number(b)) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => a.cmp(b),
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        self.pre.len().cmp(&other.pre.len())
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Version) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Numbers without sign and leading zeros
+fn parse_number(number: &str) -> Option<u64> {
+    if number.is_empty()
+        || !number.bytes().all(|b| b.is_ascii_digit())
+        || (number.len() > 1 && number.starts_with('0'))
+    {
+        return None;
+    }
+    number.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(version: &str) -> Version {
+        Version::parse(version).unwrap()
+    }
+
+    #[test]
+    fn parses_core_and_pre_release() {
+        assert_eq!(
+            version("1.20.3-rc.1+build.5"),
+            Version {
+                major: 1,
+                minor: 20,
+                patch: 3,
+                pre: vec!["rc".to_string(), "1".to_string()],
+            }
+        );
+        assert_eq!(version("0.2.0").pre, Vec::<String>::new());
+    }
+
+    #[test]
+    fn rejects_invalid_versions() {
+        for invalid in &[
+            "",
+            "1",
+            "1.2",
+            "1.2.3.4",
+            "1.02.3",
+            "v1.2.3",
+            "1.2.3-",
+            "1.2.3-a..b",
+        ] {
+            assert_eq!(Version::parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn orders_by_precedence() {
+        // Ascending precedence from the semver specification
+        let ordered = [
+            "0.9.9",
+            "0.10.0",
+            "1.0.0-alpha",
+            "1.0.0-alpha.1",
+            "1.0.0-alpha.beta",
+            "1.0.0-beta",
+            "1.0.0-beta.2",
+            "1.0.0-beta.11",
+            "1.0.0-rc.1",
+            "1.0.0",
+            "1.0.1",
+            "2.0.0",
+        ];
+        for pair in ordered.windows(2) {
+            assert!(
+                version(pair[0]) < version(pair[1]),
+                "{} < {}",
+                pair[0],
+                pair[1]
+            );
+        }
+        assert_eq!(version("1.0.0+a").cmp(&version("1.0.0+b")), Ordering::Equal);
+    }
+}
@@ -0,0 +1,195 @@
+use std::io::Write;
+use std::time::Duration;
+
+use esp_idf_sys::{esp, EspError};
+use num_enum::TryFromPrimitive;
+
+use crate::command::Command;
+use crate::control::daycycle::DayCycle;
+use crate::control::lighttracking::MotorAngles;
+
+// Lowest CPU frequency of automatic light sleep, the APB clock of wifi requires at least 40 MHz
+const MIN_CPU_FREQUENCY_MHZ: i32 = 40;
+const MAX_CPU_FREQUENCY_MHZ: i32 = 240;
+
+// Shorter sleeps are not worth the wakeup latency of a manual light sleep
+const MIN_LIGHT_SLEEP: Duration = Duration::from_millis(500);
+
+/// The button is only read while awake, without a wakeup it has to be held for this long
+pub const BUTTON_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Power mode of the device since the previous datapoint
+#[derive(Clone, Copy, Debug, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum PowerMode {
+    /// CPU kept running between the control cycles
+    Active,
+    /// CPU and wifi modem slept between the control cycles
+    LightSleep,
+    /// Woken up from deep sleep, everything but the RTC was powered off
+    DeepSleep,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WakeReason {
+    Timer,
+    Button,
+}
+
+/// State of the control loop kept in RTC memory during deep sleep
+#[derive(Clone, Copy)]
+pub struct RtcState {
+    pub motor_angles: MotorAngles,
+    pub initial_platform_offset: MotorAngles,
+    pub world_angles_offset: MotorAngles,
+    pub command: Command,
+    pub day_cycle: DayCycle,
+}
+
+// Initialised on every boot except a wakeup from deep sleep
+#[link_section = ".rtc.data"]
+static mut RTC_STATE: Option<RtcState> = None;
+
+/// Sleeps between the control cycles with the lowest power mode that keeps the device responsive
+///
+/// While idle the CPU enters automatic light sleep and wifi stays in modem sleep, which keeps
+/// the connection. Without a connection to keep, the device sleeps manually until the timer
+/// expires or the button is pressed. Deep sleep powers down all but the RTC and reboots the
+/// device, the state of the control loop survives in RTC memory.
+pub struct PowerManager {
+    button_gpio: esp_idf_sys::gpio_num_t,
+    light_sleep: bool,
+    automatic_light_sleep: bool,
+    mode: PowerMode,
+}
+
+impl PowerManager {
+    /// `button_gpio` is an RTC GPIO pulled low by the button
+    pub fn new(button_gpio: esp_idf_sys::gpio_num_t, light_sleep: bool) -> PowerManager {
+        let automatic_light_sleep = light_sleep
+            && match configure_automatic_light_sleep() {
+                Ok(()) => true,
+                Err(e) => {
+                    log::warn!("PowerManager: Automatic light sleep unavailable: {:?}", e);
+                    false
+                }
+            };
+
+        let mode = match wakeup_cause() {
+            Some(reason) => {
+                log::info!("PowerManager: Woken up from deep sleep by {:?}", reason);
+                PowerMode::DeepSleep
+            }
+            None if automatic_light_sleep => PowerMode::LightSleep,
+            None => PowerMode::Active,
+        };
+
+        PowerManager {
+            button_gpio,
+            light_sleep,
+            automatic_light_sleep,
+            mode,
+        }
+    }
+
+    /// Power mode since the last call, reported with every datapoint
+    pub fn report_mode(&mut self) -> PowerMode {
+        let mode = self.mode;
+        self.mode = if self.automatic_light_sleep {
+            PowerMode::LightSleep
+        } else {
+            PowerMode::Active
+        };
+        mode
+    }
+
+    /// Sleeps without keeping the wifi connection, returns early if the button is pressed
+    ///
+    /// Without light sleep the CPU sleeps at most until the next button poll.
+    pub fn light_sleep(&mut self, duration: Duration) -> WakeReason {
+        if !self.light_sleep {
+            std::thread::sleep(duration.min(BUTTON_POLL_INTERVAL));
+            return WakeReason::Timer;
+        }
+        if duration < MIN_LIGHT_SLEEP {
+            std::thread::sleep(duration);
+            return WakeReason::Timer;
+        }
+
+        if let Err(e) = self.enable_wakeup(duration) {
+            log::warn!("PowerManager: Failed to configure wakeup: {:?}", e);
+            std::thread::sleep(duration);
+            return WakeReason::Timer;
+        }
+        // The UART is clock gated during sleep
+        std::io::stdout().flush().ok();
+
+        let result = esp!(unsafe { esp_idf_sys::esp_light_sleep_start() });
+        // The timer would end automatic light sleep early otherwise
+        unsafe {
+            esp_idf_sys::esp_sleep_disable_wakeup_source(
+                esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER,
+            );
+        }
+        if let Err(e) = result {
+            log::warn!("PowerManager: Light sleep failed: {:?}", e);
+            std::thread::sleep(duration);
+            return WakeReason::Timer;
+        }
+
+        self.mode = PowerMode::LightSleep;
+        wakeup_cause().unwrap_or(WakeReason::Timer)
+    }
+
+    /// Powers down until the timer expires or the button is pressed, the device reboots afterwards
+    pub fn deep_sleep(&mut self, duration: Duration, state: RtcState) -> ! {
+        unsafe {
+            RTC_STATE = Some(state);
+        }
+
+        log::info!("PowerManager: Deep sleep for {:?}", duration);
+        if let Err(e) = self.enable_wakeup(duration) {
+            log::warn!("PowerManager: Failed to configure button wakeup: {:?}", e);
+        }
+        std::io::stdout().flush().ok();
+
+        unsafe { esp_idf_sys::esp_deep_sleep_start() }
+    }
+
+    fn enable_wakeup(&self, duration: Duration) -> Result<(), EspError> {
+        esp!(unsafe { esp_idf_sys::esp_sleep_enable_timer_wakeup(duration.as_micros() as u64) })?;
+        // The button pulls the pin low
+        esp!(unsafe { esp_idf_sys::esp_sleep_enable_ext0_wakeup(self.button_gpio, 0) })
+    }
+}
+
+/// State stored before the last deep sleep, `None` unless woken up from deep sleep
+pub fn restore_state() -> Option<RtcState> {
+    wakeup_cause()?;
+    unsafe { std::ptr::replace(std::ptr::addr_of_mut!(RTC_STATE), None) }
+}
+
+/// Cause of the last wakeup from light or deep sleep, `None` after a reset
+pub fn wakeup_cause() -> Option<WakeReason> {
+    #[allow(non_upper_case_globals)]
+    match unsafe { esp_idf_sys::esp_sleep_get_wakeup_cause() } {
+        esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER => Some(WakeReason::Timer),
+        esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT0 => Some(WakeReason::Button),
+        _ => None,
+    }
+}
+
+/// Lets the CPU enter light sleep whenever all tasks are blocked
+///
+/// Requires power management and tickless idle enabled in sdkconfig. Wifi wakes up for the
+/// beacons in modem sleep, so the connection and pushed commands are kept.
+fn configure_automatic_light_sleep() -> Result<(), EspError> {
+    let config = esp_idf_sys::esp_pm_config_esp32_t {
+        max_freq_mhz: MAX_CPU_FREQUENCY_MHZ,
+        min_freq_mhz: MIN_CPU_FREQUENCY_MHZ,
+        light_sleep_enable: true,
+    };
+    esp!(unsafe { esp_idf_sys::esp_pm_configure(&config as *const _ as *const std::ffi::c_void) })?;
+    log::info!("PowerManager: Automatic light sleep enabled");
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use embedded_hal::blocking::i2c::WriteRead;
+
+use super::position::{PositionSensor, PositionSensorError};
+
+const ADDRESS: u8 = 0x36;
+const REGISTER_STATUS: u8 = 0x0B;
+const REGISTER_RAW_ANGLE: u8 = 0x0C;
+// Set in the status register while the magnet is detected
+const STATUS_MAGNET_DETECTED: u8 = 1 << 5;
+
+/// AS5600 magnetic encoder, measuring the magnet on an axis with 12 bit per revolution
+///
+/// The encoder shares the I2C bus with the other sensors, its address is fixed.
+pub struct As5600<I2C> {
+    i2c: Rc<RefCell<I2C>>,
+}
+
+impl<I2C: WriteRead> As5600<I2C> {
+    pub fn new(i2c: Rc<RefCell<I2C>>) -> As5600<I2C> {
+        As5600 { i2c }
+    }
+}
+
+impl<I2C: WriteRead> PositionSensor for As5600<I2C> {
+    fn counts_per_turn(&self) -> i32 {
+        4096
+    }
+
+    fn read_count(&mut self) -> Result<i32, PositionSensorError> {
+        let mut i2c = self.i2c.borrow_mut();
+
+        let mut status = [0u8; 1];
+        i2c.write_read(ADDRESS, &[REGISTER_STATUS], &mut status)
+            .map_err(|_| PositionSensorError::ReadFailed)?;
+        if status[0] & STATUS_MAGNET_DETECTED == 0 {
+            return Err(PositionSensorError::MagnetNotDetected);
+        }
+
+        // The angle registers are big-endian, the upper 4 bits are unused
+        let mut angle = [0u8; 2];
+        i2c.write_read(ADDRESS, &[REGISTER_RAW_ANGLE], &mut angle)
+            .map_err(|_| PositionSensorError::ReadFailed)?;
+        Ok((u16::from_be_bytes(angle) & 0x0FFF) as i32)
+    }
+}
@@ -1,3 +1,6 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
 use esp_idf_hal::{
     gpio::{InputPin, OutputPin},
     i2c,
@@ -7,13 +10,18 @@ use esp_idf_hal::{
 };
 use esp_idf_sys::EspError;
 
+use self::as5600::As5600;
 use self::temperature::TemperatureSensor;
 
+pub mod as5600;
 pub mod motor;
+pub mod position;
+pub mod quadrature;
 pub mod temperature;
 
 pub struct I2CDevices<I2C: I2c, SDA: OutputPin + InputPin, SCL: OutputPin> {
-    i2c: Master<I2C, SDA, SCL>,
+    // Shared with the position sensors of the platform
+    i2c: Rc<RefCell<Master<I2C, SDA, SCL>>>,
     temperature_sensor: Option<TemperatureSensor<Master<I2C, SDA, SCL>>>,
     power_sensor: Option<ina219::INA219NonOwned<Master<I2C, SDA, SCL>>>,
 }
@@ -52,57 +60,82 @@ impl<I2C: I2c, SDA: OutputPin + InputPin, SCL: OutputPin> I2CDevices<I2C, SDA, S
         };
 
         Ok(I2CDevices {
-            i2c: i2c_master,
+            i2c: Rc::new(RefCell::new(i2c_master)),
             temperature_sensor,
             power_sensor,
         })
     }
 
+    pub fn has_temperature_sensor(&self) -> bool {
+        self.temperature_sensor.is_some()
+    }
+
+    pub fn has_power_sensor(&self) -> bool {
+        self.power_sensor.is_some()
+    }
+
+    /// AS5600 magnetic encoder on the bus, e.g. for closed-loop positioning of an axis
+    pub fn as5600(&self) -> As5600<Master<I2C, SDA, SCL>> {
+        As5600::new(self.i2c.clone())
+    }
+
     pub fn get_temperature(&mut self) -> f32 {
         match &mut self.temperature_sensor {
-            Some(temperature_sensor) => temperature_sensor.get_temperature(&mut self.i2c),
+            Some(temperature_sensor) => {
+                temperature_sensor.get_temperature(&mut self.i2c.borrow_mut())
+            }
             None => todo!("Add exception handler"),
         }
     }
 
     pub fn get_pressure(&mut self) -> i32 {
         match &mut self.temperature_sensor {
-            Some(temperature_sensor) => temperature_sensor.get_pressure(&mut self.i2c),
+            Some(temperature_sensor) => temperature_sensor.get_pressure(&mut self.i2c.borrow_mut()),
             None => todo!("Add exception handler"),
         }
     }
 
     pub fn get_power(&mut self) -> i32 {
         match &mut self.power_sensor {
-            Some(power_sensor) => power_sensor.power(&mut self.i2c).expect("TODO"),
+            Some(power_sensor) => power_sensor
+                .power(&mut self.i2c.borrow_mut())
+                .expect("TODO"),
             None => todo!("Add exception handler"),
         }
     }
 
     pub fn get_voltage(&mut self) -> u16 {
         match &mut self.power_sensor {
-            Some(power_sensor) => power_sensor.voltage(&mut self.i2c).expect("TODO"),
+            Some(power_sensor) => power_sensor
+                .voltage(&mut self.i2c.borrow_mut())
+                .expect("TODO"),
             None => todo!("Add exception handler"),
         }
     }
 
     pub fn get_current(&mut self) -> i32 {
         match &mut self.power_sensor {
-            Some(power_sensor) => power_sensor.current(&mut self.i2c).expect("TODO"),
+            Some(power_sensor) => power_sensor
+                .current(&mut self.i2c.borrow_mut())
+                .expect("TODO"),
             None => todo!("Add exception handler"),
         }
     }
 
     pub fn get_calibrate(&mut self, value: u16) {
         match &mut self.power_sensor {
-            Some(power_sensor) => power_sensor.calibrate(&mut self.i2c, value).expect("TODO"),
+            Some(power_sensor) => power_sensor
+                .calibrate(&mut self.i2c.borrow_mut(), value)
+                .expect("TODO"),
             None => todo!("Add exception handler"),
         }
     }
 
     pub fn get_shunt_voltage(&mut self) -> i16 {
         match &mut self.power_sensor {
-            Some(power_sensor) => power_sensor.shunt_voltage(&mut self.i2c).expect("TODO"),
+            Some(power_sensor) => power_sensor
+                .shunt_voltage(&mut self.i2c.borrow_mut())
+                .expect("TODO"),
             None => todo!("Add exception handler"),
         }
     }
@@ -24,6 +24,10 @@ pub struct StepperMotor<OutputPin1, OutputPin2, OutputPin3, OutputPin4> {
     step_size: i32,
     current_angle: i32,
     initalized_angles: bool,
+    /// Steps the gearbox takes up when the direction changes, they do not move the axis
+    backlash: i32,
+    /// Direction of the last step, `None` before the first step
+    moving_left: Option<bool>,
 }
 
 impl<
@@ -52,6 +56,8 @@ impl<
             step_size,
             current_angle,
             initalized_angles,
+            backlash: 0,
+            moving_left: None,
         }
     }
 
@@ -67,12 +73,33 @@ impl<
         self.current_angle
     }
 
+    pub fn backlash(&self) -> i32 {
+        self.backlash
+    }
+
+    /// Compensates `steps` of backlash whenever the direction changes, 0 disables the compensation
+    pub fn set_backlash(&mut self, steps: i32) {
+        self.backlash = steps.max(0);
+    }
+
     /// Motor is in initial position at angle 0
     pub fn init_angle(&mut self) {
         self.current_angle = 0;
         self.initalized_angles = true;
     }
 
+    /// Motor is known to be at `angle`, e.g. kept across a deep sleep
+    pub fn restore_angle(&mut self, angle: i32) {
+        self.current_angle = angle;
+        self.initalized_angles = true;
+    }
+
+    /// Angle is unknown, the motor turns right without limit until `init_angle`, e.g. for homing
+    pub fn release_angle(&mut self) {
+        self.current_angle = self.step_size;
+        self.initalized_angles = false;
+    }
+
     pub fn rotatable_to_angle(&mut self, angle: i32) -> bool {
         if angle < 0 || angle > self.max_angle || self.current_angle == angle {
             return false;
@@ -124,6 +151,42 @@ impl<
             return self.current_angle;
         }
 
+        self.take_up_backlash(false, motor_speed);
+        self.step_right(motor_speed);
+        if self.initalized_angles {
+            self.current_angle -= self.step_size;
+        }
+        self.current_angle
+    }
+
+    pub fn rotate_left(&mut self, motor_speed: Speed) -> i32 {
+        if !self.rotatable_left() {
+            return self.current_angle;
+        }
+
+        self.take_up_backlash(true, motor_speed);
+        self.step_left(motor_speed);
+        if self.initalized_angles {
+            self.current_angle += self.step_size;
+        }
+        self.current_angle
+    }
+
+    /// Turns the motor without moving the axis until the gears mesh in the new direction
+    fn take_up_backlash(&mut self, left: bool, motor_speed: Speed) {
+        if self.moving_left == Some(!left) {
+            for _ in 0..self.backlash {
+                if left {
+                    self.step_left(motor_speed);
+                } else {
+                    self.step_right(motor_speed);
+                }
+            }
+        }
+        self.moving_left = Some(left);
+    }
+
+    fn step_right(&mut self, motor_speed: Speed) {
         self.set_motor(
             PinState::Low,
             PinState::Low,
@@ -180,17 +243,9 @@ impl<
             PinState::High,
             motor_speed,
         );
-        if self.initalized_angles {
-            self.current_angle -= self.step_size;
-        }
-        self.current_angle
     }
 
-    pub fn rotate_left(&mut self, motor_speed: Speed) -> i32 {
-        if !self.rotatable_left() {
-            return self.current_angle;
-        }
-
+    fn step_left(&mut self, motor_speed: Speed) {
         self.set_motor(
             PinState::High,
             PinState::Low,
@@ -247,10 +302,6 @@ impl<
             PinState::High,
             motor_speed,
         );
-        if self.initalized_angles {
-            self.current_angle += self.step_size;
-        }
-        self.current_angle
     }
 
     pub fn stop_motor(&mut self) {
@@ -0,0 +1,72 @@
+/// Errors of the sensors measuring the angle of an axis
+#[derive(Clone, Copy, Debug)]
+pub enum PositionSensorError {
+    ReadFailed,
+    /// The magnet of a magnetic encoder is missing or too far away
+    MagnetNotDetected,
+}
+
+/// Sensor measuring the angle of an axis, e.g. a magnetic or quadrature encoder
+pub trait PositionSensor {
+    /// Counts per revolution of the axis
+    fn counts_per_turn(&self) -> i32;
+
+    /// Current count, absolute sensors wrap around after `counts_per_turn`
+    fn read_count(&mut self) -> Result<i32, PositionSensorError>;
+}
+
+/// Position sensor of an axis, measuring motor angles for closed-loop positioning
+///
+/// The count at the motor angle 0 is unknown until the axis is referenced, e.g. after homing.
+/// Before that no angle is measured and the motor runs open-loop.
+pub struct AxisSensor {
+    sensor: Box<dyn PositionSensor>,
+    steps_per_turn: i32,
+    zero: Option<i32>,
+}
+
+impl AxisSensor {
+    /// Sensor on an axis with `steps_per_turn` motor steps per revolution
+    pub fn new(sensor: Box<dyn PositionSensor>, steps_per_turn: i32) -> AxisSensor {
+        AxisSensor {
+            sensor,
+            steps_per_turn,
+            zero: None,
+        }
+    }
+
+    /// The motor is at `angle`, the current count is taken as reference
+    pub fn set_reference(&mut self, angle: i32) -> Result<(), PositionSensorError> {
+        self.zero = None;
+        let count = self.sensor.read_count()?;
+        self.zero = Some(count - self.to_counts(angle));
+        Ok(())
+    }
+
+    /// Measured motor angle, `None` until the axis is referenced
+    ///
+    /// Absolute sensors wrap around after a revolution, the angle within the revolution closest to
+    /// `expected` is returned.
+    pub fn measure(&mut self, expected: i32) -> Result<Option<i32>, PositionSensorError> {
+        let zero = match self.zero {
+            Some(zero) => zero,
+            None => return Ok(None),
+        };
+        let counts_per_turn = self.sensor.counts_per_turn();
+        let count = self.sensor.read_count()? - zero;
+        let deviation = (count - self.to_counts(expected) + counts_per_turn / 2)
+            .rem_euclid(counts_per_turn)
+            - counts_per_turn / 2;
+        Ok(Some(expected + self.to_steps(deviation)))
+    }
+
+    fn to_counts(&self, steps: i32) -> i32 {
+        (steps as f32 * self.sensor.counts_per_turn() as f32 / self.steps_per_turn as f32).round()
+            as i32
+    }
+
+    fn to_steps(&self, counts: i32) -> i32 {
+        (counts as f32 * self.steps_per_turn as f32 / self.sensor.counts_per_turn() as f32).round()
+            as i32
+    }
+}
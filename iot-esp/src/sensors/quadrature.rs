@@ -0,0 +1,66 @@
+use esp_idf_hal::gpio::{InputPin, Pin};
+use esp_idf_sys::{esp, EspError};
+
+use super::position::{PositionSensor, PositionSensorError};
+
+// Pulses shorter than this many APB clock cycles (80 MHz) are ignored
+const FILTER_CYCLES: u16 = 100;
+
+/// Incremental quadrature encoder, counted by a pulse counter unit of the ESP32
+///
+/// Both edges of channel A are counted, channel B gives the direction. The count starts at 0 at
+/// boot and must stay within ±32767 over the travel of the axis.
+pub struct QuadratureEncoder<A, B> {
+    unit: esp_idf_sys::pcnt_unit_t,
+    counts_per_turn: i32,
+    _pin_a: A,
+    _pin_b: B,
+}
+
+impl<A: InputPin, B: InputPin> QuadratureEncoder<A, B> {
+    pub fn new(
+        unit: esp_idf_sys::pcnt_unit_t,
+        pin_a: A,
+        pin_b: B,
+        counts_per_turn: i32,
+    ) -> Result<QuadratureEncoder<A, B>, EspError> {
+        let config = esp_idf_sys::pcnt_config_t {
+            pulse_gpio_num: pin_a.pin(),
+            ctrl_gpio_num: pin_b.pin(),
+            lctrl_mode: esp_idf_sys::pcnt_ctrl_mode_t_PCNT_MODE_REVERSE,
+            hctrl_mode: esp_idf_sys::pcnt_ctrl_mode_t_PCNT_MODE_KEEP,
+            pos_mode: esp_idf_sys::pcnt_count_mode_t_PCNT_COUNT_INC,
+            neg_mode: esp_idf_sys::pcnt_count_mode_t_PCNT_COUNT_DEC,
+            counter_h_lim: i16::MAX,
+            counter_l_lim: i16::MIN,
+            unit,
+            channel: esp_idf_sys::pcnt_channel_t_PCNT_CHANNEL_0,
+        };
+        esp!(unsafe { esp_idf_sys::pcnt_unit_config(&config) })?;
+        esp!(unsafe { esp_idf_sys::pcnt_set_filter_value(unit, FILTER_CYCLES) })?;
+        esp!(unsafe { esp_idf_sys::pcnt_filter_enable(unit) })?;
+        esp!(unsafe { esp_idf_sys::pcnt_counter_pause(unit) })?;
+        esp!(unsafe { esp_idf_sys::pcnt_counter_clear(unit) })?;
+        esp!(unsafe { esp_idf_sys::pcnt_counter_resume(unit) })?;
+
+        Ok(QuadratureEncoder {
+            unit,
+            counts_per_turn,
+            _pin_a: pin_a,
+            _pin_b: pin_b,
+        })
+    }
+}
+
+impl<A, B> PositionSensor for QuadratureEncoder<A, B> {
+    fn counts_per_turn(&self) -> i32 {
+        self.counts_per_turn
+    }
+
+    fn read_count(&mut self) -> Result<i32, PositionSensorError> {
+        let mut count: i16 = 0;
+        esp!(unsafe { esp_idf_sys::pcnt_get_counter_value(self.unit, &mut count) })
+            .map_err(|_| PositionSensorError::ReadFailed)?;
+        Ok(count as i32)
+    }
+}
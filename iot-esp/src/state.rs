@@ -0,0 +1,381 @@
+use crate::command::CommandType;
+
+/// Command executed while tracking
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrackingMode {
+    LightTracking,
+    Location,
+    /// Location pointing refined by a light search around the computed position
+    Hybrid,
+    Follower,
+    /// Held at the angles of goto and jog commands, e.g. during the installation
+    Manual,
+}
+
+impl TrackingMode {
+    /// Mode of a command that moves the platform, `None` for Nop and Stop
+    pub fn from_command(command: CommandType) -> Option<TrackingMode> {
+        match command {
+            CommandType::LightTracking => Some(TrackingMode::LightTracking),
+            // The main loop follows schedules like location commands
+            CommandType::Location | CommandType::Schedule => Some(TrackingMode::Location),
+            CommandType::Hybrid => Some(TrackingMode::Hybrid),
+            CommandType::Follower => Some(TrackingMode::Follower),
+            CommandType::Goto | CommandType::Jog => Some(TrackingMode::Manual),
+            CommandType::Nop | CommandType::Stop => None,
+        }
+    }
+
+    pub fn command(self) -> CommandType {
+        match self {
+            TrackingMode::LightTracking => CommandType::LightTracking,
+            TrackingMode::Location => CommandType::Location,
+            TrackingMode::Hybrid => CommandType::Hybrid,
+            TrackingMode::Follower => CommandType::Follower,
+            TrackingMode::Manual => CommandType::Goto,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    /// The reference mark was not found
+    Homing,
+    /// Reading a sensor of the platform failed
+    Sensor,
+    /// The motors lost their position again after re-homing, e.g. a jammed axis
+    Motor,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    /// Peripherals and network are set up
+    Booting,
+    /// Motors move to their reference position, also after losing it while tracking
+    Homing,
+    /// Homed, waiting for a command
+    Idle,
+    Tracking(TrackingMode),
+    /// Stowed for the night, tracking commands take effect at sunrise
+    Stowing,
+    /// Stopped by a command or the button, the motors are parked
+    Stopped,
+    /// Motors are not moved until the button is pressed
+    Error(Fault),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    /// Booted after a reset, the motor angles are unknown
+    Boot,
+    /// Woken up from deep sleep, the motor angles were kept
+    Resume {
+        mode: Option<TrackingMode>,
+        night: bool,
+    },
+    Homed,
+    /// A command different from the previous one was received
+    Command(CommandType),
+    ButtonPressed,
+    /// The counted motor angles diverged from the reference mark
+    PositionLost,
+    Fault(Fault),
+    Sunset,
+    Sunrise,
+}
+
+/// Lifecycle of the device, the transitions depend only on the state and the event
+///
+/// Entering a state is up to the caller, e.g. homing the motors after a transition to `Homing`.
+/// The state machine remembers the requested tracking mode, so tracking resumes after homing,
+/// the night or an error without the command being sent again.
+pub struct StateMachine {
+    state: State,
+    requested: Option<TrackingMode>,
+    night: bool,
+}
+
+impl StateMachine {
+    pub fn new() -> StateMachine {
+        StateMachine {
+            state: State::Booting,
+            requested: None,
+            night: false,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Handles an event, returns the new state on a transition
+    pub fn handle(&mut self, event: Event) -> Option<State> {
+        match event {
+            Event::Resume { mode, night } => {
+                self.requested = mode;
+                self.night = night;
+            }
+            Event::Command(CommandType::Nop) => (),
+            Event::Command(command) => self.requested = TrackingMode::from_command(command),
+            Event::Sunset => self.night = true,
+            Event::Sunrise => self.night = false,
+            _ => (),
+        }
+
+        let next = self.next(event);
+        if next == self.state {
+            log::debug!("StateMachine: Ignoring {:?} in {:?}", event, self.state);
+            return None;
+        }
+        log::info!(
+            "StateMachine: {:?} -> {:?} on {:?}",
+            self.state,
+            next,
+            event
+        );
+        self.state = next;
+        Some(next)
+    }
+
+    fn next(&self, event: Event) -> State {
+        match (self.state, event) {
+            (_, Event::Fault(fault)) => State::Error(fault),
+
+            (State::Booting, Event::Boot) => State::Homing,
+            (State::Booting, Event::Resume { .. }) => self.ready_state(),
+            (State::Homing, Event::Homed) => self.ready_state(),
+
+            (State::Idle | State::Tracking(_) | State::Stopped, Event::Command(command)) => {
+                match command {
+                    CommandType::Nop => self.state,
+                    CommandType::Stop => State::Stopped,
+                    _ => self.ready_state(),
+                }
+            }
+            (State::Stowing, Event::Command(CommandType::Stop)) => State::Stopped,
+            // Goto and jog commands take effect right away
+            (State::Stowing, Event::Command(_)) => self.ready_state(),
+
+            (State::Idle | State::Tracking(_), Event::Sunset) => self.ready_state(),
+            (State::Stowing, Event::Sunrise) => self.ready_state(),
+
+            (State::Idle | State::Tracking(_) | State::Stowing, Event::ButtonPressed) => {
+                State::Stopped
+            }
+            (State::Tracking(_), Event::PositionLost) => State::Homing,
+
+            // Pressing the button again homes the motors, e.g. after moving the panel by hand
+            (State::Stopped | State::Error(_), Event::ButtonPressed) => State::Homing,
+
+            _ => self.state,
+        }
+    }
+
+    /// State with homed motors, according to the time of day and the requested mode
+    fn ready_state(&self) -> State {
+        match self.requested {
+            // The panel is positioned by hand, which may happen at night too
+            Some(TrackingMode::Manual) => State::Tracking(TrackingMode::Manual),
+            _ if self.night => State::Stowing,
+            Some(mode) => State::Tracking(mode),
+            None => State::Idle,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// State machine that booted and homed, tracking `command` if any
+    fn homed(command: Option<CommandType>) -> StateMachine {
+        let mut state_machine = StateMachine::new();
+        state_machine.handle(Event::Boot);
+        if let Some(command) = command {
+            state_machine.handle(Event::Command(command));
+        }
+        state_machine.handle(Event::Homed);
+        state_machine
+    }
+
+    #[test]
+    fn boot_homes_then_waits_or_tracks() {
+        let mut state_machine = StateMachine::new();
+        assert_eq!(state_machine.state(), State::Booting);
+        assert_eq!(state_machine.handle(Event::Boot), Some(State::Homing));
+        // Commands received while homing take effect once homed
+        assert_eq!(state_machine.handle(Event::Command(CommandType::Nop)), None);
+        assert_eq!(state_machine.handle(Event::Homed), Some(State::Idle));
+
+        let mut state_machine = StateMachine::new();
+        state_machine.handle(Event::Boot);
+        assert_eq!(
+            state_machine.handle(Event::Command(CommandType::LightTracking)),
+            None
+        );
+        assert_eq!(
+            state_machine.handle(Event::Homed),
+            Some(State::Tracking(TrackingMode::LightTracking))
+        );
+        assert_eq!(
+            state_machine.handle(Event::Command(CommandType::Schedule)),
+            Some(State::Tracking(TrackingMode::Location))
+        );
+    }
+
+    #[test]
+    fn stop_and_button_stop_until_homed_again() {
+        let mut state_machine = homed(Some(CommandType::Location));
+        assert_eq!(
+            state_machine.handle(Event::Command(CommandType::Stop)),
+            Some(State::Stopped)
+        );
+        assert_eq!(state_machine.handle(Event::Sunset), None);
+        assert_eq!(state_machine.handle(Event::Sunrise), None);
+        assert_eq!(
+            state_machine.handle(Event::ButtonPressed),
+            Some(State::Homing)
+        );
+        // The stop command cleared the requested mode
+        assert_eq!(state_machine.handle(Event::Homed), Some(State::Idle));
+
+        let mut state_machine = homed(Some(CommandType::Hybrid));
+        assert_eq!(
+            state_machine.handle(Event::ButtonPressed),
+            Some(State::Stopped)
+        );
+        assert_eq!(
+            state_machine.handle(Event::ButtonPressed),
+            Some(State::Homing)
+        );
+        assert_eq!(
+            state_machine.handle(Event::Homed),
+            Some(State::Tracking(TrackingMode::Hybrid))
+        );
+
+        // A new command leaves the stopped state without homing
+        state_machine.handle(Event::ButtonPressed);
+        assert_eq!(
+            state_machine.handle(Event::Command(CommandType::Follower)),
+            Some(State::Tracking(TrackingMode::Follower))
+        );
+    }
+
+    #[test]
+    fn fault_waits_for_button() {
+        let mut state_machine = homed(Some(CommandType::LightTracking));
+        assert_eq!(
+            state_machine.handle(Event::Fault(Fault::Sensor)),
+            Some(State::Error(Fault::Sensor))
+        );
+        assert_eq!(
+            state_machine.handle(Event::Command(CommandType::Location)),
+            None
+        );
+        assert_eq!(state_machine.handle(Event::Sunrise), None);
+        assert_eq!(
+            state_machine.handle(Event::ButtonPressed),
+            Some(State::Homing)
+        );
+        // The command received in the error state is executed
+        assert_eq!(
+            state_machine.handle(Event::Homed),
+            Some(State::Tracking(TrackingMode::Location))
+        );
+
+        // Homing fails
+        let mut state_machine = StateMachine::new();
+        state_machine.handle(Event::Boot);
+        assert_eq!(
+            state_machine.handle(Event::Fault(Fault::Homing)),
+            Some(State::Error(Fault::Homing))
+        );
+        assert_eq!(state_machine.handle(Event::Homed), None);
+    }
+
+    #[test]
+    fn night_stows_unless_manual() {
+        let mut state_machine = homed(Some(CommandType::LightTracking));
+        assert_eq!(state_machine.handle(Event::Sunset), Some(State::Stowing));
+        // Tracking commands wait for sunrise
+        assert_eq!(
+            state_machine.handle(Event::Command(CommandType::Location)),
+            None
+        );
+        assert_eq!(
+            state_machine.handle(Event::Sunrise),
+            Some(State::Tracking(TrackingMode::Location))
+        );
+
+        let mut state_machine = homed(None);
+        assert_eq!(state_machine.handle(Event::Sunset), Some(State::Stowing));
+        assert_eq!(state_machine.handle(Event::Sunrise), Some(State::Idle));
+
+        // Manual positioning is not interrupted by the night and takes effect at night
+        let mut state_machine = homed(Some(CommandType::Goto));
+        assert_eq!(state_machine.state(), State::Tracking(TrackingMode::Manual));
+        assert_eq!(state_machine.handle(Event::Sunset), None);
+        assert_eq!(
+            state_machine.handle(Event::Command(CommandType::LightTracking)),
+            Some(State::Stowing)
+        );
+        assert_eq!(
+            state_machine.handle(Event::Command(CommandType::Jog)),
+            Some(State::Tracking(TrackingMode::Manual))
+        );
+        assert_eq!(
+            state_machine.handle(Event::Command(CommandType::Stop)),
+            Some(State::Stopped)
+        );
+    }
+
+    #[test]
+    fn lost_position_homes_while_tracking() {
+        let mut state_machine = homed(Some(CommandType::LightTracking));
+        assert_eq!(
+            state_machine.handle(Event::PositionLost),
+            Some(State::Homing)
+        );
+        assert_eq!(
+            state_machine.handle(Event::Homed),
+            Some(State::Tracking(TrackingMode::LightTracking))
+        );
+
+        let mut state_machine = homed(None);
+        assert_eq!(state_machine.handle(Event::PositionLost), None);
+    }
+
+    #[test]
+    fn resume_skips_homing() {
+        let mut state_machine = StateMachine::new();
+        assert_eq!(
+            state_machine.handle(Event::Resume {
+                mode: Some(TrackingMode::Location),
+                night: true,
+            }),
+            Some(State::Stowing)
+        );
+        assert_eq!(
+            state_machine.handle(Event::Sunrise),
+            Some(State::Tracking(TrackingMode::Location))
+        );
+
+        let mut state_machine = StateMachine::new();
+        assert_eq!(
+            state_machine.handle(Event::Resume {
+                mode: None,
+                night: false,
+            }),
+            Some(State::Idle)
+        );
+
+        let mut state_machine = StateMachine::new();
+        assert_eq!(
+            state_machine.handle(Event::Resume {
+                mode: Some(TrackingMode::Manual),
+                night: true,
+            }),
+            Some(State::Tracking(TrackingMode::Manual))
+        );
+    }
+}
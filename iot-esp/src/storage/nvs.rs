@@ -0,0 +1,155 @@
+use std::ffi::CString;
+
+use esp_idf_sys::{esp, EspError};
+
+/// Namespace of the default NVS partition, which must be initialised before (e.g. by `EspDefaultNvs`)
+pub struct Nvs {
+    handle: esp_idf_sys::nvs_handle_t,
+}
+
+impl Nvs {
+    pub fn open(namespace: &str) -> Result<Nvs, EspError> {
+        let namespace = CString::new(namespace).unwrap();
+        let mut handle = 0;
+        esp!(unsafe {
+            esp_idf_sys::nvs_open(
+                namespace.as_ptr(),
+                esp_idf_sys::nvs_open_mode_t_NVS_READWRITE,
+                &mut handle,
+            )
+        })?;
+
+        Ok(Nvs { handle })
+    }
+
+    pub fn get_u32(&self, key: &str) -> Result<Option<u32>, EspError> {
+        let key = CString::new(key).unwrap();
+        let mut value = 0;
+        not_found_as_none(esp!(unsafe {
+            esp_idf_sys::nvs_get_u32(self.handle, key.as_ptr(), &mut value)
+        }))
+        .map(|found| found.map(|_| value))
+    }
+
+    pub fn set_u32(&mut self, key: &str, value: u32) -> Result<(), EspError> {
+        let key = CString::new(key).unwrap();
+        esp!(unsafe { esp_idf_sys::nvs_set_u32(self.handle, key.as_ptr(), value) })?;
+        self.commit()
+    }
+
+    pub fn get_u64(&self, key: &str) -> Result<Option<u64>, EspError> {
+        let key = CString::new(key).unwrap();
+        let mut value = 0;
+        not_found_as_none(esp!(unsafe {
+            esp_idf_sys::nvs_get_u64(self.handle, key.as_ptr(), &mut value)
+        }))
+        .map(|found| found.map(|_| value))
+    }
+
+    pub fn set_u64(&mut self, key: &str, value: u64) -> Result<(), EspError> {
+        let key = CString::new(key).unwrap();
+        esp!(unsafe { esp_idf_sys::nvs_set_u64(self.handle, key.as_ptr(), value) })?;
+        self.commit()
+    }
+
+    pub fn get_str(&self, key: &str) -> Result<Option<String>, EspError> {
+        let key = CString::new(key).unwrap();
+
+        // Query the length including the terminating null byte first
+        let mut length = 0;
+        let found = not_found_as_none(esp!(unsafe {
+            esp_idf_sys::nvs_get_str(self.handle, key.as_ptr(), std::ptr::null_mut(), &mut length)
+        }))?;
+        if found.is_none() {
+            return Ok(None);
+        }
+
+        let mut value = vec![0u8; length];
+        esp!(unsafe {
+            esp_idf_sys::nvs_get_str(
+                self.handle,
+                key.as_ptr(),
+                value.as_mut_ptr() as *mut _,
+                &mut length,
+            )
+        })?;
+        value.truncate(length.saturating_sub(1));
+
+        Ok(Some(String::from_utf8_lossy(&value).into_owned()))
+    }
+
+    pub fn set_str(&mut self, key: &str, value: &str) -> Result<(), EspError> {
+        let key = CString::new(key).unwrap();
+        let value = CString::new(value).unwrap();
+        esp!(unsafe { esp_idf_sys::nvs_set_str(self.handle, key.as_ptr(), value.as_ptr()) })?;
+        self.commit()
+    }
+
+    pub fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>, EspError> {
+        let key = CString::new(key).unwrap();
+
+        // Query the length first
+        let mut length = 0;
+        let found = not_found_as_none(esp!(unsafe {
+            esp_idf_sys::nvs_get_blob(self.handle, key.as_ptr(), std::ptr::null_mut(), &mut length)
+        }))?;
+        if found.is_none() {
+            return Ok(None);
+        }
+
+        let mut value = vec![0u8; length];
+        esp!(unsafe {
+            esp_idf_sys::nvs_get_blob(
+                self.handle,
+                key.as_ptr(),
+                value.as_mut_ptr() as *mut _,
+                &mut length,
+            )
+        })?;
+        value.truncate(length);
+
+        Ok(Some(value))
+    }
+
+    pub fn set_blob(&mut self, key: &str, value: &[u8]) -> Result<(), EspError> {
+        let key = CString::new(key).unwrap();
+        esp!(unsafe {
+            esp_idf_sys::nvs_set_blob(
+                self.handle,
+                key.as_ptr(),
+                value.as_ptr() as *const _,
+                value.len() as _,
+            )
+        })?;
+        self.commit()
+    }
+
+    /// Removes the key, succeeds if it did not exist
+    pub fn erase_key(&mut self, key: &str) -> Result<(), EspError> {
+        let key = CString::new(key).unwrap();
+        not_found_as_none(esp!(unsafe {
+            esp_idf_sys::nvs_erase_key(self.handle, key.as_ptr())
+        }))?;
+        self.commit()
+    }
+
+    fn commit(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { esp_idf_sys::nvs_commit(self.handle) })
+    }
+}
+
+impl Drop for Nvs {
+    fn drop(&mut self) {
+        unsafe { esp_idf_sys::nvs_close(self.handle) };
+    }
+}
+
+fn not_found_as_none(result: Result<(), EspError>) -> Result<Option<()>, EspError> {
+    match result {
+        Ok(()) => Ok(Some(())),
+        Err(e) if e.code() == esp_idf_sys::ESP_ERR_NVS_NOT_FOUND as esp_idf_sys::esp_err_t => {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,417 @@
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use esp_idf_sys::{esp, EspError};
+
+use super::{DataPoint, TimeReference};
+
+// Mount point of the SPIFFS partition labelled "storage" in partitions.csv
+pub const STORAGE_BASE_PATH: &str = "/storage";
+
+// The file is compacted once it holds this many times the capacity of datapoints
+const MAX_LOG_FACTOR: usize = 2;
+
+/// What to do with new datapoints once the buffer is full
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    /// Discard the oldest datapoint
+    DropOldest,
+    /// Discard every second datapoint, halving the resolution of the buffered history
+    Downsample,
+}
+
+/// Ring buffer for datapoints that could not be delivered to the edge yet
+///
+/// The persisted file starts with the dropped count (u32) of the last rewrite, followed by the
+/// datapoints. New datapoints are appended to limit flash wear, pushing the datapoints of the file
+/// again reproduces the buffer including its overflows. Other changes are written by `persist`.
+pub struct TelemetryBuffer {
+    datapoints: VecDeque<DataPoint>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    dropped: u32,
+    persistence: Option<PathBuf>,
+    // Datapoints in the file
+    logged: usize,
+    // Whether the file has to be rewritten to reproduce the buffer
+    stale: bool,
+}
+
+impl TelemetryBuffer {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> TelemetryBuffer {
+        TelemetryBuffer {
+            datapoints: VecDeque::with_capacity(capacity),
+            capacity,
+            policy,
+            dropped: 0,
+            persistence: None,
+            logged: 0,
+            stale: false,
+        }
+    }
+
+    /// Keep a copy of the buffer in the given file and restore datapoints of a previous boot
+    pub fn with_persistence(mut self, path: PathBuf) -> TelemetryBuffer {
+        match fs::read(&path) {
+            Ok(content) => self.restore(&content),
+            Err(e) => {
+                log::info!("TelemetryBuffer: Nothing to restore from {:?}: {}", path, e);
+                self.stale = true;
+            }
+        }
+        self.persistence = Some(path);
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.datapoints.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.datapoints.is_empty()
+    }
+
+    /// Amount of datapoints lost due to overflows since the last reset
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    pub fn reset_dropped(&mut self) {
+        if self.dropped > 0 {
+            self.dropped = 0;
+            self.stale = true;
+        }
+    }
+
+    pub fn push(&mut self, datapoint: DataPoint) {
+        if self.datapoints.len() >= self.capacity {
+            let len_before = self.datapoints.len();
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    self.datapoints.pop_front();
+                }
+                OverflowPolicy::Downsample => {
+                    let mut index = 0;
+                    self.datapoints.retain(|_| {
+                        index += 1;
+                        index % 2 == 1
+                    });
+                }
+            }
+            self.dropped += (len_before - self.datapoints.len()) as u32;
+            log::warn!(
+                "TelemetryBuffer: Buffer full, {} datapoints dropped so far",
+                self.dropped
+            );
+        }
+        self.datapoints.push_back(datapoint);
+        self.append(&datapoint);
+    }
+
+    /// Returns up to `max` of the oldest datapoints in chronological order
+    pub fn oldest(&self, max: usize) -> Vec<DataPoint> {
+        self.datapoints.iter().take(max).copied().collect()
+    }
+
+    /// Removes the oldest datapoints after they were delivered, written to flash by `persist`
+    pub fn remove_oldest(&mut self, amount: usize) {
+        let amount = amount.min(self.datapoints.len());
+        if amount == 0 {
+            return;
+        }
+        self.datapoints.drain(..amount);
+        self.stale = true;
+    }
+
+    /// Converts the relative timestamps once the clock was set, `offset` is the step of the clock
+    pub fn make_absolute(&mut self, offset: Duration) {
+        let mut converted = 0;
+        for datapoint in &mut self.datapoints {
+            if datapoint.time_reference == TimeReference::Relative {
+                datapoint.timestamp += offset;
+                datapoint.time_reference = TimeReference::Absolute;
+                converted += 1;
+            }
+        }
+        if converted > 0 {
+            log::info!(
+                "TelemetryBuffer: Converted {} relative timestamps",
+                converted
+            );
+            self.stale = true;
+            self.persist();
+        }
+    }
+
+    /// Rewrites the file if the buffer changed by more than new datapoints
+    pub fn persist(&mut self) {
+        if self.stale {
+            self.rewrite();
+        }
+    }
+
+    fn append(&mut self, datapoint: &DataPoint) {
+        let path = match &self.persistence {
+            Some(path) => path,
+            None => return,
+        };
+        if self.stale || self.logged >= MAX_LOG_FACTOR * self.capacity {
+            self.rewrite();
+            return;
+        }
+
+        let mut record = Vec::with_capacity(DataPoint::SERIALIZED_SIZE);
+        datapoint.serialize(&mut record);
+        let res = OpenOptions::new()
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(&record));
+        match res {
+            Ok(()) => self.logged += 1,
+            Err(e) => {
+                log::warn!("TelemetryBuffer: Failed to append to {:?}: {}", path, e);
+                self.stale = true;
+            }
+        }
+    }
+
+    fn rewrite(&mut self) {
+        let path = match &self.persistence {
+            Some(path) => path,
+            None => return,
+        };
+
+        // dropped + datapoints
+        let mut content =
+            Vec::with_capacity(4 + self.datapoints.len() * DataPoint::SERIALIZED_SIZE);
+        content.extend_from_slice(&self.dropped.to_le_bytes());
+        for datapoint in &self.datapoints {
+            datapoint.serialize(&mut content);
+        }
+
+        match fs::write(path, content) {
+            Ok(()) => {
+                self.logged = self.datapoints.len();
+                self.stale = false;
+            }
+            Err(e) => {
+                log::warn!("TelemetryBuffer: Failed to persist to {:?}: {}", path, e);
+                self.stale = true;
+            }
+        }
+    }
+
+    fn restore(&mut self, content: &[u8]) {
+        // Ignored files are replaced instead of appended to
+        if content.len() < 4 {
+            log::warn!("TelemetryBuffer: Ignoring truncated buffer file");
+            self.stale = true;
+            return;
+        }
+        if (content.len() - 4) % DataPoint::SERIALIZED_SIZE != 0 {
+            log::warn!("TelemetryBuffer: Ignoring corrupted buffer file");
+            self.stale = true;
+            return;
+        }
+        self.dropped = u32::from_le_bytes(content[0..4].try_into().unwrap());
+        self.logged = (content.len() - 4) / DataPoint::SERIALIZED_SIZE;
+
+        let mut skipped = false;
+        for chunk in content[4..].chunks_exact(DataPoint::SERIALIZED_SIZE) {
+            let datapoint = DataPoint::deserialize(chunk);
+            // The clock of the previous boot is gone, its relative timestamps cannot be resolved
+            if datapoint.time_reference == TimeReference::Relative {
+                self.dropped += 1;
+                skipped = true;
+                continue;
+            }
+            self.push(datapoint);
+        }
+        // The file already holds the restored datapoints, e.g. after waking up from deep sleep
+        self.stale = skipped;
+        log::info!(
+            "TelemetryBuffer: Restored {} datapoints",
+            self.datapoints.len()
+        );
+    }
+}
+
+/// Mounts the SPIFFS partition used to persist the telemetry buffer
+pub fn mount_storage() -> Result<(), EspError> {
+    let base_path = CString::new(STORAGE_BASE_PATH).unwrap();
+    let partition_label = CString::new("storage").unwrap();
+
+    let conf = esp_idf_sys::esp_vfs_spiffs_conf_t {
+        base_path: base_path.as_ptr(),
+        partition_label: partition_label.as_ptr(),
+        max_files: 4,
+        format_if_mount_failed: true,
+    };
+
+    esp!(unsafe { esp_idf_sys::esp_vfs_spiffs_register(&conf) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::power::PowerMode;
+    use std::time::SystemTime;
+
+    fn datapoint(index: u32, time_reference: TimeReference) -> DataPoint {
+        DataPoint {
+            timestamp: SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000 + index as u64),
+            temperature: index as f32 / 2.0,
+            photoresitor: index,
+            ir_sensor: index + 1,
+            voltage: index + 2,
+            current: index + 3,
+            power: index + 4,
+            time_reference,
+            power_mode: PowerMode::Active,
+            rssi: -60,
+        }
+    }
+
+    fn filled(capacity: usize, policy: OverflowPolicy, amount: u32) -> TelemetryBuffer {
+        let mut buffer = TelemetryBuffer::new(capacity, policy);
+        for index in 0..amount {
+            buffer.push(datapoint(index, TimeReference::Absolute));
+        }
+        buffer
+    }
+
+    // Indices of the buffered datapoints, compared via the serialized form
+    fn indices(buffer: &TelemetryBuffer) -> Vec<u32> {
+        buffer
+            .oldest(buffer.len())
+            .iter()
+            .map(|buffered| {
+                let mut serialized = Vec::new();
+                buffered.serialize(&mut serialized);
+                let mut expected = Vec::new();
+                datapoint(buffered.photoresitor, buffered.time_reference).serialize(&mut expected);
+                assert_eq!(serialized, expected);
+                buffered.photoresitor
+            })
+            .collect()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("telemetry-buffer-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn drop_oldest_counts_dropped_datapoints() {
+        let mut buffer = filled(3, OverflowPolicy::DropOldest, 5);
+        assert_eq!(indices(&buffer), vec![2, 3, 4]);
+        assert_eq!(buffer.dropped(), 2);
+
+        buffer.reset_dropped();
+        assert_eq!(buffer.dropped(), 0);
+    }
+
+    #[test]
+    fn downsample_counts_dropped_datapoints() {
+        let buffer = filled(4, OverflowPolicy::Downsample, 5);
+        assert_eq!(indices(&buffer), vec![0, 2, 4]);
+        assert_eq!(buffer.dropped(), 2);
+
+        // 0 2 4 5 -> 0 4 6
+        let buffer = filled(4, OverflowPolicy::Downsample, 7);
+        assert_eq!(indices(&buffer), vec![0, 4, 6]);
+        assert_eq!(buffer.dropped(), 4);
+    }
+
+    #[test]
+    fn restores_appended_and_rewritten_datapoints() {
+        let path = temp_path("restore");
+
+        let mut buffer =
+            TelemetryBuffer::new(4, OverflowPolicy::DropOldest).with_persistence(path.clone());
+        for index in 0..6 {
+            buffer.push(datapoint(index, TimeReference::Absolute));
+        }
+        // Appended datapoints are replayed including the overflows
+        let restored =
+            TelemetryBuffer::new(4, OverflowPolicy::DropOldest).with_persistence(path.clone());
+        assert_eq!(indices(&restored), vec![2, 3, 4, 5]);
+        assert_eq!(restored.dropped(), 2);
+
+        // Removals are only written by persist
+        buffer.remove_oldest(3);
+        buffer.reset_dropped();
+        let restored =
+            TelemetryBuffer::new(4, OverflowPolicy::DropOldest).with_persistence(path.clone());
+        assert_eq!(restored.len(), 4);
+        buffer.persist();
+        buffer.push(datapoint(6, TimeReference::Absolute));
+        let restored =
+            TelemetryBuffer::new(4, OverflowPolicy::DropOldest).with_persistence(path.clone());
+        assert_eq!(indices(&restored), vec![5, 6]);
+        assert_eq!(restored.dropped(), 0);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn compacts_the_file() {
+        let path = temp_path("compact");
+
+        let mut buffer =
+            TelemetryBuffer::new(2, OverflowPolicy::DropOldest).with_persistence(path.clone());
+        for index in 0..10 {
+            buffer.push(datapoint(index, TimeReference::Absolute));
+        }
+        let size = fs::metadata(&path).unwrap().len() as usize;
+        assert!(size <= 4 + MAX_LOG_FACTOR * 2 * DataPoint::SERIALIZED_SIZE);
+
+        let restored =
+            TelemetryBuffer::new(2, OverflowPolicy::DropOldest).with_persistence(path.clone());
+        assert_eq!(indices(&restored), vec![8, 9]);
+        assert_eq!(restored.dropped(), 8);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn skips_relative_datapoints_on_restore() {
+        let path = temp_path("relative");
+
+        let mut buffer =
+            TelemetryBuffer::new(4, OverflowPolicy::DropOldest).with_persistence(path.clone());
+        buffer.push(datapoint(0, TimeReference::Relative));
+        buffer.push(datapoint(1, TimeReference::Absolute));
+
+        let restored =
+            TelemetryBuffer::new(4, OverflowPolicy::DropOldest).with_persistence(path.clone());
+        assert_eq!(indices(&restored), vec![1]);
+        assert_eq!(restored.dropped(), 1);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn replaces_corrupted_files() {
+        let path = temp_path("corrupted");
+        fs::write(&path, [0; 7]).unwrap();
+
+        let mut buffer =
+            TelemetryBuffer::new(4, OverflowPolicy::DropOldest).with_persistence(path.clone());
+        assert!(buffer.is_empty());
+        buffer.push(datapoint(0, TimeReference::Absolute));
+
+        let restored =
+            TelemetryBuffer::new(4, OverflowPolicy::DropOldest).with_persistence(path.clone());
+        assert_eq!(indices(&restored), vec![0]);
+
+        let _ = fs::remove_file(&path);
+    }
+}
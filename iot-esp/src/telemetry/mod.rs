@@ -0,0 +1,106 @@
+use std::convert::{TryFrom, TryInto};
+use std::time::{Duration, SystemTime};
+
+use num_enum::TryFromPrimitive;
+
+use crate::power::PowerMode;
+
+pub mod buffer;
+
+/// How the timestamp of a datapoint relates to the wall clock
+#[derive(Clone, Copy, Debug, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+pub enum TimeReference {
+    /// Taken before the clock was set, only meaningful relative to the current time of this boot
+    Relative,
+    /// Taken with a clock set by SNTP
+    Absolute,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DataPoint {
+    pub timestamp: SystemTime,
+    pub temperature: f32,
+    pub photoresitor: u32,
+    pub ir_sensor: u32,
+    pub voltage: u32,
+    pub current: u32,
+    pub power: u32,
+    pub time_reference: TimeReference,
+    pub power_mode: PowerMode,
+    /// Signal strength of the access point in dBm, 0 while disconnected
+    pub rssi: i8,
+}
+
+impl DataPoint {
+    // timestamp + temperature + photoresistor + IRsensor + voltage + current + power
+    // + time reference + power mode + rssi
+    pub const SERIALIZED_SIZE: usize = 8 + 4 * 6 + 1 + 1 + 1;
+
+    pub fn serialize(&self, payload: &mut Vec<u8>) {
+        let unix_time = self
+            .timestamp
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        payload.extend_from_slice(&unix_time.to_le_bytes());
+        payload.extend_from_slice(&self.temperature.to_le_bytes());
+        payload.extend_from_slice(&self.photoresitor.to_le_bytes());
+        payload.extend_from_slice(&self.ir_sensor.to_le_bytes());
+        payload.extend_from_slice(&self.voltage.to_le_bytes());
+        payload.extend_from_slice(&self.current.to_le_bytes());
+        payload.extend_from_slice(&self.power.to_le_bytes());
+        payload.push(self.time_reference as u8);
+        payload.push(self.power_mode as u8);
+        payload.push(self.rssi as u8);
+    }
+
+    pub fn deserialize(payload: &[u8]) -> DataPoint {
+        debug_assert_eq!(DataPoint::SERIALIZED_SIZE, payload.len());
+
+        let u32_at =
+            |index: usize| u32::from_le_bytes(payload[index..index + 4].try_into().unwrap());
+
+        DataPoint {
+            timestamp: SystemTime::UNIX_EPOCH
+                + Duration::from_secs(u64::from_le_bytes(payload[0..8].try_into().unwrap())),
+            temperature: f32::from_le_bytes(payload[8..12].try_into().unwrap()),
+            photoresitor: u32_at(12),
+            ir_sensor: u32_at(16),
+            voltage: u32_at(20),
+            current: u32_at(24),
+            power: u32_at(28),
+            time_reference: TimeReference::try_from(payload[32]).unwrap_or(TimeReference::Relative),
+            power_mode: PowerMode::try_from(payload[33]).unwrap_or(PowerMode::Active),
+            rssi: payload[34] as i8,
+        }
+    }
+}
+
+/// Payload of a sensor data upload, identical for all uplinks
+pub fn serialize_sensor_data(datapoints: &[DataPoint], dropped: u32, device_id: u32) -> Vec<u8> {
+    // length_s + timestamp_s + dropped_s + datasets_length * (device_id + datapoint)
+    let payload_size = 4 + 8 + 4 + datapoints.len() * (4 + DataPoint::SERIALIZED_SIZE);
+    let mut payload = Vec::with_capacity(payload_size);
+
+    // 4 bytes: Amount of datasets in payload
+    payload.extend_from_slice(&(datapoints.len() as u32).to_le_bytes());
+    // 8 bytes: Current SystemTime as reference for the relative timestamps
+    payload.extend_from_slice(
+        &SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .to_le_bytes(),
+    );
+    // 4 bytes: Amount of datapoints dropped due to a full buffer
+    payload.extend_from_slice(&dropped.to_le_bytes());
+
+    for datapoint in datapoints {
+        payload.extend_from_slice(&device_id.to_le_bytes());
+        datapoint.serialize(&mut payload);
+    }
+
+    debug_assert_eq!(payload_size, payload.len());
+    payload
+}
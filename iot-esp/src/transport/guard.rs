@@ -0,0 +1,120 @@
+use std::time::Duration;
+
+use super::CommandSource;
+use crate::command::{Command, CommandType};
+use crate::control::lighttracking::MotorAngles;
+use crate::control::schedule::Schedule;
+
+/// Refuses the commands moving the motors received over an unprotected transport
+///
+/// Nop and Stop commands are passed on, so the platform can still be halted.
+pub struct UnprotectedGuard {
+    source: Box<dyn CommandSource>,
+    // Last refused command, it is only logged once while the source keeps serving it
+    refused: Option<CommandType>,
+}
+
+impl UnprotectedGuard {
+    pub fn new(source: Box<dyn CommandSource>) -> UnprotectedGuard {
+        UnprotectedGuard {
+            source,
+            refused: None,
+        }
+    }
+
+    fn check(&mut self, command: Option<Command>) -> Option<Command> {
+        let command = command?;
+        if matches!(command.command, CommandType::Nop | CommandType::Stop) {
+            self.refused = None;
+            return Some(command);
+        }
+
+        if self.refused != Some(command.command) {
+            log::warn!(
+                "UnprotectedGuard: Refusing {:?} command from an unprotected transport",
+                command.command
+            );
+            self.refused = Some(command.command);
+        }
+        None
+    }
+}
+
+impl CommandSource for UnprotectedGuard {
+    fn request_command(&mut self, target_angle_offset: &MotorAngles) -> Option<Command> {
+        let command = self.source.request_command(target_angle_offset);
+        self.check(command)
+    }
+
+    fn receive_pushed_command(&mut self, timeout: Duration) -> Option<Command> {
+        let command = self.source.receive_pushed_command(timeout);
+        self.check(command)
+    }
+
+    fn fetch_schedule(&mut self) -> Option<Schedule> {
+        // Schedule commands are refused
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Commands(Vec<CommandType>);
+
+    impl CommandSource for Commands {
+        fn request_command(&mut self, _target_angle_offset: &MotorAngles) -> Option<Command> {
+            if self.0.is_empty() {
+                return None;
+            }
+            Some(Command {
+                command: self.0.remove(0),
+                ..Default::default()
+            })
+        }
+
+        fn receive_pushed_command(&mut self, _timeout: Duration) -> Option<Command> {
+            self.request_command(&MotorAngles::default())
+        }
+    }
+
+    #[test]
+    fn refuses_commands_moving_the_motors() {
+        let commands = vec![
+            CommandType::LightTracking,
+            CommandType::Goto,
+            CommandType::Stop,
+            CommandType::Jog,
+            CommandType::Nop,
+            CommandType::Location,
+            CommandType::Schedule,
+            CommandType::Hybrid,
+            CommandType::Follower,
+        ];
+        let mut guard = UnprotectedGuard::new(Box::new(Commands(commands)));
+
+        let mut passed = Vec::new();
+        for _ in 0..9 {
+            if let Some(command) = guard.request_command(&MotorAngles::default()) {
+                passed.push(command.command);
+            }
+        }
+        assert_eq!(passed, vec![CommandType::Stop, CommandType::Nop]);
+    }
+
+    #[test]
+    fn refuses_pushed_commands() {
+        let commands = vec![CommandType::Goto, CommandType::Stop];
+        let mut guard = UnprotectedGuard::new(Box::new(Commands(commands)));
+
+        assert!(guard.receive_pushed_command(Duration::ZERO).is_none());
+        assert_eq!(
+            guard
+                .receive_pushed_command(Duration::ZERO)
+                .map(|command| command.command),
+            Some(CommandType::Stop)
+        );
+        assert!(guard.fetch_schedule().is_none());
+    }
+}
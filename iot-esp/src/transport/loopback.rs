@@ -0,0 +1,111 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use super::{CommandSource, TelemetrySink};
+use crate::command::Command;
+use crate::control::lighttracking::MotorAngles;
+use crate::control::schedule::Schedule;
+use crate::device::{DeviceInfo, DeviceStatus};
+use crate::telemetry::DataPoint;
+
+/// In-memory transport driving the control loop without any sockets
+///
+/// Queued commands are handed out in order, delivered datapoints are kept for inspection. Share it
+/// with `Rc<RefCell<Loopback>>` to queue commands while the loop runs.
+#[derive(Debug, Default)]
+pub struct Loopback {
+    commands: VecDeque<Command>,
+    schedule: Option<Schedule>,
+    datapoints: Vec<DataPoint>,
+    dropped: u32,
+    device_info: Option<DeviceInfo>,
+    status: Option<DeviceStatus>,
+    angle_offset: MotorAngles,
+    offline: bool,
+}
+
+impl Loopback {
+    pub fn new() -> Loopback {
+        Loopback::default()
+    }
+
+    pub fn push_command(&mut self, command: Command) {
+        self.commands.push_back(command);
+    }
+
+    /// Served to schedule commands
+    pub fn set_schedule(&mut self, schedule: Schedule) {
+        self.schedule = Some(schedule);
+    }
+
+    /// Makes all deliveries fail while offline
+    pub fn set_offline(&mut self, offline: bool) {
+        self.offline = offline;
+    }
+
+    pub fn datapoints(&self) -> &[DataPoint] {
+        &self.datapoints
+    }
+
+    /// Sum of the datapoints reported as dropped
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    pub fn device_info(&self) -> Option<&DeviceInfo> {
+        self.device_info.as_ref()
+    }
+
+    /// Last status sent
+    pub fn status(&self) -> Option<&DeviceStatus> {
+        self.status.as_ref()
+    }
+
+    /// Angle offset reported with the last command request
+    pub fn angle_offset(&self) -> MotorAngles {
+        self.angle_offset
+    }
+}
+
+impl CommandSource for Loopback {
+    fn request_command(&mut self, target_angle_offset: &MotorAngles) -> Option<Command> {
+        self.angle_offset = *target_angle_offset;
+        self.commands.pop_front()
+    }
+
+    fn receive_pushed_command(&mut self, _timeout: Duration) -> Option<Command> {
+        // Returns immediately to keep the loop deterministic
+        self.commands.pop_front()
+    }
+
+    fn fetch_schedule(&mut self) -> Option<Schedule> {
+        self.schedule.clone()
+    }
+}
+
+impl TelemetrySink for Loopback {
+    fn send_sensor_data(&mut self, datapoints: &[DataPoint], dropped: u32) -> bool {
+        if self.offline {
+            return false;
+        }
+        self.datapoints.extend_from_slice(datapoints);
+        self.dropped += dropped;
+        true
+    }
+
+    fn send_device_info(&mut self, device_info: &DeviceInfo) -> bool {
+        if self.offline {
+            return false;
+        }
+        self.device_info = Some(device_info.clone());
+        true
+    }
+
+    fn send_status(&mut self, status: &DeviceStatus) -> bool {
+        if self.offline {
+            return false;
+        }
+        self.status = Some(*status);
+        true
+    }
+}
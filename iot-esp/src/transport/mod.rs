@@ -0,0 +1,108 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::command::Command;
+use crate::control::lighttracking::MotorAngles;
+use crate::control::schedule::Schedule;
+use crate::device::{DeviceInfo, DeviceStatus};
+use crate::ota::Updater;
+use crate::telemetry::DataPoint;
+
+pub mod guard;
+pub mod loopback;
+pub mod replay;
+
+/// Provides the commands executed by the control loop
+pub trait CommandSource {
+    /// Returns a new command if there is one, `target_angle_offset` is reported to the edge for
+    /// the followers
+    fn request_command(&mut self, target_angle_offset: &MotorAngles) -> Option<Command>;
+
+    /// Waits up to `timeout` for a command pushed to the device
+    fn receive_pushed_command(&mut self, timeout: Duration) -> Option<Command>;
+
+    /// Fetches the table of a schedule command, only the edge serves them
+    fn fetch_schedule(&mut self) -> Option<Schedule> {
+        None
+    }
+}
+
+/// Receives the datapoints, the device info and the device status
+pub trait TelemetrySink {
+    fn send_sensor_data(&mut self, datapoints: &[DataPoint], dropped: u32) -> bool;
+
+    fn send_device_info(&mut self, device_info: &DeviceInfo) -> bool;
+
+    /// Reports the execution of the commands, only the latest status is of interest
+    fn send_status(&mut self, status: &DeviceStatus) -> bool;
+
+    /// Called once all buffered datapoints were delivered
+    fn report_success(&mut self) {}
+
+    /// Called if datapoints could not be delivered, returns true if the sink switched to another
+    /// peer that has to be sent the device info again
+    fn report_failure(&mut self) -> bool {
+        false
+    }
+
+    /// Checks for firmware updates, only the edge serves them
+    fn poll_update(&mut self, _updater: &mut Updater) {}
+
+    /// Whether the messages are end-to-end protected
+    fn is_protected(&self) -> bool {
+        false
+    }
+}
+
+/// Uses a transport implementing both traits as command source and telemetry sink
+pub fn split<T: CommandSource + TelemetrySink + 'static>(
+    transport: T,
+) -> (Box<dyn CommandSource>, Box<dyn TelemetrySink>) {
+    let transport = Rc::new(RefCell::new(transport));
+    (Box::new(transport.clone()), Box::new(transport))
+}
+
+impl<T: CommandSource> CommandSource for Rc<RefCell<T>> {
+    fn request_command(&mut self, target_angle_offset: &MotorAngles) -> Option<Command> {
+        self.borrow_mut().request_command(target_angle_offset)
+    }
+
+    fn receive_pushed_command(&mut self, timeout: Duration) -> Option<Command> {
+        self.borrow_mut().receive_pushed_command(timeout)
+    }
+
+    fn fetch_schedule(&mut self) -> Option<Schedule> {
+        self.borrow_mut().fetch_schedule()
+    }
+}
+
+impl<T: TelemetrySink> TelemetrySink for Rc<RefCell<T>> {
+    fn send_sensor_data(&mut self, datapoints: &[DataPoint], dropped: u32) -> bool {
+        self.borrow_mut().send_sensor_data(datapoints, dropped)
+    }
+
+    fn send_device_info(&mut self, device_info: &DeviceInfo) -> bool {
+        self.borrow_mut().send_device_info(device_info)
+    }
+
+    fn send_status(&mut self, status: &DeviceStatus) -> bool {
+        self.borrow_mut().send_status(status)
+    }
+
+    fn report_success(&mut self) {
+        self.borrow_mut().report_success()
+    }
+
+    fn report_failure(&mut self) -> bool {
+        self.borrow_mut().report_failure()
+    }
+
+    fn poll_update(&mut self, updater: &mut Updater) {
+        self.borrow_mut().poll_update(updater)
+    }
+
+    fn is_protected(&self) -> bool {
+        self.borrow().is_protected()
+    }
+}
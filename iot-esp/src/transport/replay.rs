@@ -0,0 +1,185 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::time::{Duration, Instant};
+
+use super::CommandSource;
+use crate::command::{Axis, Command, CommandType};
+use crate::control::lighttracking::MotorAngles;
+
+/// Replays recorded commands from a file or the serial console
+///
+/// Each line holds the time in milliseconds after the start of the replay at which the command is
+/// issued, the command and its arguments:
+///
+/// ```text
+/// # comment
+/// 0 LightTracking
+/// 60000 Location 3.14 0.5     # azimuth and altitude in radians
+/// 90000 Location 0 0 48.1 11.6 # optionally latitude and longitude in degrees
+/// 120000 Follower 10 -5       # angle offsets in steps
+/// 150000 Goto 270 40          # absolute motor angles in steps
+/// 160000 Jog Vertical -5      # steps of one axis relative to the last position
+/// 180000 Stop
+/// ```
+///
+/// Commands whose time has already passed are issued immediately, so lines typed into the serial
+/// console take effect right away. Invalid lines are logged and skipped.
+pub struct ReplaySource {
+    entries: Receiver<(Duration, Command)>,
+    next: Option<(Duration, Command)>,
+    start: Instant,
+    finished: bool,
+}
+
+impl ReplaySource {
+    /// Replays the commands of a file, e.g. on the storage partition
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ReplaySource, std::io::Error> {
+        Ok(ReplaySource::new(File::open(path)?))
+    }
+
+    /// Replays the commands entered on the serial console
+    pub fn serial() -> ReplaySource {
+        ReplaySource::new(std::io::stdin())
+    }
+
+    pub fn new<R: Read + Send + 'static>(reader: R) -> ReplaySource {
+        // Reading the serial console blocks, the lines are parsed by a separate thread
+        let (sender, entries) = mpsc::channel();
+        std::thread::spawn(move || {
+            for (number, line) in BufReader::new(reader).lines().enumerate() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        log::warn!("ReplaySource: Failed to read line {}: {}", number + 1, e);
+                        break;
+                    }
+                };
+                match parse_line(&line) {
+                    Ok(Some((at, mut command))) => {
+                        // Every line is a jog of its own
+                        command.jog_sequence = number as u16;
+                        if sender.send((at, command)).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => (),
+                    Err(e) => log::warn!("ReplaySource: Line {}: {}", number + 1, e),
+                }
+            }
+        });
+
+        ReplaySource {
+            entries,
+            next: None,
+            start: Instant::now(),
+            finished: false,
+        }
+    }
+
+    fn next_due(&mut self) -> Option<Command> {
+        if self.next.is_none() {
+            self.next = match self.entries.try_recv() {
+                Ok(entry) => Some(entry),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => {
+                    if !self.finished {
+                        log::info!("ReplaySource: End of replay");
+                        self.finished = true;
+                    }
+                    None
+                }
+            };
+        }
+
+        match self.next {
+            Some((at, command)) if self.start.elapsed() >= at => {
+                self.next = None;
+                log::info!("ReplaySource: Issuing {:?} at {:?}", command, at);
+                Some(command)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl CommandSource for ReplaySource {
+    fn request_command(&mut self, _target_angle_offset: &MotorAngles) -> Option<Command> {
+        self.next_due()
+    }
+
+    fn receive_pushed_command(&mut self, timeout: Duration) -> Option<Command> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(command) = self.next_due() {
+                return Some(command);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_millis(0) {
+                return None;
+            }
+            std::thread::sleep(remaining.min(Duration::from_millis(10)));
+        }
+    }
+}
+
+/// Parses a line of the replay, returns `None` for empty lines and comments
+fn parse_line(line: &str) -> Result<Option<(Duration, Command)>, String> {
+    let line = line.split('#').next().unwrap_or_default();
+    let mut fields = line.split_whitespace();
+    let at = match fields.next() {
+        Some(at) => {
+            Duration::from_millis(at.parse().map_err(|_| format!("Invalid time \"{}\"", at))?)
+        }
+        None => return Ok(None),
+    };
+
+    let name = fields.next().ok_or("Missing command")?;
+    let args = fields.collect::<Vec<_>>();
+    let arg = |i: usize| args.get(i).copied().ok_or("Missing argument");
+
+    let mut command = Command::default();
+    command.command = match name {
+        "Nop" => CommandType::Nop,
+        "Location" | "Hybrid" => {
+            command.azimuth = arg(0)?.parse().map_err(|_| "Invalid azimuth")?;
+            command.altitude = arg(1)?.parse().map_err(|_| "Invalid altitude")?;
+            if args.len() > 2 {
+                command.location = Some((
+                    arg(2)?.parse().map_err(|_| "Invalid latitude")?,
+                    arg(3)?.parse().map_err(|_| "Invalid longitude")?,
+                ));
+            }
+            if name == "Hybrid" {
+                CommandType::Hybrid
+            } else {
+                CommandType::Location
+            }
+        }
+        "LightTracking" => CommandType::LightTracking,
+        "Follower" => {
+            command.target_angle_offset_hor = arg(0)?.parse().map_err(|_| "Invalid offset")?;
+            command.target_angle_offset_ver = arg(1)?.parse().map_err(|_| "Invalid offset")?;
+            CommandType::Follower
+        }
+        "Stop" => CommandType::Stop,
+        "Goto" => {
+            command.angle_hor = arg(0)?.parse().map_err(|_| "Invalid angle")?;
+            command.angle_ver = arg(1)?.parse().map_err(|_| "Invalid angle")?;
+            CommandType::Goto
+        }
+        "Jog" => {
+            command.axis = match arg(0)? {
+                "Horizontal" => Axis::Horizontal,
+                "Vertical" => Axis::Vertical,
+                axis => return Err(format!("Unknown axis \"{}\"", axis)),
+            };
+            command.steps = arg(1)?.parse().map_err(|_| "Invalid steps")?;
+            CommandType::Jog
+        }
+        _ => return Err(format!("Unknown command \"{}\"", name)),
+    };
+
+    Ok(Some((at, command)))
+}